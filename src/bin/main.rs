@@ -1,10 +1,19 @@
-use std::{fs::read_to_string, path::Path};
+use std::{
+    io::{stdin, stdout, Read},
+    process::exit,
+};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 use rust_crafting_interpreters_lib::{
-    errors::{LoxInterpreterError, Result},
+    bytecode::{debug::disassemble_source, vm::LoxBytecodeVirtualMachine},
+    diagnostics::render_error,
+    errors::{LoxInterpreterError, LoxResult},
     interpreter::{LoxInterpreter, LoxTreeWalkInterpreter},
+    json::LoxJsonPrintable,
+    printer::operations_representation,
+    reader::read_file,
+    repl::LoxRepl,
 };
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -16,39 +25,146 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
     about = "Crafting Interpreters - Lox interpreter implementations (both tree-walk and bytecode-based) in Rust",
 )]
 struct CLIArgs {
-    input: String,
+    /// Lox script to run, or `-` to read it from the standard input (starts a REPL
+    /// session if omitted).
+    input: Option<String>,
+
+    /// Interpreter running the script (tree-walk by default) or the REPL session
+    /// (bytecode by default).
+    #[clap(long, value_enum, global = true)]
+    backend: Option<CLIBackend>,
 
     #[clap(subcommand)]
     command: Option<CLICommands>,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Subcommand)]
 enum CLICommands {
     /// Start an interactive REPL session in Lox
     REPL {
-        /// Use the first interpreter version (tree-walk interpreter).
+        /// Use the first interpreter version (tree-walk interpreter), whatever the backend.
         #[clap(short, long)]
         tree_walk_version: bool,
     },
+    /// Compile a Lox script to bytecode and print its disassembly
+    Disassemble {
+        /// Lox script to compile, or `-` to read it from the standard input.
+        input: String,
+    },
+    /// Parse a Lox script and print its syntax tree
+    Ast {
+        /// Lox script to parse, or `-` to read it from the standard input.
+        input: String,
+
+        /// Output format of the syntax tree.
+        #[clap(long, value_enum, default_value = "sexp")]
+        format: CLIAstFormat,
+    },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CLIBackend {
+    /// Tree-walk interpreter.
+    TreeWalk,
+    /// Bytecode virtual machine.
+    Bytecode,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CLIAstFormat {
+    /// S-expressions, one top-level declaration per line.
+    Sexp,
+    /// JSON array of the top-level declarations, with their tokens.
+    Json,
+}
+
+fn build_interpreter(backend: CLIBackend) -> Box<dyn LoxInterpreter> {
+    match backend {
+        CLIBackend::TreeWalk => Box::new(LoxTreeWalkInterpreter::new(None)),
+        CLIBackend::Bytecode => Box::new(LoxBytecodeVirtualMachine::default()),
+    }
 }
 
-fn main() -> Result<()> {
+/// Read the given script, from the standard input if its path is `-`.
+fn read_script(input: &str) -> LoxResult<String> {
+    let mut source = String::new();
+    if input == "-" {
+        stdin()
+            .read_to_string(&mut source)
+            .map_err(LoxInterpreterError::IOError)?;
+    } else {
+        source = read_file(input)?;
+    }
+    Ok(source)
+}
+
+fn run_script(source: &str, backend: CLIBackend) -> LoxResult<()> {
+    let mut interpreter = build_interpreter(backend);
+    interpreter.run_code(source)?;
+    Ok(())
+}
+
+fn run_repl(backend: CLIBackend) -> LoxResult<()> {
+    let mut repl = LoxRepl::new(build_interpreter(backend));
+    Ok(repl.run(stdin().lock(), stdout())?)
+}
+
+fn disassemble_script(source: &str) -> LoxResult<()> {
+    print!("{}", disassemble_source(source)?);
+    Ok(())
+}
+
+fn print_syntax_tree(source: &str, format: CLIAstFormat) -> LoxResult<()> {
+    let operations = LoxTreeWalkInterpreter::new(None).parse(source.to_string())?;
+    match format {
+        CLIAstFormat::Sexp => println!("{}", operations_representation(&operations)),
+        CLIAstFormat::Json => println!("{}", operations.json()),
+    }
+    Ok(())
+}
+
+/// Read the given script and run the command on it, reporting any error
+/// with its location in the script before exiting.
+fn run_command<F: FnOnce(&str) -> LoxResult<()>>(input: &str, command: F) {
+    let source = match read_script(input) {
+        Ok(source) => source,
+        Err(why) => {
+            eprintln!("{}", why);
+            exit(why.exit_code());
+        }
+    };
+    if let Err(why) = command(&source) {
+        let file_name = if input == "-" { "<stdin>" } else { input };
+        eprint!("{}", render_error(&why, &source, file_name));
+        exit(why.exit_code());
+    }
+}
+
+fn main() -> LoxResult<()> {
     let cli_args = CLIArgs::parse();
-    match &cli_args.command {
-        Some(CLICommands::REPL {
-            tree_walk_version: _,
-        }) => {
-            // TODO: REPL
+    match (&cli_args.command, &cli_args.input) {
+        (Some(CLICommands::REPL { tree_walk_version }), _) => {
+            let backend = if *tree_walk_version {
+                CLIBackend::TreeWalk
+            } else {
+                cli_args.backend.unwrap_or(CLIBackend::Bytecode)
+            };
+            run_repl(backend)
+        }
+        (None, None) => run_repl(cli_args.backend.unwrap_or(CLIBackend::Bytecode)),
+        (Some(CLICommands::Ast { input, format }), _) => {
+            run_command(input, |source| print_syntax_tree(source, *format));
+            Ok(())
+        }
+        (Some(CLICommands::Disassemble { input }), _) => {
+            run_command(input, disassemble_script);
             Ok(())
         }
-        _ => {
-            let input_file = cli_args.input;
-            let input_filepath = Path::new(&input_file);
-            let input_source =
-                read_to_string(input_filepath).map_err(LoxInterpreterError::IOError)?;
-            let mut interpreter = LoxTreeWalkInterpreter::new(None);
-            let parsed_operations = interpreter.parse(input_source)?;
-            let _ = interpreter.interpret(&parsed_operations)?;
+        (None, Some(input)) => {
+            run_command(input, |source| {
+                run_script(source, cli_args.backend.unwrap_or(CLIBackend::TreeWalk))
+            });
             Ok(())
         }
     }
@@ -1,7 +1,13 @@
-use self::values::{LoxBytecodeValue, LoxValueArray};
+use self::{
+    heap::LoxBytecodeObjectHandle,
+    values::{LoxBytecodeValue, LoxValueArray},
+};
+use crate::span::LoxSourceSpan;
 
+pub mod builtins;
 pub mod compiler;
 pub mod debug;
+pub mod heap;
 pub mod lexer;
 pub mod values;
 pub mod vm;
@@ -22,7 +28,32 @@ pub enum LoxBytecodeOpcode {
     Divide,
     Not,
     Negate,
+    Print,
+    Pop,
+    GetLocal,
+    SetLocal,
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
     Return,
+    Class,
+    Method,
+    Inherit,
+    GetProperty,
+    SetProperty,
+    GetSuper,
+    /// Method call on an instance, without creating the intermediate bound method.
+    Invoke,
+    /// Superclass method call, without creating the intermediate bound method.
+    SuperInvoke,
 }
 
 impl LoxBytecodeOpcode {
@@ -34,31 +65,20 @@ impl LoxBytecodeOpcode {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct LoxBytecodeChunk {
-    lines: Vec<usize>,
+    /// Location in the source code of the lexemes each instruction was compiled from.
+    spans: Vec<LoxSourceSpan>,
     constants: LoxValueArray,
+    /// Interned names of the global variables and properties referenced in the chunk.
+    identifiers: Vec<LoxBytecodeObjectHandle>,
     code: Vec<LoxBytecodeOpcode>,
 }
 
-impl Default for LoxBytecodeChunk {
-    fn default() -> Self {
-        Self {
-            code: vec![],
-            lines: vec![],
-            constants: LoxValueArray::default(),
-        }
-    }
-}
-
 impl LoxBytecodeChunk {
-    pub fn append(&mut self, bytecode: LoxBytecodeOpcode, line_number: usize) {
+    pub fn append(&mut self, bytecode: LoxBytecodeOpcode, span: LoxSourceSpan) {
         self.code.push(bytecode);
-        self.lines.push(line_number);
-    }
-
-    pub fn reallocate(&mut self, new_size: usize) {
-        todo!()
+        self.spans.push(span);
     }
 
     pub fn add_constant(&mut self, value: LoxBytecodeValue) -> usize {
@@ -70,6 +90,43 @@ impl LoxBytecodeChunk {
         self.constants.read(index)
     }
 
+    pub fn get_constants(&self) -> &LoxValueArray {
+        &self.constants
+    }
+
+    /// Returns the index of the given identifier, adding it if needed.
+    pub fn add_identifier(&mut self, name: LoxBytecodeObjectHandle) -> usize {
+        if let Some(index) = self
+            .identifiers
+            .iter()
+            .position(|identifier| *identifier == name)
+        {
+            index
+        } else {
+            self.identifiers.push(name);
+            self.identifiers.len() - 1
+        }
+    }
+
+    pub fn get_identifier(&self, index: usize) -> Option<LoxBytecodeObjectHandle> {
+        self.identifiers.get(index).copied()
+    }
+
+    pub fn get_identifiers(&self) -> &[LoxBytecodeObjectHandle] {
+        &self.identifiers
+    }
+
+    /// Replace the operand at the given offset, once a jump target is known.
+    pub fn patch(&mut self, offset: usize, operand: LoxBytecodeOpcode) {
+        self.code[offset] = operand;
+    }
+
+    /// Discards the last instruction.
+    pub fn truncate_last(&mut self) {
+        self.code.pop();
+        self.spans.pop();
+    }
+
     pub fn get_instruction(&self, offset: usize) -> Option<&LoxBytecodeOpcode> {
         self.code.get(offset)
     }
@@ -79,7 +136,11 @@ impl LoxBytecodeChunk {
     }
 
     pub fn get_line(&self, offset: usize) -> Option<usize> {
-        self.lines.get(offset).cloned()
+        self.get_span(offset).map(|span| span.get_line())
+    }
+
+    pub fn get_span(&self, offset: usize) -> Option<LoxSourceSpan> {
+        self.spans.get(offset).cloned()
     }
 
     pub fn get_size(&self) -> usize {
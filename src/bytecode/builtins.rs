@@ -0,0 +1,10 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::values::{LoxBytecodeNativeFunction, LoxBytecodeValue};
+
+pub fn build_lox_clock_builtin() -> LoxBytecodeNativeFunction {
+    LoxBytecodeNativeFunction::new("clock".into(), 0, |_arguments| -> LoxBytecodeValue {
+        let time_since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        LoxBytecodeValue::Number(time_since_epoch.as_secs_f64())
+    })
+}
@@ -2,13 +2,16 @@ use std::collections::HashMap;
 
 use crate::{
     bytecode::lexer::LoxBytecodeTokenType,
-    errors::{BResult, LoxBytecodeInterpreterError},
+    errors::{BResult, LoxBytecodeCompilerError, LoxBytecodeInterpreterError},
+    span::LoxSourceSpan,
 };
 
+#[cfg(feature = "code-printing")]
+use super::debug::disassemble_chunk;
 use super::{
-    debug::disassemble_chunk,
+    heap::{LoxBytecodeHeap, LoxBytecodeObject, LoxBytecodeObjectHandle},
     lexer::{LoxBytecodeLexer, LoxBytecodeToken},
-    values::LoxBytecodeValue,
+    values::{LoxBytecodeFunction, LoxBytecodeValue},
     LoxBytecodeChunk, LoxBytecodeOpcode,
 };
 
@@ -51,6 +54,7 @@ pub type LoxParseFunction = fn(
     source: &str,
     lexer: &mut LoxBytecodeLexer,
     chunk: &mut LoxBytecodeChunk,
+    can_assign: bool,
 ) -> BResult<()>;
 
 pub struct LoxParseRule {
@@ -59,32 +63,130 @@ pub struct LoxParseRule {
     precedence: LoxBytecodeOperatorPrecedence,
 }
 
+/// Maximum number of local variables in scope at once, as addressed by a single byte.
+const LOX_LOCALS_MAX: usize = u8::MAX as usize + 1;
+
+/// Maximum number of variables captured by a function, as addressed by a single byte.
+const LOX_UPVALUES_MAX: usize = u8::MAX as usize + 1;
+
+/// Maximum number of parameters of a function, or arguments of a call.
+const LOX_PARAMETERS_MAX: usize = u8::MAX as usize;
+
+/// Maximum distance of a jump, as encoded by two bytes in the reference implementation.
+const LOX_JUMP_MAX: usize = u16::MAX as usize;
+
+/// A local variable, living in a stack slot at runtime.
+#[derive(Clone, Debug)]
+pub struct LoxBytecodeLocal {
+    name: String,
+    /// Scope depth of the block declaring the variable, `None` while its
+    /// initializer is being compiled.
+    depth: Option<usize>,
+    /// Is the variable captured by a closure?
+    is_captured: bool,
+}
+
+/// A variable captured by the function being compiled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoxBytecodeUpvalueReference {
+    /// Stack slot of the enclosing function's local if `is_local`, or index
+    /// of the enclosing function's own upvalue otherwise.
+    index: usize,
+    is_local: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoxBytecodeFunctionKind {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Compilation state of a function body.
+#[derive(Debug)]
+pub struct LoxBytecodeFunctionScope {
+    kind: LoxBytecodeFunctionKind,
+    name: Option<String>,
+    arity: usize,
+    /// Local variables currently in scope, indexed by their stack slot.
+    locals: Vec<LoxBytecodeLocal>,
+    /// Number of blocks surrounding the code being compiled.
+    scope_depth: usize,
+    upvalues: Vec<LoxBytecodeUpvalueReference>,
+}
+
+impl LoxBytecodeFunctionScope {
+    pub fn new(kind: LoxBytecodeFunctionKind, name: Option<String>) -> Self {
+        // the first stack slot holds the function being called, or the receiver of a method
+        let receiver_name = match kind {
+            LoxBytecodeFunctionKind::Initializer | LoxBytecodeFunctionKind::Method => "this",
+            LoxBytecodeFunctionKind::Function | LoxBytecodeFunctionKind::Script => "",
+        };
+        Self {
+            kind,
+            name,
+            arity: 0,
+            locals: vec![LoxBytecodeLocal {
+                name: receiver_name.to_string(),
+                depth: Some(0),
+                is_captured: false,
+            }],
+            scope_depth: 0,
+            upvalues: vec![],
+        }
+    }
+}
+
+/// Compilation state of a class body.
+#[derive(Debug)]
+pub struct LoxBytecodeClassScope {
+    has_superclass: bool,
+}
+
+#[derive(Default)]
 pub struct LoxBytecodeTokensParser {
     current: LoxBytecodeToken,
     previous: LoxBytecodeToken,
     had_error: bool,
     panic_mode: bool,
+    /// Compilation errors, in order of appearance.
+    errors: Vec<LoxBytecodeCompilerError>,
 }
 
 /// Takes tokens from the Lexer and transforms them into a chunk of bytecode.
-pub struct LoxBytecodeCompiler {
+pub struct LoxBytecodeCompiler<'a> {
+    /// String literals and functions are allocated on the virtual machine's heap.
+    heap: &'a mut LoxBytecodeHeap,
+    /// Roots of the virtual machine, kept alive by collections during compilation.
+    vm_roots: Vec<LoxBytecodeValue>,
+    /// Objects allocated during compilation, referenced by the chunks being compiled.
+    objects: Vec<LoxBytecodeObjectHandle>,
     parser: LoxBytecodeTokensParser,
     parsing_rules: HashMap<LoxBytecodeTokenType, LoxParseRule>,
+    /// Is the last top-level statement an expression statement?
+    ends_with_expression: bool,
+    /// Functions being compiled, the innermost one last.
+    scopes: Vec<LoxBytecodeFunctionScope>,
+    /// Classes being compiled, the innermost one last.
+    classes: Vec<LoxBytecodeClassScope>,
 }
 
-impl LoxBytecodeCompiler {
-    pub fn new(source: &str, lexer: &mut LoxBytecodeLexer) -> BResult<Self> {
+impl<'a> LoxBytecodeCompiler<'a> {
+    pub fn new(heap: &'a mut LoxBytecodeHeap, vm_roots: Vec<LoxBytecodeValue>) -> Self {
         // parsing rules
         // TODO: use a macro here for terseness
         let mut parsing_rules = HashMap::new();
         parsing_rules.insert(
             LoxBytecodeTokenType::LeftParenthesis,
             LoxParseRule {
-                prefix: Some(|compiler, source, lexer, chunk| {
+                prefix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_grouping(source, lexer, chunk)
                 }),
-                infix: None,
-                precedence: LoxBytecodeOperatorPrecedence::None,
+                infix: Some(|compiler, source, lexer, chunk, _| {
+                    compiler.handle_call(source, lexer, chunk)
+                }),
+                precedence: LoxBytecodeOperatorPrecedence::Call,
             },
         );
         parsing_rules.insert(
@@ -123,17 +225,19 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::Dot,
             LoxParseRule {
                 prefix: None,
-                infix: None,
-                precedence: LoxBytecodeOperatorPrecedence::None,
+                infix: Some(|compiler, source, lexer, chunk, can_assign| {
+                    compiler.handle_dot(source, lexer, chunk, can_assign)
+                }),
+                precedence: LoxBytecodeOperatorPrecedence::Call,
             },
         );
         parsing_rules.insert(
             LoxBytecodeTokenType::Minus,
             LoxParseRule {
-                prefix: Some(|compiler, source, lexer, chunk| {
+                prefix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_unary(source, lexer, chunk)
                 }),
-                infix: Some(|compiler, source, lexer, chunk| {
+                infix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_binary(source, lexer, chunk)
                 }),
                 precedence: LoxBytecodeOperatorPrecedence::Term,
@@ -143,7 +247,7 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::Plus,
             LoxParseRule {
                 prefix: None,
-                infix: Some(|compiler, source, lexer, chunk| {
+                infix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_binary(source, lexer, chunk)
                 }),
                 precedence: LoxBytecodeOperatorPrecedence::Term,
@@ -161,7 +265,7 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::Slash,
             LoxParseRule {
                 prefix: None,
-                infix: Some(|compiler, source, lexer, chunk| {
+                infix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_binary(source, lexer, chunk)
                 }),
                 precedence: LoxBytecodeOperatorPrecedence::Factor,
@@ -171,7 +275,7 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::Star,
             LoxParseRule {
                 prefix: None,
-                infix: Some(|compiler, source, lexer, chunk| {
+                infix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_binary(source, lexer, chunk)
                 }),
                 precedence: LoxBytecodeOperatorPrecedence::Factor,
@@ -180,7 +284,7 @@ impl LoxBytecodeCompiler {
         parsing_rules.insert(
             LoxBytecodeTokenType::Bang,
             LoxParseRule {
-                prefix: Some(|compiler, source, lexer, chunk| {
+                prefix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_unary(source, lexer, chunk)
                 }),
                 infix: None,
@@ -191,7 +295,7 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::BangEqual,
             LoxParseRule {
                 prefix: None,
-                infix: Some(|compiler, source, lexer, chunk| {
+                infix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_binary(source, lexer, chunk)
                 }),
                 precedence: LoxBytecodeOperatorPrecedence::Equality,
@@ -209,7 +313,7 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::EqualEqual,
             LoxParseRule {
                 prefix: None,
-                infix: Some(|compiler, source, lexer, chunk| {
+                infix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_binary(source, lexer, chunk)
                 }),
                 precedence: LoxBytecodeOperatorPrecedence::Equality,
@@ -219,7 +323,7 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::Greater,
             LoxParseRule {
                 prefix: None,
-                infix: Some(|compiler, source, lexer, chunk| {
+                infix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_binary(source, lexer, chunk)
                 }),
                 precedence: LoxBytecodeOperatorPrecedence::Comparison,
@@ -229,7 +333,7 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::GreaterEqual,
             LoxParseRule {
                 prefix: None,
-                infix: Some(|compiler, source, lexer, chunk| {
+                infix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_binary(source, lexer, chunk)
                 }),
                 precedence: LoxBytecodeOperatorPrecedence::Comparison,
@@ -239,7 +343,7 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::Less,
             LoxParseRule {
                 prefix: None,
-                infix: Some(|compiler, source, lexer, chunk| {
+                infix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_binary(source, lexer, chunk)
                 }),
                 precedence: LoxBytecodeOperatorPrecedence::Comparison,
@@ -249,7 +353,7 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::LessEqual,
             LoxParseRule {
                 prefix: None,
-                infix: Some(|compiler, source, lexer, chunk| {
+                infix: Some(|compiler, source, lexer, chunk, _| {
                     compiler.handle_binary(source, lexer, chunk)
                 }),
                 precedence: LoxBytecodeOperatorPrecedence::Comparison,
@@ -258,7 +362,9 @@ impl LoxBytecodeCompiler {
         parsing_rules.insert(
             LoxBytecodeTokenType::Identifier,
             LoxParseRule {
-                prefix: None,
+                prefix: Some(|compiler, source, lexer, chunk, can_assign| {
+                    compiler.handle_variable(source, lexer, chunk, can_assign)
+                }),
                 infix: None,
                 precedence: LoxBytecodeOperatorPrecedence::None,
             },
@@ -266,7 +372,7 @@ impl LoxBytecodeCompiler {
         parsing_rules.insert(
             LoxBytecodeTokenType::String,
             LoxParseRule {
-                prefix: None,
+                prefix: Some(|compiler, source, _, chunk, _| compiler.handle_string(source, chunk)),
                 infix: None,
                 precedence: LoxBytecodeOperatorPrecedence::None,
             },
@@ -274,7 +380,7 @@ impl LoxBytecodeCompiler {
         parsing_rules.insert(
             LoxBytecodeTokenType::Number,
             LoxParseRule {
-                prefix: Some(|compiler, source, _, chunk| compiler.handle_number(source, chunk)),
+                prefix: Some(|compiler, source, _, chunk, _| compiler.handle_number(source, chunk)),
                 infix: None,
                 precedence: LoxBytecodeOperatorPrecedence::None,
             },
@@ -283,8 +389,10 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::And,
             LoxParseRule {
                 prefix: None,
-                infix: None,
-                precedence: LoxBytecodeOperatorPrecedence::None,
+                infix: Some(|compiler, source, lexer, chunk, _| {
+                    compiler.handle_and(source, lexer, chunk)
+                }),
+                precedence: LoxBytecodeOperatorPrecedence::And,
             },
         );
         parsing_rules.insert(
@@ -306,7 +414,7 @@ impl LoxBytecodeCompiler {
         parsing_rules.insert(
             LoxBytecodeTokenType::False,
             LoxParseRule {
-                prefix: Some(|compiler, source, _, chunk| compiler.handle_literal(source, chunk)),
+                prefix: Some(|compiler, _, _, chunk, _| compiler.handle_literal(chunk)),
                 infix: None,
                 precedence: LoxBytecodeOperatorPrecedence::None,
             },
@@ -338,7 +446,7 @@ impl LoxBytecodeCompiler {
         parsing_rules.insert(
             LoxBytecodeTokenType::Nil,
             LoxParseRule {
-                prefix: Some(|compiler, source, _, chunk| compiler.handle_literal(source, chunk)),
+                prefix: Some(|compiler, _, _, chunk, _| compiler.handle_literal(chunk)),
                 infix: None,
                 precedence: LoxBytecodeOperatorPrecedence::None,
             },
@@ -347,8 +455,10 @@ impl LoxBytecodeCompiler {
             LoxBytecodeTokenType::Or,
             LoxParseRule {
                 prefix: None,
-                infix: None,
-                precedence: LoxBytecodeOperatorPrecedence::None,
+                infix: Some(|compiler, source, lexer, chunk, _| {
+                    compiler.handle_or(source, lexer, chunk)
+                }),
+                precedence: LoxBytecodeOperatorPrecedence::Or,
             },
         );
         parsing_rules.insert(
@@ -370,7 +480,9 @@ impl LoxBytecodeCompiler {
         parsing_rules.insert(
             LoxBytecodeTokenType::Super,
             LoxParseRule {
-                prefix: None,
+                prefix: Some(|compiler, source, lexer, chunk, _| {
+                    compiler.handle_super(source, lexer, chunk)
+                }),
                 infix: None,
                 precedence: LoxBytecodeOperatorPrecedence::None,
             },
@@ -378,7 +490,9 @@ impl LoxBytecodeCompiler {
         parsing_rules.insert(
             LoxBytecodeTokenType::This,
             LoxParseRule {
-                prefix: None,
+                prefix: Some(|compiler, source, lexer, chunk, _| {
+                    compiler.handle_this(source, lexer, chunk)
+                }),
                 infix: None,
                 precedence: LoxBytecodeOperatorPrecedence::None,
             },
@@ -386,7 +500,7 @@ impl LoxBytecodeCompiler {
         parsing_rules.insert(
             LoxBytecodeTokenType::True,
             LoxParseRule {
-                prefix: Some(|compiler, source, _, chunk| compiler.handle_literal(source, chunk)),
+                prefix: Some(|compiler, _, _, chunk, _| compiler.handle_literal(chunk)),
                 infix: None,
                 precedence: LoxBytecodeOperatorPrecedence::None,
             },
@@ -424,43 +538,91 @@ impl LoxBytecodeCompiler {
             },
         );
 
-        let first_token = lexer.scan_token(source)?;
-        Ok(Self {
-            parser: LoxBytecodeTokensParser {
-                current: first_token.clone(), // TODO: check init
-                previous: first_token,        // TODO: check init
-                had_error: false,
-                panic_mode: false,
-            },
+        Self {
+            heap,
+            vm_roots,
+            objects: vec![],
+            parser: LoxBytecodeTokensParser::default(),
             parsing_rules,
-        })
+            ends_with_expression: false,
+            scopes: vec![],
+            classes: vec![],
+        }
     }
 
+    /// Does the compiled script end with an expression statement?
+    ///
+    /// If so, the value of this expression is the result of the script.
+    pub fn ends_with_expression(&self) -> bool {
+        self.ends_with_expression
+    }
+
+    /// Compile the given source code into the top-level script function.
+    ///
+    /// All the compilation errors encountered are returned at once.
     pub fn compile(
         &mut self,
         source: &str,
-        chunk: &mut LoxBytecodeChunk,
         lexer: &mut LoxBytecodeLexer,
-    ) -> BResult<bool> {
-        self.init(source, lexer, chunk)?;
-        self.parser.had_error = false;
-        let mut line_number = usize::MAX;
-        loop {
-            let token = lexer.scan_token(source)?;
-            let token_line_number = token.get_line_number();
-            if token_line_number != line_number {
-                print!("{:04}", token_line_number);
-                line_number = token_line_number;
-            } else {
-                print!("   | ");
-            }
-            println!("{:?} '{}'", token.get_kind(), token.get_lexeme(source));
-            if token.get_kind() == &LoxBytecodeTokenType::EndOfFile {
-                break;
+    ) -> BResult<LoxBytecodeObjectHandle> {
+        self.parser = LoxBytecodeTokensParser::default();
+        self.ends_with_expression = false;
+        self.objects.clear();
+        self.classes.clear();
+        self.scopes = vec![LoxBytecodeFunctionScope::new(
+            LoxBytecodeFunctionKind::Script,
+            None,
+        )];
+        let mut chunk = LoxBytecodeChunk::default();
+        self.init(source, lexer, &mut chunk)?;
+        if self.ends_with_expression {
+            // keep the value of the trailing expression statement on the stack
+            chunk.truncate_last();
+            self.emit_byte(&mut chunk, LoxBytecodeOpcode::Return);
+        }
+        let (script, _) = self.end_function(chunk);
+        if self.parser.had_error {
+            Err(LoxBytecodeInterpreterError::CompilerErrors(
+                self.parser.errors.drain(..).collect(),
+            ))
+        } else {
+            Ok(script)
+        }
+    }
+
+    /// Move the given object to the heap, collecting garbage beforehand if needed.
+    fn allocate(&mut self, object: LoxBytecodeObject) -> LoxBytecodeObjectHandle {
+        if self.heap.should_collect() {
+            let compiler_roots = self.objects.iter().copied().map(LoxBytecodeValue::Object);
+            self.heap
+                .collect(self.vm_roots.iter().copied().chain(compiler_roots));
+        }
+        let handle = self.heap.allocate(object);
+        self.objects.push(handle);
+        handle
+    }
+
+    fn intern_string(&mut self, string: &str) -> LoxBytecodeObjectHandle {
+        match self.heap.find_string(string) {
+            Some(handle) => {
+                // the chunks being compiled are not traced: keep it alive until they are allocated
+                self.objects.push(handle);
+                handle
             }
+            None => self.allocate(LoxBytecodeObject::String(string.to_string())),
         }
-        self.end_compilation(chunk);
-        Ok(!self.parser.had_error)
+    }
+
+    fn scope(&self) -> &LoxBytecodeFunctionScope {
+        self.scopes
+            .last()
+            .expect("compiler expects a function being compiled")
+    }
+
+    fn scope_mut(&mut self) -> &mut LoxBytecodeFunctionScope {
+        self.scopes
+            .last_mut()
+            .expect("compiler expects a function being compiled")
     }
 
     fn init(
@@ -470,24 +632,57 @@ impl LoxBytecodeCompiler {
         chunk: &mut LoxBytecodeChunk,
     ) -> BResult<()> {
         self.advance(source, lexer)?;
-        self.handle_expression(source, lexer, chunk)?;
-        self.consume_kind(
-            &LoxBytecodeTokenType::EndOfFile,
-            source,
-            lexer,
-            "Expect end of expression.",
-        )?;
+        while !self.match_kind(&LoxBytecodeTokenType::EndOfFile, source, lexer)? {
+            let is_expression_statement = !matches!(
+                self.parser.current.get_kind(),
+                LoxBytecodeTokenType::Class
+                    | LoxBytecodeTokenType::Fun
+                    | LoxBytecodeTokenType::Var
+                    | LoxBytecodeTokenType::For
+                    | LoxBytecodeTokenType::If
+                    | LoxBytecodeTokenType::While
+                    | LoxBytecodeTokenType::Print
+                    | LoxBytecodeTokenType::Return
+                    | LoxBytecodeTokenType::LeftBrace
+            );
+            self.handle_declaration(source, lexer, chunk)?;
+            self.ends_with_expression = is_expression_statement && !self.parser.had_error;
+        }
         Ok(())
     }
 
-    fn end_compilation(&self, chunk: &mut LoxBytecodeChunk) {
-        self.emit_return(chunk);
+    /// Finish compiling the innermost function, which owns the given chunk.
+    ///
+    /// Also returns the variables the function captures.
+    fn end_function(
+        &mut self,
+        mut chunk: LoxBytecodeChunk,
+    ) -> (LoxBytecodeObjectHandle, Vec<LoxBytecodeUpvalueReference>) {
+        self.emit_return(&mut chunk);
+        let scope = self
+            .scopes
+            .pop()
+            .expect("compiler expects a function being compiled");
+        let function =
+            LoxBytecodeFunction::new(scope.name, scope.arity, scope.upvalues.len(), chunk);
         #[cfg(feature = "code-printing")]
         {
             if !self.parser.had_error {
-                disassemble_chunk(chunk, "code");
+                let mut disassembly = String::new();
+                disassemble_chunk(
+                    &mut disassembly,
+                    function.get_chunk(),
+                    function.get_name().unwrap_or("<script>"),
+                    self.heap,
+                )
+                .expect("writing to a String cannot fail");
+                print!("{}", disassembly);
             }
         }
+        (
+            self.allocate(LoxBytecodeObject::Function(function)),
+            scope.upvalues,
+        )
     }
 
     fn emit_constant(
@@ -508,14 +703,50 @@ impl LoxBytecodeCompiler {
     ) -> LoxBytecodeOpcode {
         let constant = chunk.add_constant(value);
         if constant > u8::MAX as usize {
-            self.error("Too many constants in one chunk", source);
+            self.error("Too many constants in one chunk.", source);
             LoxBytecodeOpcode::Value(0)
         } else {
             LoxBytecodeOpcode::Value(constant)
         }
     }
 
+    /// Emit a jump instruction with a placeholder operand, returning the offset
+    /// of this operand for `patch_jump`.
+    fn emit_jump(&self, chunk: &mut LoxBytecodeChunk, instruction: LoxBytecodeOpcode) -> usize {
+        self.emit_bytes(chunk, instruction, LoxBytecodeOpcode::Value(0));
+        chunk.get_size() - 1
+    }
+
+    /// Make the jump whose operand is at the given offset land on the next instruction.
+    fn patch_jump(&mut self, source: &str, chunk: &mut LoxBytecodeChunk, offset: usize) {
+        let jump = chunk.get_size() - offset - 1;
+        if jump > LOX_JUMP_MAX {
+            self.error("Too much code to jump over.", source);
+        }
+        chunk.patch(offset, LoxBytecodeOpcode::Value(jump));
+    }
+
+    fn emit_loop(&mut self, source: &str, chunk: &mut LoxBytecodeChunk, loop_start: usize) {
+        self.emit_byte(chunk, LoxBytecodeOpcode::Loop);
+        // also jump back over the operand itself
+        let offset = chunk.get_size() - loop_start + 1;
+        if offset > LOX_JUMP_MAX {
+            self.error("Loop body too large.", source);
+        }
+        self.emit_byte(chunk, LoxBytecodeOpcode::Value(offset));
+    }
+
     fn emit_return(&self, chunk: &mut LoxBytecodeChunk) {
+        if self.scope().kind == LoxBytecodeFunctionKind::Initializer {
+            // an initializer always returns its instance
+            self.emit_bytes(
+                chunk,
+                LoxBytecodeOpcode::GetLocal,
+                LoxBytecodeOpcode::Value(0),
+            );
+        } else {
+            self.emit_byte(chunk, LoxBytecodeOpcode::Nil);
+        }
         self.emit_byte(chunk, LoxBytecodeOpcode::Return);
     }
 
@@ -530,7 +761,829 @@ impl LoxBytecodeCompiler {
     }
 
     fn emit_byte(&self, chunk: &mut LoxBytecodeChunk, opcode: LoxBytecodeOpcode) {
-        chunk.append(opcode, self.parser.previous.get_line_number());
+        Self::emit_byte_at(chunk, opcode, self.parser.previous.get_span());
+    }
+
+    fn emit_byte_at(chunk: &mut LoxBytecodeChunk, opcode: LoxBytecodeOpcode, span: LoxSourceSpan) {
+        chunk.append(opcode, span);
+    }
+
+    fn handle_declaration(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        if self.match_kind(&LoxBytecodeTokenType::Class, source, lexer)? {
+            self.handle_class_declaration(source, lexer, chunk)?;
+        } else if self.match_kind(&LoxBytecodeTokenType::Fun, source, lexer)? {
+            self.handle_function_declaration(source, lexer, chunk)?;
+        } else if self.match_kind(&LoxBytecodeTokenType::Var, source, lexer)? {
+            self.handle_variable_declaration(source, lexer, chunk)?;
+        } else {
+            self.handle_statement(source, lexer, chunk)?;
+        }
+        if self.parser.panic_mode {
+            self.synchronize(source, lexer)?;
+        }
+        Ok(())
+    }
+
+    fn handle_class_declaration(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        self.consume_kind(
+            &LoxBytecodeTokenType::Identifier,
+            source,
+            lexer,
+            "Expect class name.",
+        )?;
+        let class_name = self.parser.previous.get_lexeme(source).to_string();
+        let name_constant = self.identifier_constant(source, chunk, &class_name);
+        self.declare_variable(source);
+        self.emit_bytes(chunk, LoxBytecodeOpcode::Class, name_constant.clone());
+        self.define_variable(chunk, name_constant);
+        self.classes.push(LoxBytecodeClassScope {
+            has_superclass: false,
+        });
+
+        if self.match_kind(&LoxBytecodeTokenType::Less, source, lexer)? {
+            self.consume_kind(
+                &LoxBytecodeTokenType::Identifier,
+                source,
+                lexer,
+                "Expect superclass name.",
+            )?;
+            self.handle_variable(source, lexer, chunk, false)?;
+            if self.parser.previous.get_lexeme(source) == class_name {
+                self.error("A class can't inherit from itself.", source);
+            }
+            // methods capture the superclass through a local named after the keyword
+            self.begin_scope();
+            self.add_local(source, "super".to_string());
+            self.define_variable(chunk, LoxBytecodeOpcode::Value(0));
+            self.named_variable(source, lexer, chunk, &class_name, false)?;
+            self.emit_byte(chunk, LoxBytecodeOpcode::Inherit);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // keep the class on the stack while binding its methods
+        self.named_variable(source, lexer, chunk, &class_name, false)?;
+        self.consume_kind(
+            &LoxBytecodeTokenType::LeftBrace,
+            source,
+            lexer,
+            "Expect '{' before class body.",
+        )?;
+        while !self.check(&LoxBytecodeTokenType::RightBrace)
+            && !self.check(&LoxBytecodeTokenType::EndOfFile)
+        {
+            self.handle_method(source, lexer, chunk)?;
+        }
+        self.consume_kind(
+            &LoxBytecodeTokenType::RightBrace,
+            source,
+            lexer,
+            "Expect '}' after class body.",
+        )?;
+        self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+
+        if self.classes.pop().is_some_and(|class| class.has_superclass) {
+            self.end_scope(chunk);
+        }
+        Ok(())
+    }
+
+    fn handle_method(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        self.consume_kind(
+            &LoxBytecodeTokenType::Identifier,
+            source,
+            lexer,
+            "Expect method name.",
+        )?;
+        let name = self.parser.previous.get_lexeme(source);
+        let constant = self.identifier_constant(source, chunk, name);
+        let kind = if name == "init" {
+            LoxBytecodeFunctionKind::Initializer
+        } else {
+            LoxBytecodeFunctionKind::Method
+        };
+        self.handle_function(source, lexer, chunk, kind)?;
+        self.emit_bytes(chunk, LoxBytecodeOpcode::Method, constant);
+        Ok(())
+    }
+
+    fn handle_function_declaration(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        let global = self.parse_variable(source, lexer, chunk, "Expect function name.")?;
+        // a function can refer to itself in its body
+        self.mark_initialized();
+        self.handle_function(source, lexer, chunk, LoxBytecodeFunctionKind::Function)?;
+        self.define_variable(chunk, global);
+        Ok(())
+    }
+
+    /// Compile a function's parameters and body into its own chunk, then
+    /// emit the resulting function as a constant.
+    fn handle_function(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+        kind: LoxBytecodeFunctionKind,
+    ) -> BResult<()> {
+        let name = self.parser.previous.get_lexeme(source).to_string();
+        self.scopes
+            .push(LoxBytecodeFunctionScope::new(kind, Some(name)));
+        let mut function_chunk = LoxBytecodeChunk::default();
+        self.begin_scope();
+
+        self.consume_kind(
+            &LoxBytecodeTokenType::LeftParenthesis,
+            source,
+            lexer,
+            "Expect '(' after function name.",
+        )?;
+        if !self.check(&LoxBytecodeTokenType::RightParenthesis) {
+            loop {
+                self.scope_mut().arity += 1;
+                if self.scope().arity > LOX_PARAMETERS_MAX {
+                    self.error_at_current("Can't have more than 255 parameters.", source);
+                }
+                let constant = self.parse_variable(
+                    source,
+                    lexer,
+                    &mut function_chunk,
+                    "Expect parameter name.",
+                )?;
+                self.define_variable(&mut function_chunk, constant);
+                if !self.match_kind(&LoxBytecodeTokenType::Comma, source, lexer)? {
+                    break;
+                }
+            }
+        }
+        self.consume_kind(
+            &LoxBytecodeTokenType::RightParenthesis,
+            source,
+            lexer,
+            "Expect ')' after parameters.",
+        )?;
+        self.consume_kind(
+            &LoxBytecodeTokenType::LeftBrace,
+            source,
+            lexer,
+            "Expect '{' before function body.",
+        )?;
+        self.handle_block(source, lexer, &mut function_chunk)?;
+
+        // no need to end the outermost scope: the call frame is discarded on return
+        let (function, upvalues) = self.end_function(function_chunk);
+        let constant = self.build_constant(source, chunk, LoxBytecodeValue::Object(function));
+        self.emit_bytes(chunk, LoxBytecodeOpcode::Closure, constant);
+        for upvalue in upvalues {
+            self.emit_bytes(
+                chunk,
+                LoxBytecodeOpcode::Value(upvalue.is_local as usize),
+                LoxBytecodeOpcode::Value(upvalue.index),
+            );
+        }
+        Ok(())
+    }
+
+    fn handle_variable_declaration(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        let global = self.parse_variable(source, lexer, chunk, "Expect variable name.")?;
+        if self.match_kind(&LoxBytecodeTokenType::Equal, source, lexer)? {
+            self.handle_expression(source, lexer, chunk)?;
+        } else {
+            self.emit_byte(chunk, LoxBytecodeOpcode::Nil);
+        }
+        self.consume_kind(
+            &LoxBytecodeTokenType::Semicolon,
+            source,
+            lexer,
+            "Expect ';' after variable declaration.",
+        )?;
+        self.define_variable(chunk, global);
+        Ok(())
+    }
+
+    fn handle_statement(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        if self.match_kind(&LoxBytecodeTokenType::Print, source, lexer)? {
+            self.handle_print_statement(source, lexer, chunk)
+        } else if self.match_kind(&LoxBytecodeTokenType::For, source, lexer)? {
+            self.handle_for_statement(source, lexer, chunk)
+        } else if self.match_kind(&LoxBytecodeTokenType::If, source, lexer)? {
+            self.handle_if_statement(source, lexer, chunk)
+        } else if self.match_kind(&LoxBytecodeTokenType::Return, source, lexer)? {
+            self.handle_return_statement(source, lexer, chunk)
+        } else if self.match_kind(&LoxBytecodeTokenType::While, source, lexer)? {
+            self.handle_while_statement(source, lexer, chunk)
+        } else if self.match_kind(&LoxBytecodeTokenType::LeftBrace, source, lexer)? {
+            self.begin_scope();
+            self.handle_block(source, lexer, chunk)?;
+            self.end_scope(chunk);
+            Ok(())
+        } else {
+            self.handle_expression_statement(source, lexer, chunk)
+        }
+    }
+
+    fn handle_block(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        while !self.check(&LoxBytecodeTokenType::RightBrace)
+            && !self.check(&LoxBytecodeTokenType::EndOfFile)
+        {
+            self.handle_declaration(source, lexer, chunk)?;
+        }
+        self.consume_kind(
+            &LoxBytecodeTokenType::RightBrace,
+            source,
+            lexer,
+            "Expect '}' after block.",
+        )
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_mut().scope_depth += 1;
+    }
+
+    /// Leave the current block, discarding its local variables from the stack.
+    fn end_scope(&mut self, chunk: &mut LoxBytecodeChunk) {
+        self.scope_mut().scope_depth -= 1;
+        let scope_depth = self.scope().scope_depth;
+        while let Some(local) = self
+            .scope()
+            .locals
+            .last()
+            .filter(|local| local.depth > Some(scope_depth))
+        {
+            if local.is_captured {
+                // hoist the variable to the heap for the closures referencing it
+                self.emit_byte(chunk, LoxBytecodeOpcode::CloseUpvalue);
+            } else {
+                self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+            }
+            self.scope_mut().locals.pop();
+        }
+    }
+
+    fn handle_if_statement(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        self.consume_kind(
+            &LoxBytecodeTokenType::LeftParenthesis,
+            source,
+            lexer,
+            "Expect '(' after 'if'.",
+        )?;
+        self.handle_expression(source, lexer, chunk)?;
+        self.consume_kind(
+            &LoxBytecodeTokenType::RightParenthesis,
+            source,
+            lexer,
+            "Expect ')' after condition.",
+        )?;
+
+        let then_jump = self.emit_jump(chunk, LoxBytecodeOpcode::JumpIfFalse);
+        self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+        self.handle_statement(source, lexer, chunk)?;
+        let else_jump = self.emit_jump(chunk, LoxBytecodeOpcode::Jump);
+
+        self.patch_jump(source, chunk, then_jump);
+        self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+        if self.match_kind(&LoxBytecodeTokenType::Else, source, lexer)? {
+            self.handle_statement(source, lexer, chunk)?;
+        }
+        self.patch_jump(source, chunk, else_jump);
+        Ok(())
+    }
+
+    fn handle_return_statement(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        if self.scope().kind == LoxBytecodeFunctionKind::Script {
+            self.error("Can't return from top-level code.", source);
+        }
+        if self.match_kind(&LoxBytecodeTokenType::Semicolon, source, lexer)? {
+            self.emit_return(chunk);
+        } else {
+            if self.scope().kind == LoxBytecodeFunctionKind::Initializer {
+                self.error("Can't return a value from an initializer.", source);
+            }
+            self.handle_expression(source, lexer, chunk)?;
+            self.consume_kind(
+                &LoxBytecodeTokenType::Semicolon,
+                source,
+                lexer,
+                "Expect ';' after return value.",
+            )?;
+            self.emit_byte(chunk, LoxBytecodeOpcode::Return);
+        }
+        Ok(())
+    }
+
+    fn handle_while_statement(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        let loop_start = chunk.get_size();
+        self.consume_kind(
+            &LoxBytecodeTokenType::LeftParenthesis,
+            source,
+            lexer,
+            "Expect '(' after 'while'.",
+        )?;
+        self.handle_expression(source, lexer, chunk)?;
+        self.consume_kind(
+            &LoxBytecodeTokenType::RightParenthesis,
+            source,
+            lexer,
+            "Expect ')' after condition.",
+        )?;
+
+        let exit_jump = self.emit_jump(chunk, LoxBytecodeOpcode::JumpIfFalse);
+        self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+        self.handle_statement(source, lexer, chunk)?;
+        self.emit_loop(source, chunk, loop_start);
+
+        self.patch_jump(source, chunk, exit_jump);
+        self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+        Ok(())
+    }
+
+    fn handle_for_statement(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        // the initializer variable is scoped to the loop
+        self.begin_scope();
+        self.consume_kind(
+            &LoxBytecodeTokenType::LeftParenthesis,
+            source,
+            lexer,
+            "Expect '(' after 'for'.",
+        )?;
+        if self.match_kind(&LoxBytecodeTokenType::Semicolon, source, lexer)? {
+            // no initializer
+        } else if self.match_kind(&LoxBytecodeTokenType::Var, source, lexer)? {
+            self.handle_variable_declaration(source, lexer, chunk)?;
+        } else {
+            self.handle_expression_statement(source, lexer, chunk)?;
+        }
+
+        let mut loop_start = chunk.get_size();
+        let mut exit_jump = None;
+        if !self.match_kind(&LoxBytecodeTokenType::Semicolon, source, lexer)? {
+            self.handle_expression(source, lexer, chunk)?;
+            self.consume_kind(
+                &LoxBytecodeTokenType::Semicolon,
+                source,
+                lexer,
+                "Expect ';' after loop condition.",
+            )?;
+            exit_jump = Some(self.emit_jump(chunk, LoxBytecodeOpcode::JumpIfFalse));
+            self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+        }
+
+        if !self.match_kind(&LoxBytecodeTokenType::RightParenthesis, source, lexer)? {
+            // the increment is compiled before the body but runs after it
+            let body_jump = self.emit_jump(chunk, LoxBytecodeOpcode::Jump);
+            let increment_start = chunk.get_size();
+            self.handle_expression(source, lexer, chunk)?;
+            self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+            self.consume_kind(
+                &LoxBytecodeTokenType::RightParenthesis,
+                source,
+                lexer,
+                "Expect ')' after for clauses.",
+            )?;
+            self.emit_loop(source, chunk, loop_start);
+            loop_start = increment_start;
+            self.patch_jump(source, chunk, body_jump);
+        }
+
+        self.handle_statement(source, lexer, chunk)?;
+        self.emit_loop(source, chunk, loop_start);
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(source, chunk, exit_jump);
+            self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+        }
+        self.end_scope(chunk);
+        Ok(())
+    }
+
+    fn handle_print_statement(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        self.handle_expression(source, lexer, chunk)?;
+        self.consume_kind(
+            &LoxBytecodeTokenType::Semicolon,
+            source,
+            lexer,
+            "Expect ';' after value.",
+        )?;
+        self.emit_byte(chunk, LoxBytecodeOpcode::Print);
+        Ok(())
+    }
+
+    fn handle_expression_statement(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        self.handle_expression(source, lexer, chunk)?;
+        self.consume_kind(
+            &LoxBytecodeTokenType::Semicolon,
+            source,
+            lexer,
+            "Expect ';' after expression.",
+        )?;
+        self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+        Ok(())
+    }
+
+    /// Parse a variable name, returning the operand referencing it.
+    ///
+    /// Local variables are not referenced by name: the returned operand is then unused.
+    fn parse_variable(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+        error_message: &str,
+    ) -> BResult<LoxBytecodeOpcode> {
+        self.consume_kind(
+            &LoxBytecodeTokenType::Identifier,
+            source,
+            lexer,
+            error_message,
+        )?;
+        self.declare_variable(source);
+        if self.scope().scope_depth > 0 {
+            return Ok(LoxBytecodeOpcode::Value(0));
+        }
+        Ok(self.identifier_constant(source, chunk, self.parser.previous.get_lexeme(source)))
+    }
+
+    /// Record the variable just parsed as a local, if declared inside of a block.
+    fn declare_variable(&mut self, source: &str) {
+        let scope_depth = self.scope().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+        let name = self.parser.previous.get_lexeme(source).to_string();
+        let is_duplicate = self
+            .scope()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name);
+        if is_duplicate {
+            self.error("Already a variable with this name in this scope.", source);
+        }
+        self.add_local(source, name);
+    }
+
+    fn add_local(&mut self, source: &str, name: String) {
+        if self.scope().locals.len() == LOX_LOCALS_MAX {
+            self.error("Too many local variables in function.", source);
+            return;
+        }
+        self.scope_mut().locals.push(LoxBytecodeLocal {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    /// Find the stack slot of the given local variable of the function at
+    /// `scope_index`, if any.
+    fn resolve_local(&mut self, scope_index: usize, source: &str, name: &str) -> Option<usize> {
+        let (slot, local) = self.scopes[scope_index]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)?;
+        if local.depth.is_none() {
+            self.error("Can't read local variable in its own initializer.", source);
+        }
+        Some(slot)
+    }
+
+    /// Find the given variable in the functions enclosing the one at `scope_index`,
+    /// capturing it through every function in between.
+    fn resolve_upvalue(&mut self, scope_index: usize, source: &str, name: &str) -> Option<usize> {
+        if scope_index == 0 {
+            return None;
+        }
+        if let Some(slot) = self.resolve_local(scope_index - 1, source, name) {
+            self.scopes[scope_index - 1].locals[slot].is_captured = true;
+            return Some(self.add_upvalue(scope_index, source, slot, true));
+        }
+        let upvalue = self.resolve_upvalue(scope_index - 1, source, name)?;
+        Some(self.add_upvalue(scope_index, source, upvalue, false))
+    }
+
+    fn add_upvalue(
+        &mut self,
+        scope_index: usize,
+        source: &str,
+        index: usize,
+        is_local: bool,
+    ) -> usize {
+        let reference = LoxBytecodeUpvalueReference { index, is_local };
+        let upvalues = &mut self.scopes[scope_index].upvalues;
+        if let Some(existing) = upvalues.iter().position(|upvalue| *upvalue == reference) {
+            return existing;
+        }
+        if upvalues.len() == LOX_UPVALUES_MAX {
+            self.error("Too many closure variables in function.", source);
+            return 0;
+        }
+        upvalues.push(reference);
+        upvalues.len() - 1
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope = self.scope_mut();
+        if scope.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = scope.locals.last_mut() {
+            local.depth = Some(scope.scope_depth);
+        }
+    }
+
+    fn identifier_constant(
+        &mut self,
+        source: &str,
+        chunk: &mut LoxBytecodeChunk,
+        name: &str,
+    ) -> LoxBytecodeOpcode {
+        let name = self.intern_string(name);
+        let identifier = chunk.add_identifier(name);
+        if identifier > u8::MAX as usize {
+            self.error("Too many constants in one chunk.", source);
+            LoxBytecodeOpcode::Value(0)
+        } else {
+            LoxBytecodeOpcode::Value(identifier)
+        }
+    }
+
+    fn define_variable(&mut self, chunk: &mut LoxBytecodeChunk, global: LoxBytecodeOpcode) {
+        if self.scope().scope_depth > 0 {
+            // the value of the initializer already sits in the local's stack slot
+            self.mark_initialized();
+            return;
+        }
+        self.emit_bytes(chunk, LoxBytecodeOpcode::DefineGlobal, global);
+    }
+
+    fn handle_variable(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+        can_assign: bool,
+    ) -> BResult<()> {
+        let name = self.parser.previous.get_lexeme(source);
+        self.named_variable(source, lexer, chunk, name, can_assign)
+    }
+
+    fn named_variable(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+        name: &str,
+        can_assign: bool,
+    ) -> BResult<()> {
+        let scope_index = self.scopes.len() - 1;
+        let (get_operation, set_operation, operand) =
+            if let Some(slot) = self.resolve_local(scope_index, source, name) {
+                (
+                    LoxBytecodeOpcode::GetLocal,
+                    LoxBytecodeOpcode::SetLocal,
+                    LoxBytecodeOpcode::Value(slot),
+                )
+            } else if let Some(index) = self.resolve_upvalue(scope_index, source, name) {
+                (
+                    LoxBytecodeOpcode::GetUpvalue,
+                    LoxBytecodeOpcode::SetUpvalue,
+                    LoxBytecodeOpcode::Value(index),
+                )
+            } else {
+                (
+                    LoxBytecodeOpcode::GetGlobal,
+                    LoxBytecodeOpcode::SetGlobal,
+                    self.identifier_constant(source, chunk, name),
+                )
+            };
+        if can_assign && self.match_kind(&LoxBytecodeTokenType::Equal, source, lexer)? {
+            self.handle_expression(source, lexer, chunk)?;
+            self.emit_bytes(chunk, set_operation, operand);
+        } else {
+            self.emit_bytes(chunk, get_operation, operand);
+        }
+        Ok(())
+    }
+
+    fn handle_call(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        let arguments_count = self.handle_arguments_list(source, lexer, chunk)?;
+        self.emit_bytes(
+            chunk,
+            LoxBytecodeOpcode::Call,
+            LoxBytecodeOpcode::Value(arguments_count),
+        );
+        Ok(())
+    }
+
+    fn handle_dot(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+        can_assign: bool,
+    ) -> BResult<()> {
+        self.consume_kind(
+            &LoxBytecodeTokenType::Identifier,
+            source,
+            lexer,
+            "Expect property name after '.'.",
+        )?;
+        let name = self.identifier_constant(source, chunk, self.parser.previous.get_lexeme(source));
+        if can_assign && self.match_kind(&LoxBytecodeTokenType::Equal, source, lexer)? {
+            self.handle_expression(source, lexer, chunk)?;
+            self.emit_bytes(chunk, LoxBytecodeOpcode::SetProperty, name);
+        } else if self.match_kind(&LoxBytecodeTokenType::LeftParenthesis, source, lexer)? {
+            let arguments_count = self.handle_arguments_list(source, lexer, chunk)?;
+            self.emit_bytes(chunk, LoxBytecodeOpcode::Invoke, name);
+            self.emit_byte(chunk, LoxBytecodeOpcode::Value(arguments_count));
+        } else {
+            self.emit_bytes(chunk, LoxBytecodeOpcode::GetProperty, name);
+        }
+        Ok(())
+    }
+
+    fn handle_this(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.", source);
+            return Ok(());
+        }
+        // `this` cannot be assigned to
+        self.handle_variable(source, lexer, chunk, false)
+    }
+
+    fn handle_super(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class.", source),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.", source)
+            }
+            _ => (),
+        }
+        self.consume_kind(
+            &LoxBytecodeTokenType::Dot,
+            source,
+            lexer,
+            "Expect '.' after 'super'.",
+        )?;
+        self.consume_kind(
+            &LoxBytecodeTokenType::Identifier,
+            source,
+            lexer,
+            "Expect superclass method name.",
+        )?;
+        let name = self.identifier_constant(source, chunk, self.parser.previous.get_lexeme(source));
+
+        self.named_variable(source, lexer, chunk, "this", false)?;
+        if self.match_kind(&LoxBytecodeTokenType::LeftParenthesis, source, lexer)? {
+            let arguments_count = self.handle_arguments_list(source, lexer, chunk)?;
+            self.named_variable(source, lexer, chunk, "super", false)?;
+            self.emit_bytes(chunk, LoxBytecodeOpcode::SuperInvoke, name);
+            self.emit_byte(chunk, LoxBytecodeOpcode::Value(arguments_count));
+        } else {
+            self.named_variable(source, lexer, chunk, "super", false)?;
+            self.emit_bytes(chunk, LoxBytecodeOpcode::GetSuper, name);
+        }
+        Ok(())
+    }
+
+    fn handle_arguments_list(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<usize> {
+        let mut arguments_count = 0;
+        if !self.check(&LoxBytecodeTokenType::RightParenthesis) {
+            loop {
+                self.handle_expression(source, lexer, chunk)?;
+                if arguments_count == LOX_PARAMETERS_MAX {
+                    self.error("Can't have more than 255 arguments.", source);
+                }
+                arguments_count += 1;
+                if !self.match_kind(&LoxBytecodeTokenType::Comma, source, lexer)? {
+                    break;
+                }
+            }
+        }
+        self.consume_kind(
+            &LoxBytecodeTokenType::RightParenthesis,
+            source,
+            lexer,
+            "Expect ')' after arguments.",
+        )?;
+        Ok(arguments_count)
+    }
+
+    fn handle_and(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        // short-circuit: a falsy left operand is the result
+        let end_jump = self.emit_jump(chunk, LoxBytecodeOpcode::JumpIfFalse);
+        self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+        self.parse_precedence(source, LoxBytecodeOperatorPrecedence::And, lexer, chunk)?;
+        self.patch_jump(source, chunk, end_jump);
+        Ok(())
+    }
+
+    fn handle_or(
+        &mut self,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+        chunk: &mut LoxBytecodeChunk,
+    ) -> BResult<()> {
+        // short-circuit: a truthy left operand is the result
+        let else_jump = self.emit_jump(chunk, LoxBytecodeOpcode::JumpIfFalse);
+        let end_jump = self.emit_jump(chunk, LoxBytecodeOpcode::Jump);
+        self.patch_jump(source, chunk, else_jump);
+        self.emit_byte(chunk, LoxBytecodeOpcode::Pop);
+        self.parse_precedence(source, LoxBytecodeOperatorPrecedence::Or, lexer, chunk)?;
+        self.patch_jump(source, chunk, end_jump);
+        Ok(())
     }
 
     fn handle_binary(
@@ -539,30 +1592,34 @@ impl LoxBytecodeCompiler {
         lexer: &mut LoxBytecodeLexer,
         chunk: &mut LoxBytecodeChunk,
     ) -> BResult<()> {
-        let operator_kind = self.parser.previous.get_kind().clone();
-        let rule = self.get_rule(&operator_kind)?;
+        let operator = self.parser.previous.clone();
+        let rule = self.get_rule(&operator)?;
         let precedence =
             LoxBytecodeOperatorPrecedence::from_usize(rule.precedence.clone() as usize + 1)
                 .expect("compiler expects a valid value for LoxBytecodeOperatorPrecedence");
         self.parse_precedence(source, precedence, lexer, chunk)?;
-        match operator_kind {
+        let opcodes = match operator.get_kind() {
             LoxBytecodeTokenType::BangEqual => {
-                self.emit_bytes(chunk, LoxBytecodeOpcode::Equal, LoxBytecodeOpcode::Not)
+                vec![LoxBytecodeOpcode::Equal, LoxBytecodeOpcode::Not]
             }
-            LoxBytecodeTokenType::EqualEqual => self.emit_byte(chunk, LoxBytecodeOpcode::Equal),
-            LoxBytecodeTokenType::Greater => self.emit_byte(chunk, LoxBytecodeOpcode::Greater),
+            LoxBytecodeTokenType::EqualEqual => vec![LoxBytecodeOpcode::Equal],
+            LoxBytecodeTokenType::Greater => vec![LoxBytecodeOpcode::Greater],
             LoxBytecodeTokenType::GreaterEqual => {
-                self.emit_bytes(chunk, LoxBytecodeOpcode::Less, LoxBytecodeOpcode::Not)
+                vec![LoxBytecodeOpcode::Less, LoxBytecodeOpcode::Not]
             }
-            LoxBytecodeTokenType::Less => self.emit_byte(chunk, LoxBytecodeOpcode::Less),
+            LoxBytecodeTokenType::Less => vec![LoxBytecodeOpcode::Less],
             LoxBytecodeTokenType::LessEqual => {
-                self.emit_bytes(chunk, LoxBytecodeOpcode::Greater, LoxBytecodeOpcode::Not)
+                vec![LoxBytecodeOpcode::Greater, LoxBytecodeOpcode::Not]
             }
-            LoxBytecodeTokenType::Plus => self.emit_byte(chunk, LoxBytecodeOpcode::Add),
-            LoxBytecodeTokenType::Minus => self.emit_byte(chunk, LoxBytecodeOpcode::Subtract),
-            LoxBytecodeTokenType::Star => self.emit_byte(chunk, LoxBytecodeOpcode::Multiply),
-            LoxBytecodeTokenType::Slash => self.emit_byte(chunk, LoxBytecodeOpcode::Divide),
+            LoxBytecodeTokenType::Plus => vec![LoxBytecodeOpcode::Add],
+            LoxBytecodeTokenType::Minus => vec![LoxBytecodeOpcode::Subtract],
+            LoxBytecodeTokenType::Star => vec![LoxBytecodeOpcode::Multiply],
+            LoxBytecodeTokenType::Slash => vec![LoxBytecodeOpcode::Divide],
             _ => unreachable!(),
+        };
+        // runtime errors point at the operator
+        for opcode in opcodes {
+            Self::emit_byte_at(chunk, opcode, operator.get_span());
         }
         Ok(())
     }
@@ -573,15 +1630,16 @@ impl LoxBytecodeCompiler {
         lexer: &mut LoxBytecodeLexer,
         chunk: &mut LoxBytecodeChunk,
     ) -> BResult<()> {
-        let operator_kind = self.parser.previous.get_kind().clone();
+        let operator = self.parser.previous.clone();
         // compile the operand
         self.parse_precedence(source, LoxBytecodeOperatorPrecedence::Unary, lexer, chunk)?;
         // emit the operator instruction
-        match operator_kind {
-            LoxBytecodeTokenType::Bang => self.emit_byte(chunk, LoxBytecodeOpcode::Not),
-            LoxBytecodeTokenType::Minus => self.emit_byte(chunk, LoxBytecodeOpcode::Negate),
+        let opcode = match operator.get_kind() {
+            LoxBytecodeTokenType::Bang => LoxBytecodeOpcode::Not,
+            LoxBytecodeTokenType::Minus => LoxBytecodeOpcode::Negate,
             _ => unreachable!(),
         };
+        Self::emit_byte_at(chunk, opcode, operator.get_span());
         Ok(())
     }
 
@@ -618,14 +1676,25 @@ impl LoxBytecodeCompiler {
 
     fn handle_number(&mut self, source: &str, chunk: &mut LoxBytecodeChunk) -> BResult<()> {
         let lexeme = self.parser.previous.get_lexeme(source);
-        let value: f64 = lexeme
-            .parse()
-            .map_err(|_| LoxBytecodeInterpreterError::ParserInvalidNumber(lexeme.into()))?;
+        let value: f64 = lexeme.parse().map_err(|_| {
+            LoxBytecodeInterpreterError::ParserInvalidNumber(
+                lexeme.into(),
+                self.parser.previous.get_span(),
+            )
+        })?;
         self.emit_constant(source, chunk, LoxBytecodeValue::Number(value));
         Ok(())
     }
 
-    fn handle_literal(&mut self, source: &str, chunk: &mut LoxBytecodeChunk) -> BResult<()> {
+    fn handle_string(&mut self, source: &str, chunk: &mut LoxBytecodeChunk) -> BResult<()> {
+        // trim the surrounding quotes
+        let lexeme = self.parser.previous.get_lexeme(source);
+        let string = self.intern_string(&lexeme[1..lexeme.len() - 1]);
+        self.emit_constant(source, chunk, LoxBytecodeValue::Object(string));
+        Ok(())
+    }
+
+    fn handle_literal(&mut self, chunk: &mut LoxBytecodeChunk) -> BResult<()> {
         match self.parser.previous.get_kind() {
             LoxBytecodeTokenType::False => self.emit_byte(chunk, LoxBytecodeOpcode::False),
             LoxBytecodeTokenType::Nil => self.emit_byte(chunk, LoxBytecodeOpcode::Nil),
@@ -643,27 +1712,30 @@ impl LoxBytecodeCompiler {
         chunk: &mut LoxBytecodeChunk,
     ) -> BResult<()> {
         self.advance(source, lexer)?;
-        if let Some(prefix_rule) = self.get_rule(self.parser.previous.get_kind())?.prefix {
-            prefix_rule(self, source, lexer, chunk)?;
+        let can_assign =
+            precedence.clone() as usize <= LoxBytecodeOperatorPrecedence::Assignment as usize;
+        if let Some(prefix_rule) = self.get_rule(&self.parser.previous)?.prefix {
+            prefix_rule(self, source, lexer, chunk, can_assign)?;
         } else {
             self.error("Expect expression.", source);
             return Ok(());
         }
 
         while precedence.clone() as usize
-            <= self
-                .get_rule(self.parser.current.get_kind())?
-                .precedence
-                .clone() as usize
+            <= self.get_rule(&self.parser.current)?.precedence.clone() as usize
         {
             self.advance(source, lexer)?;
-            if let Some(infix_rule) = self.get_rule(self.parser.previous.get_kind())?.infix {
-                infix_rule(self, source, lexer, chunk)?;
+            if let Some(infix_rule) = self.get_rule(&self.parser.previous)?.infix {
+                infix_rule(self, source, lexer, chunk, can_assign)?;
             } else {
                 panic!("Compiler: infix rule expected");
             }
         }
 
+        if can_assign && self.match_kind(&LoxBytecodeTokenType::Equal, source, lexer)? {
+            self.error("Invalid assignment target.", source);
+        }
+
         Ok(())
     }
 
@@ -674,7 +1746,8 @@ impl LoxBytecodeCompiler {
             if self.parser.current.get_kind() != &LoxBytecodeTokenType::Error {
                 break;
             }
-            self.error_at_current(self.parser.current.get_lexeme(source), source);
+            let message = self.parser.current.get_error_message().unwrap_or_default();
+            self.error_at_current(message, source);
         }
         Ok(())
     }
@@ -686,7 +1759,7 @@ impl LoxBytecodeCompiler {
         lexer: &mut LoxBytecodeLexer,
         message: &str,
     ) -> BResult<()> {
-        if self.parser.current.get_kind() == kind {
+        if self.check(kind) {
             self.advance(source, lexer)?;
         } else {
             self.error_at_current(message, source);
@@ -694,10 +1767,58 @@ impl LoxBytecodeCompiler {
         Ok(())
     }
 
-    fn get_rule(&self, kind: &LoxBytecodeTokenType) -> BResult<&LoxParseRule> {
-        self.parsing_rules
-            .get(kind)
-            .ok_or_else(|| LoxBytecodeInterpreterError::CompilerUnknownRule(format!("{:?}", kind)))
+    /// If the current token has the given kind, consume it and return true.
+    fn match_kind(
+        &mut self,
+        kind: &LoxBytecodeTokenType,
+        source: &str,
+        lexer: &mut LoxBytecodeLexer,
+    ) -> BResult<bool> {
+        if self.check(kind) {
+            self.advance(source, lexer)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn check(&self, kind: &LoxBytecodeTokenType) -> bool {
+        self.parser.current.get_kind() == kind
+    }
+
+    /// Discards tokens until a probable statement boundary is found.
+    ///
+    /// Used to avoid cascade errors when encountering a compilation error.
+    fn synchronize(&mut self, source: &str, lexer: &mut LoxBytecodeLexer) -> BResult<()> {
+        self.parser.panic_mode = false;
+        while !self.check(&LoxBytecodeTokenType::EndOfFile) {
+            if self.parser.previous.get_kind() == &LoxBytecodeTokenType::Semicolon
+                || matches!(
+                    self.parser.current.get_kind(),
+                    LoxBytecodeTokenType::Class
+                        | LoxBytecodeTokenType::Fun
+                        | LoxBytecodeTokenType::Var
+                        | LoxBytecodeTokenType::For
+                        | LoxBytecodeTokenType::If
+                        | LoxBytecodeTokenType::While
+                        | LoxBytecodeTokenType::Print
+                        | LoxBytecodeTokenType::Return
+                )
+            {
+                return Ok(());
+            }
+            self.advance(source, lexer)?;
+        }
+        Ok(())
+    }
+
+    fn get_rule(&self, token: &LoxBytecodeToken) -> BResult<&LoxParseRule> {
+        self.parsing_rules.get(token.get_kind()).ok_or_else(|| {
+            LoxBytecodeInterpreterError::CompilerUnknownRule(
+                format!("{:?}", token.get_kind()),
+                token.get_span(),
+            )
+        })
     }
 
     fn error(&mut self, message: &str, source: &str) {
@@ -716,14 +1837,17 @@ impl LoxBytecodeCompiler {
         }
 
         self.parser.panic_mode = true;
-        let mut error = format!("[line {}] Error", token.get_line_number());
-        match token.get_kind() {
-            LoxBytecodeTokenType::EndOfFile => error += " at end",
-            LoxBytecodeTokenType::Error => (),
-            _ => error += format!(" at '{}'", token.get_lexeme(source)).as_str(), // TODO: check formatting
-        }
-        error += format!(": {}\n", message).as_str();
-        println!("{}", error);
+        let location = match token.get_kind() {
+            LoxBytecodeTokenType::EndOfFile => " at end".to_string(),
+            LoxBytecodeTokenType::Error => String::new(),
+            _ => format!(" at '{}'", token.get_lexeme(source)),
+        };
+        self.parser.errors.push(LoxBytecodeCompilerError::new(
+            message.to_string(),
+            location,
+            token.get_line_number(),
+            token.get_span(),
+        ));
         self.parser.had_error = true;
     }
 }
@@ -1,66 +1,343 @@
-use crate::{bytecode::LoxBytecodeOpcode, printer::LoxPrintable};
+use std::fmt::{self, Write};
 
-use super::{values::LoxBytecodeValue, LoxBytecodeChunk};
+use crate::{bytecode::LoxBytecodeOpcode, errors::BResult};
 
-pub fn disassemble_chunk(chunk: &LoxBytecodeChunk, name: &str) {
-    println!("== {} ==", name);
+use super::{
+    compiler::LoxBytecodeCompiler,
+    heap::{LoxBytecodeHeap, LoxBytecodeObject, LoxBytecodeObjectHandle},
+    lexer::LoxBytecodeLexer,
+    values::LoxBytecodeValue,
+    LoxBytecodeChunk,
+};
+
+/// Compile the given source code and disassemble the resulting script, along
+/// with every function it declares.
+pub fn disassemble_source(source: &str) -> BResult<String> {
+    let mut heap = LoxBytecodeHeap::default();
+    let mut lexer = LoxBytecodeLexer::default();
+    let script = LoxBytecodeCompiler::new(&mut heap, vec![]).compile(source, &mut lexer)?;
+    let mut disassembly = String::new();
+    disassemble_function(&mut disassembly, script, &heap).expect("writing to a String cannot fail");
+    Ok(disassembly)
+}
+
+/// Disassemble the chunk of the given function, then of the functions it declares.
+pub fn disassemble_function<W: Write>(
+    out: &mut W,
+    function: LoxBytecodeObjectHandle,
+    heap: &LoxBytecodeHeap,
+) -> fmt::Result {
+    let function = heap.as_function(function);
+    let chunk = function.get_chunk();
+    disassemble_chunk(out, chunk, function.get_name().unwrap_or("<script>"), heap)?;
+    for constant in chunk.get_constants().iter() {
+        if let LoxBytecodeValue::Object(handle) = constant {
+            if let LoxBytecodeObject::Function(_) = heap.get(*handle) {
+                writeln!(out)?;
+                disassemble_function(out, *handle, heap)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn disassemble_chunk<W: Write>(
+    out: &mut W,
+    chunk: &LoxBytecodeChunk,
+    name: &str,
+    heap: &LoxBytecodeHeap,
+) -> fmt::Result {
+    writeln!(out, "== {} ==", name)?;
     let mut offset = 0;
     while offset < chunk.get_size() {
-        offset = disassemble_instruction(chunk, offset);
+        offset = disassemble_instruction(out, chunk, offset, heap)?;
     }
+    Ok(())
 }
 
-pub fn disassemble_instruction(chunk: &LoxBytecodeChunk, offset: usize) -> usize {
-    print!("{:04}", offset);
+/// Disassemble the instruction at the given offset, returning the offset of the next one.
+pub fn disassemble_instruction<W: Write>(
+    out: &mut W,
+    chunk: &LoxBytecodeChunk,
+    offset: usize,
+    heap: &LoxBytecodeHeap,
+) -> Result<usize, fmt::Error> {
+    write!(out, "{:04} ", offset)?;
     let line_number = chunk.get_line(offset);
-    if offset > 0 && line_number == chunk.get_line(offset + 1) {
-        print!("   | ");
+    if offset > 0 && line_number == chunk.get_line(offset - 1) {
+        write!(out, "   | ")?;
     } else {
-        print!("{:04}", line_number.unwrap());
+        write!(out, "{:>4} ", line_number.unwrap_or_default())?;
     }
-    if let Some(instruction) = chunk.get_instruction(offset) {
-        match instruction {
-            LoxBytecodeOpcode::Constant => constant_instruction("OP_CONSTANT", chunk, offset),
-            LoxBytecodeOpcode::Nil => simple_instruction("OP_NIL", offset),
-            LoxBytecodeOpcode::True => simple_instruction("OP_TRUE", offset),
-            LoxBytecodeOpcode::False => simple_instruction("OP_FALSE", offset),
-            LoxBytecodeOpcode::Equal => simple_instruction("OP_EQUAL", offset),
-            LoxBytecodeOpcode::Greater => simple_instruction("OP_GREATER", offset),
-            LoxBytecodeOpcode::Less => simple_instruction("OP_LESS", offset),
-            LoxBytecodeOpcode::Add => simple_instruction("OP_ADD", offset),
-            LoxBytecodeOpcode::Subtract => simple_instruction("OP_SUBTRACT", offset),
-            LoxBytecodeOpcode::Multiply => simple_instruction("OP_MULTIPLY", offset),
-            LoxBytecodeOpcode::Divide => simple_instruction("OP_DIVIDE", offset),
-            LoxBytecodeOpcode::Not => simple_instruction("OP_NOT", offset),
-            LoxBytecodeOpcode::Negate => simple_instruction("OP_NEGATE", offset),
-            LoxBytecodeOpcode::Return => simple_instruction("OP_RETURN", offset),
-            _ => {
-                print!("Unknown opcode {:?}", instruction);
-                offset + 1
-            }
+    let instruction = match chunk.get_instruction(offset) {
+        Some(instruction) => instruction,
+        None => return Ok(offset + 1),
+    };
+    match instruction {
+        LoxBytecodeOpcode::Constant => {
+            constant_instruction(out, "OP_CONSTANT", chunk, offset, heap)
+        }
+        LoxBytecodeOpcode::Nil => simple_instruction(out, "OP_NIL", offset),
+        LoxBytecodeOpcode::True => simple_instruction(out, "OP_TRUE", offset),
+        LoxBytecodeOpcode::False => simple_instruction(out, "OP_FALSE", offset),
+        LoxBytecodeOpcode::Equal => simple_instruction(out, "OP_EQUAL", offset),
+        LoxBytecodeOpcode::Greater => simple_instruction(out, "OP_GREATER", offset),
+        LoxBytecodeOpcode::Less => simple_instruction(out, "OP_LESS", offset),
+        LoxBytecodeOpcode::Add => simple_instruction(out, "OP_ADD", offset),
+        LoxBytecodeOpcode::Subtract => simple_instruction(out, "OP_SUBTRACT", offset),
+        LoxBytecodeOpcode::Multiply => simple_instruction(out, "OP_MULTIPLY", offset),
+        LoxBytecodeOpcode::Divide => simple_instruction(out, "OP_DIVIDE", offset),
+        LoxBytecodeOpcode::Not => simple_instruction(out, "OP_NOT", offset),
+        LoxBytecodeOpcode::Negate => simple_instruction(out, "OP_NEGATE", offset),
+        LoxBytecodeOpcode::Print => simple_instruction(out, "OP_PRINT", offset),
+        LoxBytecodeOpcode::Pop => simple_instruction(out, "OP_POP", offset),
+        LoxBytecodeOpcode::GetLocal => byte_instruction(out, "OP_GET_LOCAL", chunk, offset),
+        LoxBytecodeOpcode::SetLocal => byte_instruction(out, "OP_SET_LOCAL", chunk, offset),
+        LoxBytecodeOpcode::DefineGlobal => {
+            identifier_instruction(out, "OP_DEFINE_GLOBAL", chunk, offset, heap)
+        }
+        LoxBytecodeOpcode::GetGlobal => {
+            identifier_instruction(out, "OP_GET_GLOBAL", chunk, offset, heap)
         }
+        LoxBytecodeOpcode::SetGlobal => {
+            identifier_instruction(out, "OP_SET_GLOBAL", chunk, offset, heap)
+        }
+        LoxBytecodeOpcode::Jump => jump_instruction(out, "OP_JUMP", true, chunk, offset),
+        LoxBytecodeOpcode::JumpIfFalse => {
+            jump_instruction(out, "OP_JUMP_IF_FALSE", true, chunk, offset)
+        }
+        LoxBytecodeOpcode::Loop => jump_instruction(out, "OP_LOOP", false, chunk, offset),
+        LoxBytecodeOpcode::Call => byte_instruction(out, "OP_CALL", chunk, offset),
+        LoxBytecodeOpcode::Closure => closure_instruction(out, chunk, offset, heap),
+        LoxBytecodeOpcode::GetUpvalue => byte_instruction(out, "OP_GET_UPVALUE", chunk, offset),
+        LoxBytecodeOpcode::SetUpvalue => byte_instruction(out, "OP_SET_UPVALUE", chunk, offset),
+        LoxBytecodeOpcode::CloseUpvalue => simple_instruction(out, "OP_CLOSE_UPVALUE", offset),
+        LoxBytecodeOpcode::Return => simple_instruction(out, "OP_RETURN", offset),
+        LoxBytecodeOpcode::Class => identifier_instruction(out, "OP_CLASS", chunk, offset, heap),
+        LoxBytecodeOpcode::Method => identifier_instruction(out, "OP_METHOD", chunk, offset, heap),
+        LoxBytecodeOpcode::Inherit => simple_instruction(out, "OP_INHERIT", offset),
+        LoxBytecodeOpcode::GetProperty => {
+            identifier_instruction(out, "OP_GET_PROPERTY", chunk, offset, heap)
+        }
+        LoxBytecodeOpcode::SetProperty => {
+            identifier_instruction(out, "OP_SET_PROPERTY", chunk, offset, heap)
+        }
+        LoxBytecodeOpcode::GetSuper => {
+            identifier_instruction(out, "OP_GET_SUPER", chunk, offset, heap)
+        }
+        LoxBytecodeOpcode::Invoke => invoke_instruction(out, "OP_INVOKE", chunk, offset, heap),
+        LoxBytecodeOpcode::SuperInvoke => {
+            invoke_instruction(out, "OP_SUPER_INVOKE", chunk, offset, heap)
+        }
+        LoxBytecodeOpcode::Value(value) => {
+            writeln!(out, "Unexpected operand {}", value)?;
+            Ok(offset + 1)
+        }
+    }
+}
+
+/// Get the operand at the given offset.
+fn read_operand(chunk: &LoxBytecodeChunk, offset: usize) -> usize {
+    chunk
+        .get_instruction(offset)
+        .and_then(LoxBytecodeOpcode::as_value)
+        .copied()
+        .unwrap_or_default()
+}
+
+fn simple_instruction<W: Write>(
+    out: &mut W,
+    name: &str,
+    offset: usize,
+) -> Result<usize, fmt::Error> {
+    writeln!(out, "{}", name)?;
+    Ok(offset + 1)
+}
+
+fn constant_instruction<W: Write>(
+    out: &mut W,
+    name: &str,
+    chunk: &LoxBytecodeChunk,
+    offset: usize,
+    heap: &LoxBytecodeHeap,
+) -> Result<usize, fmt::Error> {
+    let constant_index = read_operand(chunk, offset + 1);
+    write!(out, "{:<16} {:>4} '", name, constant_index)?;
+    if let Some(constant) = chunk.get_constant(constant_index) {
+        write_value(out, constant, heap)?;
+    }
+    writeln!(out, "'")?;
+    Ok(offset + 2)
+}
+
+fn byte_instruction<W: Write>(
+    out: &mut W,
+    name: &str,
+    chunk: &LoxBytecodeChunk,
+    offset: usize,
+) -> Result<usize, fmt::Error> {
+    writeln!(out, "{:<16} {:>4}", name, read_operand(chunk, offset + 1))?;
+    Ok(offset + 2)
+}
+
+/// Write a jump instruction along with the offset of its target.
+fn jump_instruction<W: Write>(
+    out: &mut W,
+    name: &str,
+    forward: bool,
+    chunk: &LoxBytecodeChunk,
+    offset: usize,
+) -> Result<usize, fmt::Error> {
+    let jump = read_operand(chunk, offset + 1);
+    let target = if forward {
+        offset + 2 + jump
     } else {
-        offset + 1
+        offset + 2 - jump
+    };
+    writeln!(out, "{:<16} {:>4} -> {}", name, offset, target)?;
+    Ok(offset + 2)
+}
+
+/// Write a closure instruction along with the variables it captures.
+fn closure_instruction<W: Write>(
+    out: &mut W,
+    chunk: &LoxBytecodeChunk,
+    offset: usize,
+    heap: &LoxBytecodeHeap,
+) -> Result<usize, fmt::Error> {
+    let constant_index = read_operand(chunk, offset + 1);
+    write!(out, "{:<16} {:>4} ", "OP_CLOSURE", constant_index)?;
+    let upvalues_count = match chunk.get_constant(constant_index) {
+        Some(constant) => {
+            write_value(out, constant, heap)?;
+            match constant {
+                LoxBytecodeValue::Object(handle) => match heap.get(*handle) {
+                    LoxBytecodeObject::Function(function) => function.get_upvalues_count(),
+                    _ => 0,
+                },
+                _ => 0,
+            }
+        }
+        None => 0,
+    };
+    writeln!(out)?;
+
+    let mut offset = offset + 2;
+    for _ in 0..upvalues_count {
+        let is_local = read_operand(chunk, offset);
+        let index = read_operand(chunk, offset + 1);
+        writeln!(
+            out,
+            "{:04}    |                     {} {}",
+            offset,
+            if is_local == 1 { "local" } else { "upvalue" },
+            index
+        )?;
+        offset += 2;
     }
+    Ok(offset)
 }
 
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{}", name);
-    offset + 1
+fn identifier_instruction<W: Write>(
+    out: &mut W,
+    name: &str,
+    chunk: &LoxBytecodeChunk,
+    offset: usize,
+    heap: &LoxBytecodeHeap,
+) -> Result<usize, fmt::Error> {
+    let identifier_index = read_operand(chunk, offset + 1);
+    writeln!(
+        out,
+        "{:<16} {:>4} '{}'",
+        name,
+        identifier_index,
+        chunk
+            .get_identifier(identifier_index)
+            .map_or("", |identifier| heap.as_interned_string(identifier))
+    )?;
+    Ok(offset + 2)
 }
 
-fn constant_instruction(name: &str, chunk: &LoxBytecodeChunk, offset: usize) -> usize {
-    let constant_index = chunk
-        .get_instruction(offset + 1)
-        .unwrap()
-        .as_value()
-        .unwrap();
-    print!("{} {:?}", name, constant_index); // TODO: check formatting
-    print_value(chunk.get_constant(*constant_index).unwrap());
-    println!();
-    offset + 2
+/// Write a method invocation along with its arguments count.
+fn invoke_instruction<W: Write>(
+    out: &mut W,
+    name: &str,
+    chunk: &LoxBytecodeChunk,
+    offset: usize,
+    heap: &LoxBytecodeHeap,
+) -> Result<usize, fmt::Error> {
+    let identifier_index = read_operand(chunk, offset + 1);
+    let arguments_count = read_operand(chunk, offset + 2);
+    writeln!(
+        out,
+        "{:<16} ({} args) {:>4} '{}'",
+        name,
+        arguments_count,
+        identifier_index,
+        chunk
+            .get_identifier(identifier_index)
+            .map_or("", |identifier| heap.as_interned_string(identifier))
+    )?;
+    Ok(offset + 3)
+}
+
+/// Write a value as `print` would, numbers included (unlike clox's `%g`).
+pub fn write_value<W: Write>(
+    out: &mut W,
+    value: &LoxBytecodeValue,
+    heap: &LoxBytecodeHeap,
+) -> fmt::Result {
+    write!(out, "{}", heap.representation(value))
 }
 
-pub fn print_value(value: &LoxBytecodeValue) {
-    print!("{}", value.representation()); // TODO: check equivalent to C-printf formatting "%g"
+#[cfg(test)]
+mod tests {
+    use super::disassemble_source;
+
+    #[test]
+    fn test_disassembly() {
+        let disassembly = disassemble_source(
+            "var a = 1;
+            fun add(b) {
+              return a + b;
+            }
+            while (a < 3) a = add(1);",
+        )
+        .unwrap();
+        assert_eq!(
+            disassembly,
+            "== <script> ==
+0000    1 OP_CONSTANT         0 '1'
+0002    | OP_DEFINE_GLOBAL    0 'a'
+0004    4 OP_CLOSURE          1 <fn add>
+0006    | OP_DEFINE_GLOBAL    1 'add'
+0008    5 OP_GET_GLOBAL       0 'a'
+0010    | OP_CONSTANT         2 '3'
+0012    | OP_LESS
+0013    | OP_JUMP_IF_FALSE   13 -> 27
+0015    | OP_POP
+0016    | OP_GET_GLOBAL       1 'add'
+0018    | OP_CONSTANT         3 '1'
+0020    | OP_CALL             1
+0022    | OP_SET_GLOBAL       0 'a'
+0024    | OP_POP
+0025    | OP_LOOP            25 -> 8
+0027    | OP_POP
+0028    | OP_NIL
+0029    | OP_RETURN
+
+== add ==
+0000    3 OP_GET_GLOBAL       0 'a'
+0002    | OP_GET_LOCAL        1
+0004    | OP_ADD
+0005    | OP_RETURN
+0006    4 OP_NIL
+0007    | OP_RETURN
+"
+        );
+        assert_eq!(
+            disassemble_source("print;").unwrap_err().to_string(),
+            "[line 1] Error at ';': Expect expression."
+        );
+    }
 }
@@ -0,0 +1,405 @@
+use std::{collections::HashMap, mem};
+
+use super::values::{
+    LoxBytecodeBoundMethod, LoxBytecodeClass, LoxBytecodeClosure, LoxBytecodeFunction,
+    LoxBytecodeInstance, LoxBytecodeNativeFunction, LoxBytecodeUpvalue, LoxBytecodeValue,
+};
+
+/// Collection threshold, in bytes, before the first garbage collection.
+const LOX_GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+/// Growth of the collection threshold relative to the bytes surviving a collection.
+const LOX_GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Reference to an object living on the heap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LoxBytecodeObjectHandle(usize);
+
+#[derive(Debug)]
+pub enum LoxBytecodeObject {
+    /// Interned string.
+    String(String),
+    Function(LoxBytecodeFunction),
+    NativeFunction(LoxBytecodeNativeFunction),
+    Closure(LoxBytecodeClosure),
+    Upvalue(LoxBytecodeUpvalue),
+    Class(LoxBytecodeClass),
+    Instance(LoxBytecodeInstance),
+    BoundMethod(LoxBytecodeBoundMethod),
+}
+
+impl LoxBytecodeObject {
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Self::String(_) => "string",
+            Self::Function(_) => "function",
+            Self::NativeFunction(_) => "native function",
+            Self::Closure(_) => "closure",
+            Self::Upvalue(_) => "upvalue",
+            Self::Class(_) => "class",
+            Self::Instance(_) => "instance",
+            Self::BoundMethod(_) => "bound method",
+        }
+    }
+
+    /// Approximate number of bytes owned by the object.
+    fn size(&self) -> usize {
+        mem::size_of::<Self>()
+            + match self {
+                Self::String(string) => string.capacity(),
+                Self::Function(function) => function.get_chunk().get_size(),
+                Self::Closure(closure) => mem::size_of_val(closure.get_upvalues()),
+                // methods and fields are added after allocation: only count the object itself
+                // so that sweeping releases what was allocated
+                Self::NativeFunction(_)
+                | Self::Upvalue(_)
+                | Self::Class(_)
+                | Self::Instance(_)
+                | Self::BoundMethod(_) => 0,
+            }
+    }
+}
+
+#[derive(Debug)]
+struct LoxBytecodeHeapEntry {
+    object: LoxBytecodeObject,
+    is_marked: bool,
+}
+
+/// Storage of all the objects allocated by the virtual machine and its compiler,
+/// reclaimed by a mark-sweep garbage collector.
+///
+/// The heap does not know its roots: its owner must call `collect` with them
+/// whenever `should_collect` says so, before allocating.
+#[derive(Debug)]
+pub struct LoxBytecodeHeap {
+    /// Objects indexed by their handle, `None` for the freed ones.
+    entries: Vec<Option<LoxBytecodeHeapEntry>>,
+    /// Freed entries, available for reuse.
+    free_entries: Vec<usize>,
+    /// Interned strings.
+    strings: HashMap<String, LoxBytecodeObjectHandle>,
+    /// Objects marked but whose references are not yet traced.
+    gray_stack: Vec<LoxBytecodeObjectHandle>,
+    bytes_allocated: usize,
+    next_collection: usize,
+}
+
+impl Default for LoxBytecodeHeap {
+    fn default() -> Self {
+        Self {
+            entries: vec![],
+            free_entries: vec![],
+            strings: HashMap::new(),
+            gray_stack: vec![],
+            bytes_allocated: 0,
+            next_collection: LOX_GC_INITIAL_THRESHOLD,
+        }
+    }
+}
+
+impl LoxBytecodeHeap {
+    /// Move the given object to the heap.
+    ///
+    /// Strings must have been looked up with `find_string` first.
+    pub fn allocate(&mut self, object: LoxBytecodeObject) -> LoxBytecodeObjectHandle {
+        let size = object.size();
+        #[cfg(feature = "log-gc")]
+        let kind_name = object.kind_name();
+        let interned = match &object {
+            LoxBytecodeObject::String(string) => Some(string.clone()),
+            _ => None,
+        };
+
+        let entry = Some(LoxBytecodeHeapEntry {
+            object,
+            is_marked: false,
+        });
+        let handle = if let Some(index) = self.free_entries.pop() {
+            self.entries[index] = entry;
+            LoxBytecodeObjectHandle(index)
+        } else {
+            self.entries.push(entry);
+            LoxBytecodeObjectHandle(self.entries.len() - 1)
+        };
+        if let Some(string) = interned {
+            self.strings.insert(string, handle);
+        }
+        self.bytes_allocated += size;
+
+        #[cfg(feature = "log-gc")]
+        println!("{:?} allocate {} for {}", handle, size, kind_name);
+        handle
+    }
+
+    /// Find the interned copy of the given string, if any.
+    pub fn find_string(&self, string: &str) -> Option<LoxBytecodeObjectHandle> {
+        self.strings.get(string).copied()
+    }
+
+    /// Has enough memory been allocated since the last collection to warrant a new one?
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "stress-gc") || self.bytes_allocated > self.next_collection
+    }
+
+    pub fn get(&self, handle: LoxBytecodeObjectHandle) -> &LoxBytecodeObject {
+        &self.entries[handle.0]
+            .as_ref()
+            .expect("heap.get expects a live object")
+            .object
+    }
+
+    pub fn get_mut(&mut self, handle: LoxBytecodeObjectHandle) -> &mut LoxBytecodeObject {
+        &mut self.entries[handle.0]
+            .as_mut()
+            .expect("heap.get_mut expects a live object")
+            .object
+    }
+
+    pub fn as_string(&self, value: &LoxBytecodeValue) -> Option<&str> {
+        match value {
+            LoxBytecodeValue::Object(handle) => match self.get(*handle) {
+                LoxBytecodeObject::String(string) => Some(string),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_interned_string(&self, handle: LoxBytecodeObjectHandle) -> &str {
+        match self.get(handle) {
+            LoxBytecodeObject::String(string) => string,
+            object => panic!("heap.as_interned_string expects a string, got {:?}", object),
+        }
+    }
+
+    pub fn as_function(&self, handle: LoxBytecodeObjectHandle) -> &LoxBytecodeFunction {
+        match self.get(handle) {
+            LoxBytecodeObject::Function(function) => function,
+            object => panic!("heap.as_function expects a function, got {:?}", object),
+        }
+    }
+
+    pub fn as_closure(&self, handle: LoxBytecodeObjectHandle) -> &LoxBytecodeClosure {
+        match self.get(handle) {
+            LoxBytecodeObject::Closure(closure) => closure,
+            object => panic!("heap.as_closure expects a closure, got {:?}", object),
+        }
+    }
+
+    pub fn as_class(&self, handle: LoxBytecodeObjectHandle) -> &LoxBytecodeClass {
+        match self.get(handle) {
+            LoxBytecodeObject::Class(class) => class,
+            object => panic!("heap.as_class expects a class, got {:?}", object),
+        }
+    }
+
+    pub fn as_class_mut(&mut self, handle: LoxBytecodeObjectHandle) -> &mut LoxBytecodeClass {
+        match self.get_mut(handle) {
+            LoxBytecodeObject::Class(class) => class,
+            object => panic!("heap.as_class_mut expects a class, got {:?}", object),
+        }
+    }
+
+    pub fn as_instance(&self, value: &LoxBytecodeValue) -> Option<&LoxBytecodeInstance> {
+        match value {
+            LoxBytecodeValue::Object(handle) => match self.get(*handle) {
+                LoxBytecodeObject::Instance(instance) => Some(instance),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_instance_mut(
+        &mut self,
+        value: &LoxBytecodeValue,
+    ) -> Option<&mut LoxBytecodeInstance> {
+        match value {
+            LoxBytecodeValue::Object(handle) => match self.get_mut(*handle) {
+                LoxBytecodeObject::Instance(instance) => Some(instance),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self, handle: LoxBytecodeObjectHandle) -> &LoxBytecodeUpvalue {
+        match self.get(handle) {
+            LoxBytecodeObject::Upvalue(upvalue) => upvalue,
+            object => panic!("heap.as_upvalue expects an upvalue, got {:?}", object),
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self, handle: LoxBytecodeObjectHandle) -> &mut LoxBytecodeUpvalue {
+        match self.get_mut(handle) {
+            LoxBytecodeObject::Upvalue(upvalue) => upvalue,
+            object => panic!("heap.as_upvalue_mut expects an upvalue, got {:?}", object),
+        }
+    }
+
+    /// Number of live objects.
+    pub fn count(&self) -> usize {
+        self.entries.len() - self.free_entries.len()
+    }
+
+    pub fn representation(&self, value: &LoxBytecodeValue) -> String {
+        match value {
+            LoxBytecodeValue::Nil => "nil".to_string(),
+            LoxBytecodeValue::Number(value) => format!("{}", value),
+            LoxBytecodeValue::Boolean(boolean) => {
+                (if *boolean { "true" } else { "false" }).to_string()
+            }
+            LoxBytecodeValue::Object(handle) => match self.get(*handle) {
+                LoxBytecodeObject::String(string) => string.clone(),
+                LoxBytecodeObject::Function(function) => function.representation(),
+                LoxBytecodeObject::NativeFunction(_) => "<native fn>".to_string(),
+                LoxBytecodeObject::Closure(closure) => {
+                    self.as_function(closure.get_function()).representation()
+                }
+                LoxBytecodeObject::Upvalue(_) => "upvalue".to_string(),
+                LoxBytecodeObject::Class(class) => class.get_name().to_string(),
+                LoxBytecodeObject::Instance(instance) => {
+                    format!(
+                        "{} instance",
+                        self.as_class(instance.get_class()).get_name()
+                    )
+                }
+                LoxBytecodeObject::BoundMethod(bound_method) => {
+                    let closure = self.as_closure(bound_method.get_method());
+                    self.as_function(closure.get_function()).representation()
+                }
+            },
+        }
+    }
+
+    /// Free every object that cannot be reached from the given roots.
+    pub fn collect<I: IntoIterator<Item = LoxBytecodeValue>>(&mut self, roots: I) {
+        #[cfg(feature = "log-gc")]
+        let bytes_before = self.bytes_allocated;
+        #[cfg(feature = "log-gc")]
+        println!("-- gc begin");
+
+        for root in roots {
+            self.mark_value(&root);
+        }
+        self.trace_references();
+        // interned strings are weak references
+        let entries = &self.entries;
+        self.strings.retain(|_, handle| {
+            entries[handle.0]
+                .as_ref()
+                .is_some_and(|entry| entry.is_marked)
+        });
+        self.sweep();
+        self.next_collection =
+            (self.bytes_allocated * LOX_GC_HEAP_GROW_FACTOR).max(LOX_GC_INITIAL_THRESHOLD);
+
+        #[cfg(feature = "log-gc")]
+        {
+            println!("-- gc end");
+            println!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                bytes_before - self.bytes_allocated,
+                bytes_before,
+                self.bytes_allocated,
+                self.next_collection
+            );
+        }
+    }
+
+    fn mark_value(&mut self, value: &LoxBytecodeValue) {
+        if let LoxBytecodeValue::Object(handle) = value {
+            self.mark_object(*handle);
+        }
+    }
+
+    fn mark_object(&mut self, handle: LoxBytecodeObjectHandle) {
+        let entry = self.entries[handle.0]
+            .as_mut()
+            .expect("heap.mark_object expects a live object");
+        if entry.is_marked {
+            return;
+        }
+        #[cfg(feature = "log-gc")]
+        println!("{:?} mark {}", handle, entry.object.kind_name());
+        entry.is_marked = true;
+        self.gray_stack.push(handle);
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(handle) = self.gray_stack.pop() {
+            self.blacken_object(handle);
+        }
+    }
+
+    /// Mark all the objects referenced by the given one.
+    fn blacken_object(&mut self, handle: LoxBytecodeObjectHandle) {
+        let references: Vec<LoxBytecodeValue> = match self.get(handle) {
+            LoxBytecodeObject::String(_) | LoxBytecodeObject::NativeFunction(_) => vec![],
+            LoxBytecodeObject::Function(function) => {
+                let chunk = function.get_chunk();
+                chunk
+                    .get_constants()
+                    .iter()
+                    .copied()
+                    .chain(
+                        chunk
+                            .get_identifiers()
+                            .iter()
+                            .copied()
+                            .map(LoxBytecodeValue::Object),
+                    )
+                    .collect()
+            }
+            LoxBytecodeObject::Closure(closure) => std::iter::once(closure.get_function())
+                .chain(closure.get_upvalues().iter().copied())
+                .map(LoxBytecodeValue::Object)
+                .collect(),
+            LoxBytecodeObject::Upvalue(LoxBytecodeUpvalue::Closed(value)) => vec![*value],
+            LoxBytecodeObject::Upvalue(LoxBytecodeUpvalue::Open(_)) => vec![],
+            LoxBytecodeObject::Class(class) => class
+                .get_methods()
+                .iter()
+                .flat_map(|(name, method)| [*name, *method])
+                .map(LoxBytecodeValue::Object)
+                .collect(),
+            LoxBytecodeObject::Instance(instance) => {
+                std::iter::once(LoxBytecodeValue::Object(instance.get_class()))
+                    .chain(
+                        instance
+                            .get_fields()
+                            .iter()
+                            .flat_map(|(name, value)| [LoxBytecodeValue::Object(*name), *value]),
+                    )
+                    .collect()
+            }
+            LoxBytecodeObject::BoundMethod(bound_method) => vec![
+                bound_method.get_receiver(),
+                LoxBytecodeValue::Object(bound_method.get_method()),
+            ],
+        };
+        for reference in references {
+            self.mark_value(&reference);
+        }
+    }
+
+    fn sweep(&mut self) {
+        for (index, slot) in self.entries.iter_mut().enumerate() {
+            match slot {
+                Some(entry) if entry.is_marked => entry.is_marked = false,
+                Some(entry) => {
+                    #[cfg(feature = "log-gc")]
+                    println!(
+                        "{:?} free {}",
+                        LoxBytecodeObjectHandle(index),
+                        entry.object.kind_name()
+                    );
+                    self.bytes_allocated -= entry.object.size();
+                    *slot = None;
+                    self.free_entries.push(index);
+                }
+                None => (),
+            }
+        }
+    }
+}
@@ -1,6 +1,4 @@
-use crate::errors::BResult;
-
-use super::LoxBytecodeChunk;
+use crate::{errors::BResult, span::LoxSourceSpan};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum LoxBytecodeTokenType {
@@ -54,35 +52,54 @@ pub enum LoxBytecodeTokenType {
 #[derive(Clone, Debug)]
 pub struct LoxBytecodeToken {
     kind: LoxBytecodeTokenType,
-    start: usize,
-    length: usize,
+    span: LoxSourceSpan,
+    /// Line where the token ends, used in error messages like clox does.
     line_number: usize,
     error_message: Option<&'static str>,
 }
 
+impl Default for LoxBytecodeToken {
+    fn default() -> Self {
+        Self {
+            kind: LoxBytecodeTokenType::EndOfFile,
+            span: LoxSourceSpan::new(0, 0, 1, 1),
+            line_number: 1,
+            error_message: None,
+        }
+    }
+}
+
 impl LoxBytecodeToken {
     pub fn get_kind(&self) -> &LoxBytecodeTokenType {
         &self.kind
     }
 
     pub fn get_start(&self) -> usize {
-        self.start
+        self.span.get_offset()
     }
 
     pub fn get_length(&self) -> usize {
-        self.length
+        self.span.get_length()
     }
 
     pub fn get_line_number(&self) -> usize {
         self.line_number
     }
 
+    pub fn get_span(&self) -> LoxSourceSpan {
+        self.span
+    }
+
     pub fn get_lexeme<'a>(&self, source: &'a str) -> &'a str {
-        &source[self.start..self.start + self.length]
+        &source[self.span.get_offset()..self.span.get_end()]
+    }
+
+    /// The message of an error token.
+    pub fn get_error_message(&self) -> Option<&'static str> {
+        self.error_message
     }
 }
 
-#[derive(Default)]
 pub struct LoxBytecodeLexer {
     /// Start index of the lexeme currently being scanned.
     start: usize,
@@ -90,24 +107,43 @@ pub struct LoxBytecodeLexer {
     current: usize,
     /// Current line number.
     line_number: usize,
+    /// Start index of the current line.
+    line_start: usize,
+    /// Line and column of the lexeme currently being scanned.
+    start_position: (usize, usize),
 }
 
-impl LoxBytecodeLexer {
-    pub fn compile(&mut self, source: &str) -> BResult<()> {
-        self.line_number = 0;
-        loop {
-            let token = self.scan_token(source);
+impl Default for LoxBytecodeLexer {
+    fn default() -> Self {
+        Self {
+            start: 0,
+            current: 0,
+            line_number: 1,
+            line_start: 0,
+            start_position: (1, 1),
         }
     }
+}
 
+impl LoxBytecodeLexer {
     pub fn scan_token(&mut self, source: &str) -> BResult<LoxBytecodeToken> {
         self.skip_whitespace(source);
         self.start = self.current;
+        // columns are counted in characters, skipping UTF-8 continuation bytes
+        let column = source.as_bytes()[self.line_start..self.start]
+            .iter()
+            .filter(|byte| (**byte & 0xC0) != 0x80)
+            .count()
+            + 1;
+        self.start_position = (self.line_number, column);
         if self.is_at_end(source) {
             return Ok(self.build_token(LoxBytecodeTokenType::EndOfFile));
         }
 
         let char = self.advance(source);
+        if Self::is_alpha(char) {
+            return Ok(self.handle_identifier(source));
+        }
         if Self::is_digit(char) {
             return self.handle_number(source);
         }
@@ -164,22 +200,16 @@ impl LoxBytecodeLexer {
     }
 
     fn identifier_type(&self, source: &str) -> LoxBytecodeTokenType {
-        match self.peek(source) {
+        match Self::char_at(source, self.start) {
             'a' => self.check_keyword(source, 1, 2, "nd", LoxBytecodeTokenType::And),
             'c' => self.check_keyword(source, 1, 4, "lass", LoxBytecodeTokenType::Class),
             'e' => self.check_keyword(source, 1, 3, "lse", LoxBytecodeTokenType::Else),
             'f' => {
                 if self.current - self.start > 1 {
-                    match self.peek_next(source) {
-                        Some('a') => {
-                            self.check_keyword(source, 2, 3, "lse", LoxBytecodeTokenType::False)
-                        }
-                        Some('o') => {
-                            self.check_keyword(source, 2, 1, "r", LoxBytecodeTokenType::For)
-                        }
-                        Some('u') => {
-                            self.check_keyword(source, 2, 1, "n", LoxBytecodeTokenType::Fun)
-                        }
+                    match Self::char_at(source, self.start + 1) {
+                        'a' => self.check_keyword(source, 2, 3, "lse", LoxBytecodeTokenType::False),
+                        'o' => self.check_keyword(source, 2, 1, "r", LoxBytecodeTokenType::For),
+                        'u' => self.check_keyword(source, 2, 1, "n", LoxBytecodeTokenType::Fun),
                         _ => LoxBytecodeTokenType::Identifier,
                     }
                 } else {
@@ -194,13 +224,9 @@ impl LoxBytecodeLexer {
             's' => self.check_keyword(source, 1, 4, "uper", LoxBytecodeTokenType::Super),
             't' => {
                 if self.current - self.start > 1 {
-                    match self.peek_next(source) {
-                        Some('h') => {
-                            self.check_keyword(source, 2, 2, "is", LoxBytecodeTokenType::This)
-                        }
-                        Some('r') => {
-                            self.check_keyword(source, 2, 2, "ue", LoxBytecodeTokenType::True)
-                        }
+                    match Self::char_at(source, self.start + 1) {
+                        'h' => self.check_keyword(source, 2, 2, "is", LoxBytecodeTokenType::This),
+                        'r' => self.check_keyword(source, 2, 2, "ue", LoxBytecodeTokenType::True),
                         _ => LoxBytecodeTokenType::Identifier,
                     }
                 } else {
@@ -231,10 +257,9 @@ impl LoxBytecodeLexer {
 
     fn handle_string(&mut self, source: &str) -> LoxBytecodeToken {
         while self.peek(source) != '"' && !self.is_at_end(source) {
-            if self.peek(source) == '\n' {
-                self.line_number += 1;
+            if self.advance(source) == '\n' {
+                self.new_line();
             }
-            self.advance(source);
         }
 
         if self.is_at_end(source) {
@@ -251,12 +276,7 @@ impl LoxBytecodeLexer {
         }
 
         // look for a fractional part
-        if self.peek(source) == '.'
-            && Self::is_digit(
-                self.peek_next(source)
-                    .expect("compiler expects a digit after number fractional separator '.'"),
-            )
-        {
+        if self.peek(source) == '.' && Self::is_digit(self.peek_next(source)) {
             self.advance(source); // consume the '.'
             while Self::is_digit(self.peek(source)) {
                 self.advance(source);
@@ -269,8 +289,7 @@ impl LoxBytecodeLexer {
     fn build_token(&self, kind: LoxBytecodeTokenType) -> LoxBytecodeToken {
         LoxBytecodeToken {
             kind,
-            start: self.start,
-            length: self.current - self.start,
+            span: self.get_lexeme_span(),
             line_number: self.line_number,
             error_message: None,
         }
@@ -279,13 +298,23 @@ impl LoxBytecodeLexer {
     fn build_token_error(&self, message: &'static str) -> LoxBytecodeToken {
         LoxBytecodeToken {
             kind: LoxBytecodeTokenType::Error,
-            start: self.start,
-            length: message.len(),
+            span: self.get_lexeme_span(),
             line_number: self.line_number,
             error_message: Some(message),
         }
     }
 
+    fn get_lexeme_span(&self) -> LoxSourceSpan {
+        let (line, column) = self.start_position;
+        LoxSourceSpan::new(self.start, self.current - self.start, line, column)
+    }
+
+    /// Move on to the next line, starting at the current character.
+    fn new_line(&mut self) {
+        self.line_number += 1;
+        self.line_start = self.current;
+    }
+
     /// Skip over whitespace, line breaks and comments.
     fn skip_whitespace(&mut self, source: &str) {
         loop {
@@ -294,20 +323,12 @@ impl LoxBytecodeLexer {
                     self.advance(source);
                 }
                 '\n' => {
-                    self.line_number += 1;
                     self.advance(source);
+                    self.new_line();
                 }
-                '/' => {
-                    if let Some(next) = self.peek_next(source) {
-                        if next == '/' {
-                            while self.peek(source) != '\n' && !self.is_at_end(source) {
-                                self.advance(source);
-                            }
-                        } else {
-                            return;
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next(source) == '/' => {
+                    while self.peek(source) != '\n' && !self.is_at_end(source) {
+                        self.advance(source);
                     }
                 }
                 _ => return,
@@ -317,10 +338,7 @@ impl LoxBytecodeLexer {
 
     fn advance(&mut self, source: &str) -> char {
         self.current += 1;
-        source
-            .chars()
-            .nth(self.current - 1)
-            .expect("compiler expects a character")
+        Self::char_at(source, self.current - 1)
     }
 
     fn match_char(&mut self, source: &str, expected: char) -> bool {
@@ -333,14 +351,20 @@ impl LoxBytecodeLexer {
     }
 
     fn peek(&self, source: &str) -> char {
-        source
-            .chars()
-            .nth(self.current)
-            .expect("compiler expects a character at current index")
+        Self::char_at(source, self.current)
     }
 
-    fn peek_next(&self, source: &str) -> Option<char> {
-        source.chars().nth(self.current + 1)
+    fn peek_next(&self, source: &str) -> char {
+        Self::char_at(source, self.current + 1)
+    }
+
+    /// The source is scanned byte by byte: any non-ASCII character can only
+    /// appear inside of string literals and comments.
+    fn char_at(source: &str, index: usize) -> char {
+        source
+            .as_bytes()
+            .get(index)
+            .map_or('\0', |byte| *byte as char)
     }
 
     fn is_at_end(&self, source: &str) -> bool {
@@ -348,10 +372,10 @@ impl LoxBytecodeLexer {
     }
 
     fn is_alpha(char: char) -> bool {
-        (char >= 'a' && char <= 'z') || (char >= 'A' && char <= 'Z') || char == '_'
+        char.is_ascii_alphabetic() || char == '_'
     }
 
     fn is_digit(char: char) -> bool {
-        char >= '0' && char <= '9'
+        char.is_ascii_digit()
     }
 }
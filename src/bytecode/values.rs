@@ -1,12 +1,226 @@
-use crate::printer::LoxPrintable;
+use std::collections::HashMap;
+
+use super::{heap::LoxBytecodeObjectHandle, LoxBytecodeChunk};
 
 pub const LOX_NUMBER_VALUE_COMPARISON_EPSILON: f64 = f64::EPSILON;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug)]
 pub enum LoxBytecodeValue {
     Nil,
     Number(f64),
     Boolean(bool),
+    /// Object living on the virtual machine's heap.
+    Object(LoxBytecodeObjectHandle),
+}
+
+/// A compiled Lox function, owning its bytecode.
+#[derive(Debug)]
+pub struct LoxBytecodeFunction {
+    /// Name of the function, `None` for the top-level script.
+    name: Option<String>,
+    arity: usize,
+    /// Number of variables captured from the enclosing functions.
+    upvalues_count: usize,
+    chunk: LoxBytecodeChunk,
+}
+
+impl LoxBytecodeFunction {
+    pub fn new(
+        name: Option<String>,
+        arity: usize,
+        upvalues_count: usize,
+        chunk: LoxBytecodeChunk,
+    ) -> Self {
+        Self {
+            name,
+            arity,
+            upvalues_count,
+            chunk,
+        }
+    }
+
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn get_arity(&self) -> usize {
+        self.arity
+    }
+
+    pub fn get_upvalues_count(&self) -> usize {
+        self.upvalues_count
+    }
+
+    pub fn get_chunk(&self) -> &LoxBytecodeChunk {
+        &self.chunk
+    }
+
+    pub fn representation(&self) -> String {
+        match &self.name {
+            Some(name) => format!("<fn {}>", name),
+            None => "<script>".to_string(),
+        }
+    }
+}
+
+/// A variable captured by a closure.
+#[derive(Debug)]
+pub enum LoxBytecodeUpvalue {
+    /// The variable still lives on the stack, at the given slot.
+    Open(usize),
+    /// The variable went out of scope and now lives in the upvalue itself.
+    Closed(LoxBytecodeValue),
+}
+
+/// A function along with the variables it captured, as created at runtime.
+#[derive(Debug)]
+pub struct LoxBytecodeClosure {
+    function: LoxBytecodeObjectHandle,
+    upvalues: Vec<LoxBytecodeObjectHandle>,
+}
+
+impl LoxBytecodeClosure {
+    pub fn new(function: LoxBytecodeObjectHandle, upvalues: Vec<LoxBytecodeObjectHandle>) -> Self {
+        Self { function, upvalues }
+    }
+
+    pub fn get_function(&self) -> LoxBytecodeObjectHandle {
+        self.function
+    }
+
+    pub fn get_upvalue(&self, index: usize) -> Option<LoxBytecodeObjectHandle> {
+        self.upvalues.get(index).copied()
+    }
+
+    pub fn get_upvalues(&self) -> &[LoxBytecodeObjectHandle] {
+        &self.upvalues
+    }
+}
+
+/// A Lox class, along with its methods.
+///
+/// Inherited methods are copied down into the subclass when it is declared.
+#[derive(Debug)]
+pub struct LoxBytecodeClass {
+    name: String,
+    /// Closures of the methods, by interned name.
+    methods: HashMap<LoxBytecodeObjectHandle, LoxBytecodeObjectHandle>,
+}
+
+impl LoxBytecodeClass {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_method(&self, name: LoxBytecodeObjectHandle) -> Option<LoxBytecodeObjectHandle> {
+        self.methods.get(&name).copied()
+    }
+
+    pub fn get_methods(&self) -> &HashMap<LoxBytecodeObjectHandle, LoxBytecodeObjectHandle> {
+        &self.methods
+    }
+
+    pub fn set_method(&mut self, name: LoxBytecodeObjectHandle, method: LoxBytecodeObjectHandle) {
+        self.methods.insert(name, method);
+    }
+}
+
+/// An instance of a Lox class.
+#[derive(Debug)]
+pub struct LoxBytecodeInstance {
+    class: LoxBytecodeObjectHandle,
+    /// Values of the fields, by interned name.
+    fields: HashMap<LoxBytecodeObjectHandle, LoxBytecodeValue>,
+}
+
+impl LoxBytecodeInstance {
+    pub fn new(class: LoxBytecodeObjectHandle) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+
+    pub fn get_class(&self) -> LoxBytecodeObjectHandle {
+        self.class
+    }
+
+    pub fn get_field(&self, name: LoxBytecodeObjectHandle) -> Option<LoxBytecodeValue> {
+        self.fields.get(&name).copied()
+    }
+
+    pub fn get_fields(&self) -> &HashMap<LoxBytecodeObjectHandle, LoxBytecodeValue> {
+        &self.fields
+    }
+
+    pub fn set_field(&mut self, name: LoxBytecodeObjectHandle, value: LoxBytecodeValue) {
+        self.fields.insert(name, value);
+    }
+}
+
+/// A method closure bound to the instance it was accessed from.
+#[derive(Debug)]
+pub struct LoxBytecodeBoundMethod {
+    receiver: LoxBytecodeValue,
+    method: LoxBytecodeObjectHandle,
+}
+
+impl LoxBytecodeBoundMethod {
+    pub fn new(receiver: LoxBytecodeValue, method: LoxBytecodeObjectHandle) -> Self {
+        Self { receiver, method }
+    }
+
+    pub fn get_receiver(&self) -> LoxBytecodeValue {
+        self.receiver
+    }
+
+    pub fn get_method(&self) -> LoxBytecodeObjectHandle {
+        self.method
+    }
+}
+
+pub type LoxBytecodeNativeFunctionCall = fn(arguments: &[LoxBytecodeValue]) -> LoxBytecodeValue;
+
+/// A function implemented in Rust and callable from Lox code.
+pub struct LoxBytecodeNativeFunction {
+    name: String,
+    arity: usize,
+    execute: LoxBytecodeNativeFunctionCall,
+}
+
+impl std::fmt::Debug for LoxBytecodeNativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+impl LoxBytecodeNativeFunction {
+    pub fn new(name: String, arity: usize, execute: LoxBytecodeNativeFunctionCall) -> Self {
+        Self {
+            name,
+            arity,
+            execute,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_arity(&self) -> usize {
+        self.arity
+    }
+
+    pub fn call(&self, arguments: &[LoxBytecodeValue]) -> LoxBytecodeValue {
+        (self.execute)(arguments)
+    }
 }
 
 impl LoxBytecodeValue {
@@ -30,6 +244,14 @@ impl LoxBytecodeValue {
         matches!(self, Self::Number(_))
     }
 
+    pub fn as_object(&self) -> Option<LoxBytecodeObjectHandle> {
+        if let Self::Object(handle) = self {
+            Some(*handle)
+        } else {
+            None
+        }
+    }
+
     pub fn equals(&self, other: &Self) -> bool {
         match (self, other) {
             (Self::Nil, Self::Nil) => true,
@@ -38,21 +260,13 @@ impl LoxBytecodeValue {
             (Self::Number(left), Self::Number(right)) => {
                 (left - right).abs() < LOX_NUMBER_VALUE_COMPARISON_EPSILON
             }
+            // strings are interned: equal strings are the same object
+            (Self::Object(left), Self::Object(right)) => left == right,
             _ => false,
         }
     }
 }
 
-impl LoxPrintable for LoxBytecodeValue {
-    fn representation(&self) -> String {
-        match self {
-            Self::Nil => "nil".to_string(),
-            Self::Number(value) => format!("{}", value),
-            Self::Boolean(boolean) => (if *boolean { "true" } else { "false" }).to_string(),
-        }
-    }
-}
-
 /// Constants pool.
 #[derive(Clone, Debug, Default)]
 pub struct LoxValueArray {
@@ -71,4 +285,8 @@ impl LoxValueArray {
     pub fn count(&self) -> usize {
         self.values.len()
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LoxBytecodeValue> {
+        self.values.iter()
+    }
 }
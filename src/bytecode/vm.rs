@@ -1,105 +1,167 @@
-use crate::errors::BResult;
+use std::collections::HashMap;
 
+use crate::{
+    errors::{BResult, LoxBytecodeInterpreterError, LoxResult},
+    interpreter::LoxInterpreter,
+    printer::{LoxLinePrinterInstance, StdOutPrinter},
+};
+
+#[cfg(feature = "bytecode-tracing")]
+use super::debug::{disassemble_instruction, write_value};
 use super::{
-    debug::{disassemble_instruction, print_value},
+    builtins::build_lox_clock_builtin,
+    compiler::LoxBytecodeCompiler,
+    heap::{LoxBytecodeHeap, LoxBytecodeObject, LoxBytecodeObjectHandle},
     lexer::LoxBytecodeLexer,
-    values::LoxBytecodeValue,
+    values::{
+        LoxBytecodeBoundMethod, LoxBytecodeClass, LoxBytecodeClosure, LoxBytecodeInstance,
+        LoxBytecodeUpvalue, LoxBytecodeValue,
+    },
     LoxBytecodeChunk, LoxBytecodeOpcode,
 };
 
-const LOX_STACK_MAX: usize = 256;
+/// Maximum depth of the call stack.
+const LOX_FRAMES_MAX: usize = 64;
+const LOX_STACK_MAX: usize = LOX_FRAMES_MAX * (u8::MAX as usize + 1);
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum LoxInterpreterResult {
-    Ok,
-    CompilationError,
-    RuntimeError,
+/// An ongoing function call.
+struct LoxBytecodeCallFrame {
+    closure: LoxBytecodeObjectHandle,
+    /// Function of the closure, cached for instructions decoding.
+    function: LoxBytecodeObjectHandle,
+    instruction_pointer: usize,
+    /// Index of the first stack slot usable by the function, holding the function itself.
+    slots_start: usize,
 }
 
 pub struct LoxBytecodeVirtualMachine {
-    chunk: LoxBytecodeChunk,
-    instruction_pointer: usize,
-    stack: [LoxBytecodeValue; LOX_STACK_MAX],
+    frames: Vec<LoxBytecodeCallFrame>,
+    stack: Vec<LoxBytecodeValue>,
     stack_index: usize,
-}
-
-fn stack_init<const N: usize>() -> [LoxBytecodeValue; N] {
-    let mut vec = Vec::with_capacity(N);
-    for _ in 0..N {
-        vec.push(LoxBytecodeValue::Nil);
-    }
-    vec.try_into().unwrap()
+    /// Values of the global variables, by interned name.
+    globals: HashMap<LoxBytecodeObjectHandle, LoxBytecodeValue>,
+    heap: LoxBytecodeHeap,
+    /// Interned name of the class initializers.
+    init_string: LoxBytecodeObjectHandle,
+    /// Upvalues still pointing to a stack slot.
+    open_upvalues: Vec<LoxBytecodeObjectHandle>,
+    printer: LoxLinePrinterInstance,
 }
 
 macro_rules! vm_binary_operation {
     ($self: ident, $operator: tt, $value_type: path) => {{
         // type checking
         if !$self.peek(0).is_number() || !$self.peek(1).is_number() {
-            $self.runtime_error("Operands must be a numbers.");
-            return Ok(LoxInterpreterResult::RuntimeError);
+            return Err($self.runtime_error("Operands must be numbers."));
         }
         // watch out for the pop order
         let b = $self.stack_pop().as_number().expect("vm.binary_operation expects a number value");
         let a = $self.stack_pop().as_number().expect("vm.binary_operation expects a number value");
-        $self.stack_push($value_type(a $operator b));
+        $self.stack_push($value_type(a $operator b))?;
     }};
 }
 
 impl Default for LoxBytecodeVirtualMachine {
     fn default() -> Self {
-        Self {
-            chunk: LoxBytecodeChunk::default(),
-            instruction_pointer: 0,
-            stack: stack_init(),
-            stack_index: 0,
-        }
+        Self::new(None)
     }
 }
 
-impl LoxBytecodeVirtualMachine {
-    pub fn run_code(&mut self, code: &String) -> BResult<LoxInterpreterResult> {
+impl LoxInterpreter for LoxBytecodeVirtualMachine {
+    fn run_code(&mut self, code: &str) -> LoxResult<Option<String>> {
         let mut lexer = LoxBytecodeLexer::default();
-        let parsed = lexer.compile(code)?;
-        self.interpret()
+        let vm_roots = self.roots().collect();
+        let mut compiler = LoxBytecodeCompiler::new(&mut self.heap, vm_roots);
+        let function = compiler.compile(code, &mut lexer)?;
+        let ends_with_expression = compiler.ends_with_expression();
+
+        // keep the function reachable while allocating its closure
+        self.stack_push(LoxBytecodeValue::Object(function))?;
+        let script = self.allocate(LoxBytecodeObject::Closure(LoxBytecodeClosure::new(
+            function,
+            vec![],
+        )));
+        self.stack_pop();
+        self.stack_push(LoxBytecodeValue::Object(script))?;
+        self.call(script, 0)?;
+        let value = self.interpret()?;
+        Ok(ends_with_expression.then(|| self.heap.representation(&value)))
+    }
+
+    fn get_printer(&self) -> &LoxLinePrinterInstance {
+        &self.printer
+    }
+}
+
+impl LoxBytecodeVirtualMachine {
+    pub fn new(printer: Option<LoxLinePrinterInstance>) -> Self {
+        let mut heap = LoxBytecodeHeap::default();
+        let init_string = heap.allocate(LoxBytecodeObject::String("init".to_string()));
+        let mut vm = Self {
+            frames: Vec::with_capacity(LOX_FRAMES_MAX),
+            stack: vec![LoxBytecodeValue::Nil; LOX_STACK_MAX],
+            stack_index: 0,
+            globals: HashMap::new(),
+            heap,
+            init_string,
+            open_upvalues: vec![],
+            printer: printer.unwrap_or_else(|| Box::new(StdOutPrinter)),
+        };
+        vm.define_native("clock", build_lox_clock_builtin());
+        vm
     }
 
-    pub fn interpret(&mut self) -> BResult<LoxInterpreterResult> {
-        let instructions = self.chunk.get_instructions().to_vec();
-        while let Some(instruction) = instructions.get(self.instruction_pointer) {
+    fn define_native(&mut self, name: &str, native: super::values::LoxBytecodeNativeFunction) {
+        // keep the name reachable while allocating the function
+        let name = self.intern_string(name.to_string());
+        self.stack_push(LoxBytecodeValue::Object(name))
+            .expect("the stack is empty when defining natives");
+        let native = self.allocate(LoxBytecodeObject::NativeFunction(native));
+        self.globals.insert(name, LoxBytecodeValue::Object(native));
+        self.stack_pop();
+    }
+
+    /// Run the current call frame until it returns, yielding the value it evaluates to.
+    pub fn interpret(&mut self) -> BResult<LoxBytecodeValue> {
+        loop {
             #[cfg(feature = "bytecode-tracing")]
             {
-                print!("          ");
+                use std::fmt::Write;
+                let mut trace = String::from("          ");
                 for index in 0..self.stack_index {
-                    print!("[ ");
-                    print_value(self.stack[index]);
-                    print!(" ]");
+                    trace += "[ ";
+                    write_value(&mut trace, &self.stack[index], &self.heap)
+                        .and_then(|_| write!(trace, " ]"))
+                        .expect("writing to a String cannot fail");
                 }
-                println!();
-                disassemble_instruction(&self.chunk, self.instruction_pointer); // TODO: check offset
+                trace += "\n";
+                disassemble_instruction(
+                    &mut trace,
+                    self.chunk(),
+                    self.frame().instruction_pointer,
+                    &self.heap,
+                )
+                .expect("writing to a String cannot fail");
+                print!("{}", trace);
             }
 
+            let instruction = self.read_instruction();
             match instruction {
                 LoxBytecodeOpcode::Constant => {
-                    let constant_index = *instructions
-                        .get(self.instruction_pointer + 1)
-                        .expect("constant opcode is followed by value")
-                        .as_value()
-                        .expect("next opcode after constant opcode must be a value");
-                    let constant = self
-                        .chunk
+                    let constant_index = self.read_value();
+                    let constant = *self
+                        .chunk()
                         .get_constant(constant_index)
-                        .expect("the constant must exist")
-                        .clone();
-                    self.stack_push(constant);
+                        .expect("the constant must exist");
+                    self.stack_push(constant)?;
                 }
-                LoxBytecodeOpcode::Nil => self.stack_push(LoxBytecodeValue::Nil),
-                LoxBytecodeOpcode::True => self.stack_push(LoxBytecodeValue::Boolean(true)),
-                LoxBytecodeOpcode::False => self.stack_push(LoxBytecodeValue::Boolean(false)),
+                LoxBytecodeOpcode::Nil => self.stack_push(LoxBytecodeValue::Nil)?,
+                LoxBytecodeOpcode::True => self.stack_push(LoxBytecodeValue::Boolean(true))?,
+                LoxBytecodeOpcode::False => self.stack_push(LoxBytecodeValue::Boolean(false))?,
                 LoxBytecodeOpcode::Equal => {
-                    let b = self.stack_pop().clone(); // TODO: can we avoid this?
+                    let b = self.stack_pop();
                     let a = self.stack_pop();
-                    let value = a.equals(&b);
-                    self.stack_push(LoxBytecodeValue::Boolean(value));
+                    self.stack_push(LoxBytecodeValue::Boolean(a.equals(&b)))?;
                 }
                 LoxBytecodeOpcode::Greater => {
                     vm_binary_operation!(self, >, LoxBytecodeValue::Boolean)
@@ -107,7 +169,19 @@ impl LoxBytecodeVirtualMachine {
                 LoxBytecodeOpcode::Less => {
                     vm_binary_operation!(self, <, LoxBytecodeValue::Boolean)
                 }
-                LoxBytecodeOpcode::Add => vm_binary_operation!(self, +, LoxBytecodeValue::Number),
+                LoxBytecodeOpcode::Add => {
+                    if self.heap.as_string(self.peek(0)).is_some()
+                        && self.heap.as_string(self.peek(1)).is_some()
+                    {
+                        self.concatenate()?;
+                    } else if self.peek(0).is_number() && self.peek(1).is_number() {
+                        vm_binary_operation!(self, +, LoxBytecodeValue::Number)
+                    } else {
+                        return Err(
+                            self.runtime_error("Operands must be two numbers or two strings.")
+                        );
+                    }
+                }
                 LoxBytecodeOpcode::Subtract => {
                     vm_binary_operation!(self, -, LoxBytecodeValue::Number)
                 }
@@ -119,62 +193,985 @@ impl LoxBytecodeVirtualMachine {
                 }
                 LoxBytecodeOpcode::Not => {
                     let value = self.stack_pop().is_falsy();
-                    self.stack_push(LoxBytecodeValue::Boolean(value));
+                    self.stack_push(LoxBytecodeValue::Boolean(value))?;
                 }
                 LoxBytecodeOpcode::Negate => {
-                    if let LoxBytecodeValue::Number(value) = self.peek(0).clone() {
+                    if let LoxBytecodeValue::Number(value) = self.peek(0) {
+                        let negated = LoxBytecodeValue::Number(-value);
                         self.stack_pop();
-                        self.stack_push(LoxBytecodeValue::Number(-value));
+                        self.stack_push(negated)?;
                     } else {
-                        self.runtime_error("Operand must be a number.");
-                        return Ok(LoxInterpreterResult::RuntimeError);
+                        return Err(self.runtime_error("Operand must be a number."));
+                    }
+                }
+                LoxBytecodeOpcode::Print => {
+                    let value = self.stack_pop();
+                    self.printer.print(self.heap.representation(&value));
+                }
+                LoxBytecodeOpcode::Pop => {
+                    self.stack_pop();
+                }
+                LoxBytecodeOpcode::GetLocal => {
+                    let slot = self.frame().slots_start + self.read_value();
+                    self.stack_push(self.stack[slot])?;
+                }
+                LoxBytecodeOpcode::SetLocal => {
+                    let slot = self.frame().slots_start + self.read_value();
+                    // assignment is an expression: leave the value on the stack
+                    self.stack[slot] = *self.peek(0);
+                }
+                LoxBytecodeOpcode::DefineGlobal => {
+                    let name = self.read_identifier();
+                    let value = self.stack_pop();
+                    self.globals.insert(name, value);
+                }
+                LoxBytecodeOpcode::GetGlobal => {
+                    let name = self.read_identifier();
+                    if let Some(value) = self.globals.get(&name) {
+                        self.stack_push(*value)?;
+                    } else {
+                        let message = format!(
+                            "Undefined variable '{}'.",
+                            self.heap.as_interned_string(name)
+                        );
+                        return Err(self.runtime_error(message));
+                    }
+                }
+                LoxBytecodeOpcode::SetGlobal => {
+                    let name = self.read_identifier();
+                    if let Some(value) = self.globals.get_mut(&name) {
+                        // assignment is an expression: leave the value on the stack
+                        *value = self.stack[self.stack_index - 1];
+                    } else {
+                        let message = format!(
+                            "Undefined variable '{}'.",
+                            self.heap.as_interned_string(name)
+                        );
+                        return Err(self.runtime_error(message));
+                    }
+                }
+                LoxBytecodeOpcode::Jump => {
+                    let offset = self.read_value();
+                    self.frame_mut().instruction_pointer += offset;
+                }
+                LoxBytecodeOpcode::JumpIfFalse => {
+                    let offset = self.read_value();
+                    if self.peek(0).is_falsy() {
+                        self.frame_mut().instruction_pointer += offset;
+                    }
+                }
+                LoxBytecodeOpcode::Loop => {
+                    let offset = self.read_value();
+                    self.frame_mut().instruction_pointer -= offset;
+                }
+                LoxBytecodeOpcode::Call => {
+                    let arguments_count = self.read_value();
+                    let callee = *self.peek(arguments_count);
+                    self.call_value(callee, arguments_count)?;
+                }
+                LoxBytecodeOpcode::Closure => {
+                    let constant_index = self.read_value();
+                    let function = self
+                        .chunk()
+                        .get_constant(constant_index)
+                        .and_then(LoxBytecodeValue::as_object)
+                        .expect("vm.interpret expects a function constant for a closure");
+                    let upvalues_count = self.heap.as_function(function).get_upvalues_count();
+                    let mut upvalues = Vec::with_capacity(upvalues_count);
+                    for _ in 0..upvalues_count {
+                        let is_local = self.read_value() == 1;
+                        let index = self.read_value();
+                        upvalues.push(if is_local {
+                            self.capture_upvalue(self.frame().slots_start + index)
+                        } else {
+                            self.upvalue(index)
+                        });
                     }
+                    // the captured upvalues are either open or reachable from the current closure
+                    let closure = self.allocate(LoxBytecodeObject::Closure(
+                        LoxBytecodeClosure::new(function, upvalues),
+                    ));
+                    self.stack_push(LoxBytecodeValue::Object(closure))?;
+                }
+                LoxBytecodeOpcode::GetUpvalue => {
+                    let index = self.read_value();
+                    let value = match self.heap.as_upvalue(self.upvalue(index)) {
+                        LoxBytecodeUpvalue::Open(slot) => self.stack[*slot],
+                        LoxBytecodeUpvalue::Closed(value) => *value,
+                    };
+                    self.stack_push(value)?;
+                }
+                LoxBytecodeOpcode::SetUpvalue => {
+                    let index = self.read_value();
+                    // assignment is an expression: leave the value on the stack
+                    let value = *self.peek(0);
+                    let upvalue = self.upvalue(index);
+                    match self.heap.as_upvalue_mut(upvalue) {
+                        LoxBytecodeUpvalue::Open(slot) => self.stack[*slot] = value,
+                        LoxBytecodeUpvalue::Closed(closed) => *closed = value,
+                    };
+                }
+                LoxBytecodeOpcode::CloseUpvalue => {
+                    self.close_upvalues(self.stack_index - 1);
+                    self.stack_pop();
                 }
                 LoxBytecodeOpcode::Return => {
-                    print_value(self.stack_pop());
-                    println!();
+                    let result = self.stack_pop();
+                    let frame = self
+                        .frames
+                        .pop()
+                        .expect("vm.interpret expects a call frame to return from");
+                    self.close_upvalues(frame.slots_start);
+                    // discard the callee and its arguments and locals
+                    self.stack_truncate(frame.slots_start);
+                    if self.frames.is_empty() {
+                        return Ok(result);
+                    }
+                    self.stack_push(result)?;
+                }
+                LoxBytecodeOpcode::Class => {
+                    let name = self.read_identifier();
+                    let name = self.heap.as_interned_string(name).to_string();
+                    let class =
+                        self.allocate(LoxBytecodeObject::Class(LoxBytecodeClass::new(name)));
+                    self.stack_push(LoxBytecodeValue::Object(class))?;
+                }
+                LoxBytecodeOpcode::Method => {
+                    let name = self.read_identifier();
+                    let method = self
+                        .peek(0)
+                        .as_object()
+                        .expect("vm.interpret expects a method closure");
+                    let class = self
+                        .peek(1)
+                        .as_object()
+                        .expect("vm.interpret expects a class for a method");
+                    self.heap.as_class_mut(class).set_method(name, method);
+                    self.stack_pop();
+                }
+                LoxBytecodeOpcode::Inherit => {
+                    let superclass = match self.peek(1) {
+                        LoxBytecodeValue::Object(handle)
+                            if matches!(self.heap.get(*handle), LoxBytecodeObject::Class(_)) =>
+                        {
+                            *handle
+                        }
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    let subclass = self
+                        .peek(0)
+                        .as_object()
+                        .expect("vm.interpret expects a subclass to inherit into");
+                    // copy-down inheritance: the methods declared afterwards override these
+                    let methods = self.heap.as_class(superclass).get_methods().clone();
+                    for (name, method) in methods {
+                        self.heap.as_class_mut(subclass).set_method(name, method);
+                    }
+                    self.stack_pop();
+                }
+                LoxBytecodeOpcode::GetProperty => {
+                    let name = self.read_identifier();
+                    let receiver = *self.peek(0);
+                    let instance = match self.heap.as_instance(&receiver) {
+                        Some(instance) => instance,
+                        None => return Err(self.runtime_error("Only instances have properties.")),
+                    };
+                    // fields shadow methods
+                    if let Some(value) = instance.get_field(name) {
+                        self.stack_pop();
+                        self.stack_push(value)?;
+                    } else {
+                        let class = instance.get_class();
+                        self.bind_method(class, name)?;
+                    }
+                }
+                LoxBytecodeOpcode::SetProperty => {
+                    let name = self.read_identifier();
+                    let value = *self.peek(0);
+                    let receiver = *self.peek(1);
+                    match self.heap.as_instance_mut(&receiver) {
+                        Some(instance) => instance.set_field(name, value),
+                        None => return Err(self.runtime_error("Only instances have fields.")),
+                    }
+                    // assignment is an expression: leave the value on the stack
+                    self.stack_pop();
+                    self.stack_pop();
+                    self.stack_push(value)?;
+                }
+                LoxBytecodeOpcode::GetSuper => {
+                    let name = self.read_identifier();
+                    let superclass = self
+                        .stack_pop()
+                        .as_object()
+                        .expect("vm.interpret expects a superclass");
+                    self.bind_method(superclass, name)?;
+                }
+                LoxBytecodeOpcode::Invoke => {
+                    let name = self.read_identifier();
+                    let arguments_count = self.read_value();
+                    self.invoke(name, arguments_count)?;
                 }
-                _ => panic!(
-                    "vm.interpret instruction not implemented: {:?}",
+                LoxBytecodeOpcode::SuperInvoke => {
+                    let name = self.read_identifier();
+                    let arguments_count = self.read_value();
+                    let superclass = self
+                        .stack_pop()
+                        .as_object()
+                        .expect("vm.interpret expects a superclass");
+                    self.invoke_from_class(superclass, name, arguments_count)?;
+                }
+                LoxBytecodeOpcode::Value(_) => panic!(
+                    "vm.interpret expects an instruction, got an operand: {:?}",
                     instruction
                 ),
             }
         }
-        Ok(LoxInterpreterResult::Ok)
     }
 
-    fn stack_push(&mut self, value: LoxBytecodeValue) {
+    /// Values directly reachable by the virtual machine.
+    fn roots(&self) -> impl Iterator<Item = LoxBytecodeValue> + '_ {
+        self.stack[..self.stack_index]
+            .iter()
+            .copied()
+            .chain(
+                self.globals
+                    .iter()
+                    .flat_map(|(name, value)| [LoxBytecodeValue::Object(*name), *value]),
+            )
+            .chain(std::iter::once(LoxBytecodeValue::Object(self.init_string)))
+            .chain(
+                self.frames
+                    .iter()
+                    .map(|frame| LoxBytecodeValue::Object(frame.closure)),
+            )
+            .chain(
+                self.open_upvalues
+                    .iter()
+                    .copied()
+                    .map(LoxBytecodeValue::Object),
+            )
+    }
+
+    /// Free every heap object unreachable from the virtual machine.
+    pub fn collect_garbage(&mut self) {
+        let roots: Vec<LoxBytecodeValue> = self.roots().collect();
+        self.heap.collect(roots);
+    }
+
+    /// Move the given object to the heap, collecting garbage beforehand if needed.
+    fn allocate(&mut self, object: LoxBytecodeObject) -> LoxBytecodeObjectHandle {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.allocate(object)
+    }
+
+    fn intern_string(&mut self, string: String) -> LoxBytecodeObjectHandle {
+        match self.heap.find_string(&string) {
+            Some(handle) => handle,
+            None => self.allocate(LoxBytecodeObject::String(string)),
+        }
+    }
+
+    fn concatenate(&mut self) -> BResult<()> {
+        let concatenated = format!(
+            "{}{}",
+            self.heap
+                .as_string(self.peek(1))
+                .expect("vm.concatenate expects a string value"),
+            self.heap
+                .as_string(self.peek(0))
+                .expect("vm.concatenate expects a string value")
+        );
+        let string = self.intern_string(concatenated);
+        self.stack_pop();
+        self.stack_pop();
+        self.stack_push(LoxBytecodeValue::Object(string))
+    }
+
+    fn call_value(&mut self, callee: LoxBytecodeValue, arguments_count: usize) -> BResult<()> {
+        if let LoxBytecodeValue::Object(handle) = callee {
+            match self.heap.get(handle) {
+                LoxBytecodeObject::Closure(_) => return self.call(handle, arguments_count),
+                LoxBytecodeObject::BoundMethod(bound_method) => {
+                    // the receiver takes the place of the callee, as `this`
+                    let method = bound_method.get_method();
+                    self.stack[self.stack_index - arguments_count - 1] =
+                        bound_method.get_receiver();
+                    return self.call(method, arguments_count);
+                }
+                LoxBytecodeObject::Class(class) => {
+                    let initializer = class.get_method(self.init_string);
+                    // the class stays on the stack as the callee during the allocation
+                    let instance = self.allocate(LoxBytecodeObject::Instance(
+                        LoxBytecodeInstance::new(handle),
+                    ));
+                    self.stack[self.stack_index - arguments_count - 1] =
+                        LoxBytecodeValue::Object(instance);
+                    return match initializer {
+                        Some(initializer) => self.call(initializer, arguments_count),
+                        None if arguments_count != 0 => Err(self.runtime_error(format!(
+                            "Expected 0 arguments but got {}.",
+                            arguments_count
+                        ))),
+                        None => Ok(()),
+                    };
+                }
+                LoxBytecodeObject::NativeFunction(native) => {
+                    if arguments_count != native.get_arity() {
+                        let message = format!(
+                            "Expected {} arguments but got {}.",
+                            native.get_arity(),
+                            arguments_count
+                        );
+                        return Err(self.runtime_error(message));
+                    }
+                    let arguments_start = self.stack_index - arguments_count;
+                    let result = native.call(&self.stack[arguments_start..self.stack_index]);
+                    self.stack_truncate(arguments_start - 1);
+                    self.stack_push(result)?;
+                    return Ok(());
+                }
+                _ => (),
+            }
+        }
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    /// Call the given method of the instance below the arguments on top of the stack.
+    fn invoke(&mut self, name: LoxBytecodeObjectHandle, arguments_count: usize) -> BResult<()> {
+        let receiver = *self.peek(arguments_count);
+        let instance = match self.heap.as_instance(&receiver) {
+            Some(instance) => instance,
+            None => return Err(self.runtime_error("Only instances have methods.")),
+        };
+        // a field holding a function shadows the method of the same name
+        if let Some(field) = instance.get_field(name) {
+            self.stack[self.stack_index - arguments_count - 1] = field;
+            return self.call_value(field, arguments_count);
+        }
+        let class = instance.get_class();
+        self.invoke_from_class(class, name, arguments_count)
+    }
+
+    /// Call the given method of a class on the receiver below the arguments,
+    /// without binding it first.
+    fn invoke_from_class(
+        &mut self,
+        class: LoxBytecodeObjectHandle,
+        name: LoxBytecodeObjectHandle,
+        arguments_count: usize,
+    ) -> BResult<()> {
+        match self.heap.as_class(class).get_method(name) {
+            Some(method) => self.call(method, arguments_count),
+            None => Err(self.undefined_property(name)),
+        }
+    }
+
+    /// Replace the instance on top of the stack by its given method, bound to it.
+    fn bind_method(
+        &mut self,
+        class: LoxBytecodeObjectHandle,
+        name: LoxBytecodeObjectHandle,
+    ) -> BResult<()> {
+        let method = match self.heap.as_class(class).get_method(name) {
+            Some(method) => method,
+            None => return Err(self.undefined_property(name)),
+        };
+        // the receiver stays on the stack during the allocation
+        let bound_method = self.allocate(LoxBytecodeObject::BoundMethod(
+            LoxBytecodeBoundMethod::new(*self.peek(0), method),
+        ));
+        self.stack_pop();
+        self.stack_push(LoxBytecodeValue::Object(bound_method))?;
+        Ok(())
+    }
+
+    /// Push a call frame for the given closure, whose arguments are on top of the stack.
+    fn call(&mut self, closure: LoxBytecodeObjectHandle, arguments_count: usize) -> BResult<()> {
+        let function = self.heap.as_closure(closure).get_function();
+        let arity = self.heap.as_function(function).get_arity();
+        if arguments_count != arity {
+            return Err(self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                arity, arguments_count
+            )));
+        }
+        if self.frames.len() == LOX_FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        self.frames.push(LoxBytecodeCallFrame {
+            closure,
+            function,
+            instruction_pointer: 0,
+            slots_start: self.stack_index - arguments_count - 1,
+        });
+        Ok(())
+    }
+
+    fn frame(&self) -> &LoxBytecodeCallFrame {
+        self.frames.last().expect("vm expects a call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut LoxBytecodeCallFrame {
+        self.frames.last_mut().expect("vm expects a call frame")
+    }
+
+    fn chunk(&self) -> &LoxBytecodeChunk {
+        self.heap.as_function(self.frame().function).get_chunk()
+    }
+
+    /// Upvalue of the current closure.
+    fn upvalue(&self, index: usize) -> LoxBytecodeObjectHandle {
+        self.heap
+            .as_closure(self.frame().closure)
+            .get_upvalue(index)
+            .expect("the upvalue must exist")
+    }
+
+    /// Get an upvalue for the given stack slot, reusing the existing one if any
+    /// so that closures share the variables they capture.
+    fn capture_upvalue(&mut self, slot: usize) -> LoxBytecodeObjectHandle {
+        let heap = &self.heap;
+        if let Some(upvalue) = self.open_upvalues.iter().find(|upvalue| {
+            matches!(heap.as_upvalue(**upvalue), LoxBytecodeUpvalue::Open(open) if *open == slot)
+        }) {
+            return *upvalue;
+        }
+        let upvalue = self.allocate(LoxBytecodeObject::Upvalue(LoxBytecodeUpvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    /// Move the variables living in the given stack slot and above into their upvalues.
+    fn close_upvalues(&mut self, last_slot: usize) {
+        let (heap, stack) = (&mut self.heap, &self.stack);
+        self.open_upvalues.retain(|upvalue| {
+            let upvalue = heap.as_upvalue_mut(*upvalue);
+            match *upvalue {
+                LoxBytecodeUpvalue::Open(slot) if slot >= last_slot => {
+                    *upvalue = LoxBytecodeUpvalue::Closed(stack[slot]);
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    fn read_instruction(&mut self) -> LoxBytecodeOpcode {
+        let frame = self.frames.last_mut().expect("vm expects a call frame");
+        let instruction = self
+            .heap
+            .as_function(frame.function)
+            .get_chunk()
+            .get_instruction(frame.instruction_pointer)
+            .expect("vm.read_instruction expects a valid instruction pointer")
+            .clone();
+        frame.instruction_pointer += 1;
+        instruction
+    }
+
+    /// Read the operand following the current instruction.
+    fn read_value(&mut self) -> usize {
+        *self
+            .read_instruction()
+            .as_value()
+            .expect("vm.read_value expects an operand")
+    }
+
+    /// Read the interned name of the global variable or property referenced by
+    /// the current instruction.
+    fn read_identifier(&mut self) -> LoxBytecodeObjectHandle {
+        let identifier_index = self.read_value();
+        self.chunk()
+            .get_identifier(identifier_index)
+            .expect("the identifier must exist")
+    }
+
+    fn stack_push(&mut self, value: LoxBytecodeValue) -> BResult<()> {
+        if self.stack_index == LOX_STACK_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
         self.stack[self.stack_index] = value;
         self.stack_index += 1;
+        Ok(())
     }
 
-    fn stack_pop(&mut self) -> &LoxBytecodeValue {
+    fn stack_pop(&mut self) -> LoxBytecodeValue {
         self.stack_index -= 1;
-        self.stack
-            .last()
-            .expect("the stack should not be empty when popped")
+        std::mem::replace(&mut self.stack[self.stack_index], LoxBytecodeValue::Nil)
+    }
+
+    /// Discard every value from the given stack index onwards.
+    fn stack_truncate(&mut self, index: usize) {
+        while self.stack_index > index {
+            self.stack_pop();
+        }
     }
 
     fn stack_reset(&mut self) {
-        self.stack_index = 0; // TODO: check that this is the correct behavior
+        self.stack_truncate(0);
+        self.frames.clear();
+        self.open_upvalues.clear();
     }
 
     fn peek(&self, distance: usize) -> &LoxBytecodeValue {
-        self.stack
-            .get(LOX_STACK_MAX - 1 - distance)
-            .unwrap_or_else(|| panic!("vm.peek({}) expects a valid stack value", distance))
-    }
-
-    fn runtime_error<S: AsRef<str> + std::fmt::Display>(&mut self, message: S) {
-        println!("{}", message);
-        let instruction_offset = self.instruction_pointer - self.chunk.get_size() - 1; // TODO: check formula
-        let line_number = self
-            .chunk
-            .get_line(instruction_offset)
-            .expect("vm.runtime_error should be able to get the line number");
-        println!("[line {}] in script", line_number);
+        &self.stack[self.stack_index - 1 - distance]
+    }
+
+    fn undefined_property(&mut self, name: LoxBytecodeObjectHandle) -> LoxBytecodeInterpreterError {
+        let message = format!(
+            "Undefined property '{}'.",
+            self.heap.as_interned_string(name)
+        );
+        self.runtime_error(message)
+    }
+
+    fn runtime_error<S: AsRef<str>>(&mut self, message: S) -> LoxBytecodeInterpreterError {
+        let span = self
+            .chunk()
+            .get_span(self.frame().instruction_pointer - 1)
+            .expect("vm.runtime_error should be able to get the failing instruction location");
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self.heap.as_function(frame.function);
+                // the instruction pointer has already moved past the failing instruction
+                let line_number = function
+                    .get_chunk()
+                    .get_line(frame.instruction_pointer - 1)
+                    .expect("vm.runtime_error should be able to get the line number");
+                match function.get_name() {
+                    Some(name) => format!("[line {}] in {}()", line_number, name),
+                    None => format!("[line {}] in script", line_number),
+                }
+            })
+            .collect();
         self.stack_reset();
+        LoxBytecodeInterpreterError::VMRuntimeError(message.as_ref().to_string(), trace, span)
     }
 }
 
-// TODO: unit tests
+#[cfg(test)]
+mod tests {
+    use crate::{interpreter::LoxInterpreter, span::LoxSourceSpan};
+
+    use super::LoxBytecodeVirtualMachine;
+
+    #[test]
+    fn test_vm_expressions() {
+        let test_data = vec![
+            ("1 + 2 * 3;", "7"),
+            ("(-1 + 2) * 3 - -4;", "7"),
+            ("!(5 - 4 > 3 * 2 == !nil);", "true"),
+            ("1 <= 1 != false;", "true"),
+        ];
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        for (source, expected) in test_data {
+            assert_eq!(vm.run_code(source).unwrap(), Some(expected.to_string()));
+        }
+        assert_eq!(
+            vm.run_code("1 - nil;").unwrap_err().to_string(),
+            "Operands must be numbers.\n[line 1] in script"
+        );
+        assert!(vm.run_code("1 +;").is_err());
+    }
+
+    #[test]
+    fn test_vm_error_spans() {
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        // runtime errors point at the operator
+        assert_eq!(
+            vm.run_code("var a = 1;\nprint (a) + \"b\";")
+                .unwrap_err()
+                .span(),
+            Some(LoxSourceSpan::new(21, 1, 2, 11))
+        );
+        // compilation errors point at the offending token
+        assert_eq!(
+            vm.run_code("print 1 +;").unwrap_err().span(),
+            Some(LoxSourceSpan::new(9, 1, 1, 10))
+        );
+        assert_eq!(
+            vm.run_code("print \"é\" @;").unwrap_err().span(),
+            Some(LoxSourceSpan::new(11, 1, 1, 11))
+        );
+    }
+
+    #[test]
+    fn test_vm_global_variables() {
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        assert_eq!(
+            vm.run_code("var a = 1; var b; b = a = a + 2; a * b;")
+                .unwrap(),
+            Some("9".into())
+        );
+        // globals survive across runs
+        assert_eq!(vm.run_code("print a; var a = 10;").unwrap(), None);
+        assert_eq!(
+            vm.run_code("a").unwrap_err().to_string(),
+            "[line 1] Error at end: Expect ';' after expression."
+        );
+        assert_eq!(
+            vm.run_code("c = 1;").unwrap_err().to_string(),
+            "Undefined variable 'c'.\n[line 1] in script"
+        );
+        assert_eq!(
+            vm.run_code("var x = 1;\na + b = 3;")
+                .unwrap_err()
+                .to_string(),
+            "[line 2] Error at '=': Invalid assignment target."
+        );
+    }
+
+    #[test]
+    fn test_vm_local_variables() {
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        assert_eq!(
+            vm.run_code("var a = 100; { var a = 1; { var b = a + 1; a = b * 10; } print a; } a;")
+                .unwrap(),
+            Some("100".into())
+        );
+        assert_eq!(
+            vm.run_code("{ var a = 1; var a = 2; }")
+                .unwrap_err()
+                .to_string(),
+            "[line 1] Error at 'a': Already a variable with this name in this scope."
+        );
+        assert_eq!(
+            vm.run_code("{ var a = 1; { var a = a; } }")
+                .unwrap_err()
+                .to_string(),
+            "[line 1] Error at 'a': Can't read local variable in its own initializer."
+        );
+        assert_eq!(
+            vm.run_code("{ var a = 1;").unwrap_err().to_string(),
+            "[line 1] Error at end: Expect '}' after block."
+        );
+    }
+
+    #[test]
+    fn test_vm_control_flow() {
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        let test_data = vec![
+            ("if (1 > 2) 1; else 2;", None),
+            ("var a = 0; if (a == 0) a = 10; else a = 20; a;", Some("10")),
+            ("var n = 0; while (n < 5) n = n + 1; n;", Some("5")),
+            (
+                "var sum = 0; for (var i = 1; i <= 10; i = i + 1) sum = sum + i; sum;",
+                Some("55"),
+            ),
+            (
+                "var count = 0; for (;count < 3;) { count = count + 1; } count;",
+                Some("3"),
+            ),
+            ("nil or 2;", Some("2")),
+            ("1 or undefined;", Some("1")),
+            ("false and undefined;", Some("false")),
+            ("true and 3;", Some("3")),
+        ];
+        for (source, expected) in test_data {
+            assert_eq!(vm.run_code(source).unwrap(), expected.map(String::from));
+        }
+        assert_eq!(
+            vm.run_code("for (var i = 0; i < 1; i = i + 1) {} i;")
+                .unwrap_err()
+                .to_string(),
+            "Undefined variable 'i'.\n[line 1] in script"
+        );
+    }
+
+    #[test]
+    fn test_vm_functions() {
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        assert_eq!(
+            vm.run_code(
+                "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); } fib(15);"
+            )
+            .unwrap(),
+            Some("610".into())
+        );
+        assert_eq!(
+            vm.run_code("fun f(a, b) { var c = a * b; { var d = c + 1; return d; } } f(2, 3);")
+                .unwrap(),
+            Some("7".into())
+        );
+        assert_eq!(vm.run_code("fun g() {} g();").unwrap(), Some("nil".into()));
+        assert_eq!(vm.run_code("g;").unwrap(), Some("<fn g>".into()));
+        assert_eq!(vm.run_code("clock;").unwrap(), Some("<native fn>".into()));
+        assert_eq!(vm.run_code("clock() > 0;").unwrap(), Some("true".into()));
+        assert_eq!(
+            vm.run_code("return 1;").unwrap_err().to_string(),
+            "[line 1] Error at 'return': Can't return from top-level code."
+        );
+        assert_eq!(
+            vm.run_code("f(1);").unwrap_err().to_string(),
+            "Expected 2 arguments but got 1.\n[line 1] in script"
+        );
+        assert_eq!(
+            vm.run_code("fun h() {\n  return 1 - nil;\n}\nfun k() { h(); }\nk();")
+                .unwrap_err()
+                .to_string(),
+            "Operands must be numbers.\n[line 2] in h()\n[line 4] in k()\n[line 5] in script"
+        );
+        assert_eq!(
+            vm.run_code("true();").unwrap_err().to_string(),
+            "Can only call functions and classes.\n[line 1] in script"
+        );
+        let stack_overflow = vm
+            .run_code("fun loop() { loop(); } loop();")
+            .unwrap_err()
+            .to_string();
+        assert!(stack_overflow.starts_with("Stack overflow.\n[line 1] in loop()\n"));
+        // the value stack fills up before the call frames with enough arguments
+        let parameters: Vec<String> = (0..255).map(|index| format!("p{}", index)).collect();
+        let stack_overflow = vm
+            .run_code(&format!(
+                "fun wide({0}) {{ wide({0}); }} wide({1});",
+                parameters.join(", "),
+                vec!["0"; 255].join(", ")
+            ))
+            .unwrap_err()
+            .to_string();
+        assert!(stack_overflow.starts_with("Stack overflow.\n[line 1] in wide()\n"));
+        // the virtual machine is still usable after a runtime error
+        assert_eq!(vm.run_code("f(4, 5);").unwrap(), Some("21".into()));
+    }
+
+    #[test]
+    fn test_vm_strings() {
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        assert_eq!(
+            vm.run_code("var a = \"con\"; var b = a + \"cat\" + \"enated\"; b;")
+                .unwrap(),
+            Some("concatenated".into())
+        );
+        assert_eq!(
+            vm.run_code("b == \"concatenated\";").unwrap(),
+            Some("true".into())
+        );
+        assert_eq!(
+            vm.run_code("\"a\" == \"b\" or \"1\" == 1;").unwrap(),
+            Some("false".into())
+        );
+        // strings are interned
+        assert_eq!(
+            vm.heap.find_string("concatenated"),
+            vm.globals[&vm.heap.find_string("b").unwrap()].as_object()
+        );
+        assert_eq!(
+            vm.run_code("\"a\" + 1;").unwrap_err().to_string(),
+            "Operands must be two numbers or two strings.\n[line 1] in script"
+        );
+        assert_eq!(
+            vm.run_code("\"unterminated;").unwrap_err().to_string(),
+            "[line 1] Error: Unterminated string."
+        );
+    }
+
+    #[test]
+    fn test_vm_closures() {
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        // counters sharing a captured variable
+        assert_eq!(
+            vm.run_code(
+                "fun makeCounter() {
+                  var count = 0;
+                  fun increment() { count = count + 1; return count; }
+                  fun peek() { return count; }
+                  increment();
+                  return increment;
+                }
+                var counter = makeCounter();
+                counter();
+                counter();"
+            )
+            .unwrap(),
+            Some("3".into())
+        );
+        // variables captured through several functions
+        assert_eq!(
+            vm.run_code(
+                "fun outer(a) {
+                  fun middle(b) {
+                    fun inner(c) { return a * 100 + b * 10 + c; }
+                    return inner;
+                  }
+                  return middle;
+                }
+                outer(1)(2)(3);"
+            )
+            .unwrap(),
+            Some("123".into())
+        );
+        // each loop iteration closes over the variable of its own block
+        assert_eq!(
+            vm.run_code(
+                "var first; var second;
+                for (var i = 1; i <= 2; i = i + 1) {
+                  var j = i;
+                  fun get() { return j; }
+                  if (first == nil) first = get; else second = get;
+                }
+                first() * 10 + second();"
+            )
+            .unwrap(),
+            Some("12".into())
+        );
+        // assignments through a closure are visible by the enclosing function
+        assert_eq!(
+            vm.run_code("fun f() { var a = 1; fun set() { a = 2; } set(); return a; } f();")
+                .unwrap(),
+            Some("2".into())
+        );
+    }
+
+    #[test]
+    fn test_vm_classes() {
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        assert_eq!(
+            vm.run_code(
+                "class Point {
+                  init(x, y) { this.x = x; this.y = y; }
+                  sum() { return this.x + this.y; }
+                }
+                var point = Point(1, 2);
+                point.x = 3;
+                point.sum();"
+            )
+            .unwrap(),
+            Some("5".into())
+        );
+        assert_eq!(vm.run_code("Point;").unwrap(), Some("Point".into()));
+        assert_eq!(
+            vm.run_code("point;").unwrap(),
+            Some("Point instance".into())
+        );
+        // bound methods remember their receiver
+        assert_eq!(
+            vm.run_code("var sum = point.sum; point.y = 10; sum;")
+                .unwrap(),
+            Some("<fn sum>".into())
+        );
+        assert_eq!(vm.run_code("sum();").unwrap(), Some("13".into()));
+        // fields shadow methods
+        assert_eq!(
+            vm.run_code("fun answer() { return 42; } point.sum = answer; point.sum();")
+                .unwrap(),
+            Some("42".into())
+        );
+        // inheritance and super calls
+        assert_eq!(
+            vm.run_code(
+                "class A { method() { return \"A\"; } other() { return \"other\"; } }
+                class B < A { method() { return \"B\" + super.method(); } }
+                class C < B {
+                  method() { var parent = super.method; return \"C\" + parent(); }
+                }
+                C().method() + C().other();"
+            )
+            .unwrap(),
+            Some("CBAother".into())
+        );
+        // initializers return their instance
+        assert_eq!(
+            vm.run_code(
+                "class Foo { init() { this.value = 1; return; } }
+                var foo = Foo();
+                foo.init().value;"
+            )
+            .unwrap(),
+            Some("1".into())
+        );
+
+        assert_eq!(
+            vm.run_code("class Foo < Foo {}").unwrap_err().to_string(),
+            "[line 1] Error at 'Foo': A class can't inherit from itself."
+        );
+        assert_eq!(
+            vm.run_code("class Foo { init() { return 1; } }")
+                .unwrap_err()
+                .to_string(),
+            "[line 1] Error at 'return': Can't return a value from an initializer."
+        );
+        assert_eq!(
+            vm.run_code("this;").unwrap_err().to_string(),
+            "[line 1] Error at 'this': Can't use 'this' outside of a class."
+        );
+        assert_eq!(
+            vm.run_code("class Foo { bar() { super.bar(); } }")
+                .unwrap_err()
+                .to_string(),
+            "[line 1] Error at 'super': Can't use 'super' in a class with no superclass."
+        );
+        assert_eq!(
+            vm.run_code("var nope = 1; class Foo < nope {}")
+                .unwrap_err()
+                .to_string(),
+            "Superclass must be a class.\n[line 1] in script"
+        );
+        assert_eq!(
+            vm.run_code("point.missing;").unwrap_err().to_string(),
+            "Undefined property 'missing'.\n[line 1] in script"
+        );
+        assert_eq!(
+            vm.run_code("1.field = 2;").unwrap_err().to_string(),
+            "Only instances have fields.\n[line 1] in script"
+        );
+        assert_eq!(
+            vm.run_code("Point(1);").unwrap_err().to_string(),
+            "Expected 2 arguments but got 1.\n[line 1] in script"
+        );
+    }
+
+    #[test]
+    fn test_vm_garbage_collection() {
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        vm.run_code(
+            "var kept = \"kept\";
+            fun makeClosure(value) {
+              fun closure() { return value; }
+              return closure;
+            }
+            var closure = makeClosure(\"captured\");
+            for (var i = 0; i < 100; i = i + 1) {
+              var garbage = \"garbage\" + \"string\";
+              makeClosure(garbage);
+            }",
+        )
+        .unwrap();
+        let objects_before = vm.heap.count();
+        vm.collect_garbage();
+        assert!(vm.heap.count() < objects_before);
+        // reachable objects survive the collection
+        assert_eq!(vm.run_code("kept;").unwrap(), Some("kept".into()));
+        assert_eq!(vm.run_code("closure();").unwrap(), Some("captured".into()));
+        assert_eq!(vm.heap.find_string("garbagestring"), None);
+        // collecting twice frees nothing more
+        vm.collect_garbage();
+        let objects_count = vm.heap.count();
+        vm.collect_garbage();
+        assert_eq!(vm.heap.count(), objects_count);
+    }
+
+    #[test]
+    fn test_vm_identifiers_limit() {
+        let declarations = |count: usize| {
+            (0..count)
+                .map(|i| format!("var v{} = {};", i, i))
+                .collect::<String>()
+        };
+        let mut vm = LoxBytecodeVirtualMachine::default();
+        assert_eq!(
+            vm.run_code(&format!("{} v255;", declarations(256)))
+                .unwrap(),
+            Some("255".into())
+        );
+        let error = vm.run_code(&declarations(257)).unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("Error at 'v256': Too many constants in one chunk."));
+    }
+}
@@ -1,43 +1,73 @@
-use std::collections::HashMap;
+use std::{cell::Cell, collections::HashMap};
 
 use crate::{
-    errors::{LoxInterpreterError, Result},
+    errors::{LoxCallFrame, LoxInterpreterError, Result},
     interpreter::{
         environment::{environment_handle_get_at_depth, LoxEnvironment, LoxEnvironmentHandle},
-        tree_walk::{LoxLinePrinterInstance, LoxTreeWalkEvaluator, LoxTreeWalkEvaluatorLocals},
+        tree_walk::{LoxCompletion, LoxTreeWalkEvaluator},
     },
     lexer::LoxToken,
-    values::{LoxValue, LoxValueHandle},
+    printer::LoxLinePrinterInstance,
+    values::{LoxObject, LoxObjectHandle, LoxValue},
 };
 
+/// Maximum number of nested calls of Lox functions, as in the bytecode virtual
+/// machine, before reporting a stack overflow instead of overflowing the native one.
+const LOX_CALL_DEPTH_MAX: usize = 64;
+
+thread_local! {
+    static LOX_CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
 pub trait LoxCallable {
     fn arity(&self) -> Option<usize>;
 
     fn call(
         &self,
         env: &mut LoxEnvironmentHandle,
-        locals: &LoxTreeWalkEvaluatorLocals,
-        arguments: &[LoxValueHandle],
+        arguments: &[LoxValue],
+        parenthesis: &LoxToken,
+        output: &mut LoxLinePrinterInstance,
+    ) -> Result<LoxValue>;
+}
+
+impl LoxCallable for LoxValue {
+    fn arity(&self) -> Option<usize> {
+        self.as_object()?.arity()
+    }
+
+    fn call(
+        &self,
+        env: &mut LoxEnvironmentHandle,
+        arguments: &[LoxValue],
         parenthesis: &LoxToken,
         output: &mut LoxLinePrinterInstance,
-    ) -> Result<LoxValueHandle>;
+    ) -> Result<LoxValue> {
+        match self {
+            LoxValue::Object(object) => object.call(env, arguments, parenthesis, output),
+            _ => Err(LoxInterpreterError::InterpreterNonCallableValue(
+                parenthesis.clone(),
+            )),
+        }
+    }
 }
 
-impl LoxCallable for LoxValueHandle {
+impl LoxCallable for LoxObjectHandle {
     fn arity(&self) -> Option<usize> {
         match &*self.borrow() {
-            LoxValue::Function {
+            LoxObject::Function {
                 arity,
                 is_initializer: _,
                 declaration: _,
                 closure: _,
+                locals: _,
             } => Some(*arity),
-            LoxValue::NativeFunction {
+            LoxObject::NativeFunction {
                 label: _,
                 arity,
                 execute: _,
             } => Some(*arity),
-            LoxValue::Class {
+            LoxObject::Class {
                 name: _,
                 methods: _,
                 super_class: _,
@@ -55,55 +85,65 @@ impl LoxCallable for LoxValueHandle {
     fn call(
         &self,
         env: &mut LoxEnvironmentHandle,
-        locals: &LoxTreeWalkEvaluatorLocals,
-        arguments: &[LoxValueHandle],
+        arguments: &[LoxValue],
         parenthesis: &LoxToken,
         output: &mut LoxLinePrinterInstance,
-    ) -> Result<LoxValueHandle> {
+    ) -> Result<LoxValue> {
         match &*self.borrow() {
             // TODO: adapt to other evaluators implementations (bytecode)
-            LoxValue::Function {
+            LoxObject::Function {
                 arity,
-                is_initializer: _,
+                is_initializer,
                 declaration,
                 closure,
+                locals,
             } => {
                 if *arity != arguments.len() {
                     Err(LoxInterpreterError::InterpreterCallableWrongArity(
                         *arity,
                         arguments.len(),
+                        parenthesis.get_span(),
                     ))
                 } else {
+                    let depth = LOX_CALL_DEPTH.with(Cell::get);
+                    if depth == LOX_CALL_DEPTH_MAX {
+                        return Err(LoxInterpreterError::InterpreterStackOverflow(
+                            parenthesis.get_span(),
+                        ));
+                    }
                     let mut function_env = LoxEnvironment::new(Some(closure.clone()));
-                    let (_, parameters, body) =
+                    let (name, parameters, body) =
                         declaration.deconstruct_function_declaration().unwrap();
                     for (i, parameter) in parameters.iter().enumerate() {
                         function_env
                             .borrow_mut()
-                            .define(parameter.get_lexeme().clone(), arguments[i].clone());
+                            .define(parameter.get_lexeme(), arguments[i].clone());
                     }
                     // TODO: abstract over interpreter evaluator (bytecode)
-                    match LoxTreeWalkEvaluator::execute_block_statement(
+                    LOX_CALL_DEPTH.with(|call_depth| call_depth.set(depth + 1));
+                    let completion = LoxTreeWalkEvaluator::execute_block_statement(
                         body,
                         &mut function_env,
                         locals,
                         output,
-                    ) {
-                        Ok(_) => environment_handle_get_at_depth(closure, "this", 0),
-                        Err(why) => match why {
-                            LoxInterpreterError::InterpreterReturn(value) => {
-                                if self.borrow().function_is_initializer() {
-                                    environment_handle_get_at_depth(closure, "this", 0)
-                                } else {
-                                    Ok(value)
-                                }
-                            }
-                            _ => Err(why),
-                        },
+                    );
+                    LOX_CALL_DEPTH.with(|call_depth| call_depth.set(depth));
+                    let completion = completion.map_err(|why| {
+                        why.through_call(LoxCallFrame::new(
+                            name.get_lexeme().clone(),
+                            parenthesis.get_line_number(),
+                        ))
+                    })?;
+                    if *is_initializer {
+                        return get_this(closure, parenthesis);
+                    }
+                    match completion {
+                        LoxCompletion::Normal => Ok(LoxValue::Nil),
+                        LoxCompletion::Return(value) => Ok(value),
                     }
                 }
             }
-            LoxValue::NativeFunction {
+            LoxObject::NativeFunction {
                 label: _,
                 arity,
                 execute,
@@ -112,18 +152,19 @@ impl LoxCallable for LoxValueHandle {
                     Err(LoxInterpreterError::InterpreterCallableWrongArity(
                         *arity,
                         arguments.len(),
+                        parenthesis.get_span(),
                     ))
                 } else {
                     execute(env, arguments)
                 }
             }
-            LoxValue::Class {
+            LoxObject::Class {
                 name: _,
                 methods: _,
                 super_class: _,
             } => {
                 // class constructor (empty by default)
-                let instance = LoxValue::new(LoxValue::ClassInstance {
+                let instance = LoxValue::new_object(LoxObject::ClassInstance {
                     class: self.clone(),
                     fields: HashMap::new(),
                 });
@@ -131,9 +172,15 @@ impl LoxCallable for LoxValueHandle {
                 if let Some(initializer) = self.borrow().class_find_method("init") {
                     initializer
                         .borrow()
-                        .class_method_bind_this(self)
+                        .class_method_bind_this(&instance)
                         .unwrap()
-                        .call(env, locals, arguments, parenthesis, output)?;
+                        .call(env, arguments, parenthesis, output)?;
+                } else if !arguments.is_empty() {
+                    return Err(LoxInterpreterError::InterpreterCallableWrongArity(
+                        0,
+                        arguments.len(),
+                        parenthesis.get_span(),
+                    ));
                 }
                 Ok(instance)
             }
@@ -143,3 +190,10 @@ impl LoxCallable for LoxValueHandle {
         }
     }
 }
+
+/// Retrieve the instance bound to a method.
+fn get_this(closure: &LoxEnvironmentHandle, parenthesis: &LoxToken) -> Result<LoxValue> {
+    environment_handle_get_at_depth(closure, 0, 0).ok_or_else(|| {
+        LoxInterpreterError::InterpreterUndefinedVariable("this".into(), parenthesis.get_span())
+    })
+}
@@ -0,0 +1,290 @@
+use std::fmt::{self, Write};
+
+use crate::{
+    errors::{LoxBytecodeInterpreterError, LoxError, LoxInterpreterError},
+    span::LoxSourceSpan,
+};
+
+/// Error report pointing at the location of the error in the source code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoxDiagnostic {
+    message: String,
+    span: Option<LoxSourceSpan>,
+    /// Additional information, printed after the source snippet.
+    notes: Vec<String>,
+}
+
+impl LoxDiagnostic {
+    pub fn new(message: String, span: Option<LoxSourceSpan>) -> Self {
+        let notes = hint_for_message(&message)
+            .map(|hint| vec![format!("hint: {}", hint)])
+            .unwrap_or_default();
+        Self {
+            message,
+            span,
+            notes,
+        }
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    /// Add the given stack trace lines as notes.
+    pub fn with_stack_trace(self, trace: &[String]) -> Self {
+        trace.iter().fold(self, |diagnostic, frame| {
+            diagnostic.with_note(format!("trace: {}", frame))
+        })
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn get_span(&self) -> Option<LoxSourceSpan> {
+        self.span
+    }
+
+    pub fn get_notes(&self) -> &[String] {
+        &self.notes
+    }
+}
+
+/// Suggestion for fixing the error with the given message, if any.
+///
+/// Both interpreters share the error messages of the reference implementations.
+fn hint_for_message(message: &str) -> Option<&'static str> {
+    Some(match message {
+        "Unterminated string." => "add a closing '\"' to end the string",
+        "Unexpected character." => "this character is not part of the Lox syntax",
+        "Can't read local variable in its own initializer." => {
+            "a local variable is only defined once its initializer has been evaluated"
+        }
+        "Already a variable with this name in this scope." => {
+            "use an assignment to change the value of the existing variable"
+        }
+        "Can't return from top-level code." => "'return' can only be used inside of a function",
+        "Can't return a value from an initializer." => {
+            "an initializer always returns its instance, use a bare 'return;' instead"
+        }
+        "Can't use 'this' outside of a class." | "Can't use 'super' outside of a class." => {
+            "this keyword can only be used inside of methods"
+        }
+        "Can't use 'super' in a class with no superclass." => {
+            "declare a superclass with '<' after the class name"
+        }
+        "Can only call functions and classes." => {
+            "only functions, methods and classes are callable"
+        }
+        _ if message.starts_with("Undefined variable") => {
+            "variables must be declared with 'var' before being used"
+        }
+        _ if message.starts_with("Undefined property") => {
+            "fields must be assigned before being read"
+        }
+        _ => return None,
+    })
+}
+
+impl From<&LoxInterpreterError> for LoxDiagnostic {
+    fn from(error: &LoxInterpreterError) -> Self {
+        let message = match error {
+            // the location is rendered separately
+            LoxInterpreterError::ParserError(_, message) => message.clone(),
+            _ => error.to_string(),
+        };
+        Self::new(message, error.span())
+    }
+}
+
+/// Diagnostics reporting the given error, in order of appearance.
+///
+/// Syntax and compilation errors are reported all at once.
+pub fn diagnostics_from_error(error: &LoxError) -> Vec<LoxDiagnostic> {
+    match error {
+        LoxError::TreeWalk(LoxInterpreterError::ParserErrors(errors)) => {
+            errors.iter().map(LoxDiagnostic::from).collect()
+        }
+        LoxError::TreeWalk(error @ LoxInterpreterError::InterpreterStackTrace(inner, _)) => {
+            vec![LoxDiagnostic::from(inner.as_ref()).with_stack_trace(&error.get_stack_trace())]
+        }
+        LoxError::TreeWalk(error) => vec![error.into()],
+        LoxError::Bytecode(LoxBytecodeInterpreterError::CompilerErrors(errors)) => errors
+            .iter()
+            .map(|error| {
+                LoxDiagnostic::new(error.get_message().to_string(), Some(error.get_span()))
+            })
+            .collect(),
+        LoxError::Bytecode(LoxBytecodeInterpreterError::VMRuntimeError(message, trace, span)) => {
+            vec![LoxDiagnostic::new(message.clone(), Some(*span)).with_stack_trace(trace)]
+        }
+        LoxError::Bytecode(error) => vec![LoxDiagnostic::new(error.to_string(), error.span())],
+    }
+}
+
+/// Render the given diagnostic, with the offending line of the source code and
+/// its location underlined.
+///
+/// ```text
+/// error: Undefined variable 'b'.
+///  --> script.lox:1:11
+///   |
+/// 1 | print a + b;
+///   |           ^
+///   = hint: variables must be declared with 'var' before being used
+/// ```
+pub fn render_diagnostic<W: Write>(
+    out: &mut W,
+    diagnostic: &LoxDiagnostic,
+    source: &str,
+    file_name: &str,
+) -> fmt::Result {
+    writeln!(out, "error: {}", diagnostic.message)?;
+    let span = match diagnostic.span {
+        Some(span) => span,
+        None => {
+            writeln!(out, " --> {}", file_name)?;
+            for note in &diagnostic.notes {
+                writeln!(out, "  = {}", note)?;
+            }
+            return Ok(());
+        }
+    };
+
+    let gutter = " ".repeat(span.get_line().to_string().len());
+    writeln!(out, "{}--> {}:{}", gutter, file_name, span)?;
+    writeln!(out, "{} |", gutter)?;
+    let line = source.lines().nth(span.get_line() - 1).unwrap_or_default();
+    writeln!(out, "{} | {}", span.get_line(), line)?;
+    // keep the tabulations so that the carets stay aligned
+    let padding: String = line
+        .chars()
+        .take(span.get_column() - 1)
+        .map(|character| if character == '\t' { '\t' } else { ' ' })
+        .collect();
+    let underlined = line
+        .chars()
+        .skip(span.get_column() - 1)
+        .scan(0, |length, character| {
+            *length += character.len_utf8();
+            Some(*length)
+        })
+        .take_while(|length| *length <= span.get_length())
+        .count();
+    writeln!(
+        out,
+        "{} | {}{}",
+        gutter,
+        padding,
+        "^".repeat(underlined.max(1))
+    )?;
+    for note in &diagnostic.notes {
+        writeln!(out, "{} = {}", gutter, note)?;
+    }
+    Ok(())
+}
+
+/// Render all the diagnostics reporting the given error.
+pub fn render_error(error: &LoxError, source: &str, file_name: &str) -> String {
+    let mut rendered = String::new();
+    for (i, diagnostic) in diagnostics_from_error(error).iter().enumerate() {
+        if i > 0 {
+            rendered.push('\n');
+        }
+        render_diagnostic(&mut rendered, diagnostic, source, file_name)
+            .expect("writing to a String cannot fail");
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bytecode::vm::LoxBytecodeVirtualMachine,
+        errors::LoxInterpreterError,
+        interpreter::{LoxInterpreter, LoxTreeWalkInterpreter},
+        lexer::Lexer,
+    };
+
+    use super::render_error;
+
+    fn render(interpreter: &mut dyn LoxInterpreter, source: &str) -> String {
+        let error = interpreter.run_code(source).unwrap_err();
+        render_error(&error, source, "test.lox")
+    }
+
+    #[test]
+    fn test_diagnostics_rendering() {
+        let source = "var a = 1;\nprint a +\n\tb;";
+        let expected = concat!(
+            "error: Undefined variable 'b'.\n",
+            " --> test.lox:3:2\n",
+            "  |\n",
+            "3 | \tb;\n",
+            "  | \t^\n",
+            "  = hint: variables must be declared with 'var' before being used\n",
+            "  = trace: [line 3] in script\n",
+        );
+        assert_eq!(
+            render(&mut LoxTreeWalkInterpreter::new(None), source),
+            expected
+        );
+        assert_eq!(
+            render(&mut LoxBytecodeVirtualMachine::default(), source),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_diagnostics_underline() {
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        assert_eq!(
+            render(&mut interpreter, "print \"é\" + unknown;"),
+            concat!(
+                "error: Undefined variable 'unknown'.\n",
+                " --> test.lox:1:13\n",
+                "  |\n",
+                "1 | print \"é\" + unknown;\n",
+                "  |             ^^^^^^^\n",
+                "  = hint: variables must be declared with 'var' before being used\n",
+                "  = trace: [line 1] in script\n",
+            )
+        );
+        // the parse error message is kept
+        let source = "print 1";
+        let end_of_file = Lexer::from_source(source.to_string()).unwrap().get_tokens()[2].clone();
+        let error = LoxInterpreterError::ParserError(end_of_file, "Expect ';' after value.".into());
+        assert_eq!(
+            render_error(&error.into(), source, "test.lox"),
+            concat!(
+                "error: Expect ';' after value.\n",
+                " --> test.lox:1:8\n",
+                "  |\n",
+                "1 | print 1\n",
+                "  |        ^\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_diagnostics_multiple_errors() {
+        let source = "print 1 +;\nvar 2;";
+        assert_eq!(
+            render(&mut LoxBytecodeVirtualMachine::default(), source),
+            concat!(
+                "error: Expect expression.\n",
+                " --> test.lox:1:10\n",
+                "  |\n",
+                "1 | print 1 +;\n",
+                "  |          ^\n",
+                "\n",
+                "error: Expect variable name.\n",
+                " --> test.lox:2:5\n",
+                "  |\n",
+                "2 | var 2;\n",
+                "  |     ^\n",
+            )
+        );
+    }
+}
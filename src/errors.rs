@@ -1,6 +1,11 @@
+use std::fmt;
+
 use thiserror::Error;
 
-use crate::{lexer::LoxToken, values::LoxValueHandle};
+use crate::{
+    lexer::{LoxToken, LoxTokenType},
+    span::LoxSourceSpan,
+};
 
 pub type Result<T> = std::result::Result<T, LoxInterpreterError>;
 
@@ -8,18 +13,21 @@ pub type Result<T> = std::result::Result<T, LoxInterpreterError>;
 pub enum LoxInterpreterError {
     #[error("IO Error: {0}")]
     IOError(#[from] std::io::Error),
-    #[error("Unterminated string")]
-    LexerUnterminatedString,
+    #[error("Unterminated string.")]
+    LexerUnterminatedString(LoxSourceSpan),
     #[error("Invalid number: {0}")]
-    LexerInvalidNumber(String),
-    #[error("Unexpected character at line {0}")]
-    LexerUnexpectedCharacter(String),
-    #[error("Parse error")]
+    LexerInvalidNumber(String, LoxSourceSpan),
+    #[error("Unexpected character.")]
+    LexerUnexpectedCharacter(String, LoxSourceSpan),
+    #[error("[line {}] Error{}: {}", .0.get_line_number(), token_location(.0), .1)]
     ParserError(LoxToken, String),
+    /// All the syntax or resolution errors found in a script, in order of appearance.
+    #[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))]
+    ParserErrors(Vec<LoxInterpreterError>),
     #[error("Parse error: unexpected operation: {0}")]
-    ParserUnexpectedOperation(String),
+    ParserUnexpectedOperation(String, Option<LoxSourceSpan>),
     #[error("Resolver error: unexpected operation: {0}")]
-    ResolverUnexpectedOperation(String),
+    ResolverUnexpectedOperation(String, Option<LoxSourceSpan>),
     #[error("Can't read local variable in its own initializer.")]
     ResolverRecursiveLocalAssignment(LoxToken),
     #[error("Already a variable with this name in this scope.")]
@@ -31,29 +39,174 @@ pub enum LoxInterpreterError {
     #[error("Can't use 'this' outside of a class.")]
     ResolverImpossibleThisUsage(LoxToken),
     #[error("A class can't inherit from itself.")]
-    ResolverRecursiveInheritance(String),
+    ResolverRecursiveInheritance(LoxToken),
     #[error("Can't use 'super' outside of a class.")]
-    ResolverSuperUseOutsideOfClass(),
+    ResolverSuperUseOutsideOfClass(LoxToken),
     #[error("Can't use 'super' in a class with no superclass.")]
-    ResolverSuperUseOutsideOfSubClass(),
-    #[error("Unexpected operation: {0}")]
-    InterpreterUnexpectedOperation(String),
-    #[error("Not a number: {0}")]
-    InterpreterNotANumber(String),
+    ResolverSuperUseOutsideOfSubClass(LoxToken),
+    #[error("Unexpected operation: {}", .0.get_lexeme())]
+    InterpreterUnexpectedOperation(LoxToken),
+    #[error("Operand must be a number.")]
+    InterpreterNotANumber(LoxSourceSpan),
+    #[error("Operands must be numbers.")]
+    InterpreterNotNumbers(LoxSourceSpan),
+    #[error("Operands must be two numbers or two strings.")]
+    InterpreterInvalidAddition(LoxSourceSpan),
     #[error("Undefined variable '{0}'.")]
-    InterpreterUndefinedVariable(String),
+    InterpreterUndefinedVariable(String, LoxSourceSpan),
     #[error("Can only call functions and classes.")]
     InterpreterNonCallableValue(LoxToken),
+    #[error("Only instances have properties.")]
+    InterpreterCannotGetProperty(LoxToken),
     #[error("Only instances have fields.")]
-    InterpreterCannotGetOrSetField(LoxToken),
-    #[error("Undefined property '{0}'.")]
-    InterpreterUndefinedClassProperty(String),
+    InterpreterCannotSetField(LoxToken),
+    #[error("Undefined property '{}'.", .0.get_lexeme())]
+    InterpreterUndefinedClassProperty(LoxToken),
     #[error("Expected {0} arguments but got {1}.")]
-    InterpreterCallableWrongArity(usize, usize),
+    InterpreterCallableWrongArity(usize, usize, LoxSourceSpan),
     #[error("Superclass must be a class.")]
-    InterpreterSuperClassNotAClass(String),
-    #[error("Return value")]
-    InterpreterReturn(LoxValueHandle), // TODO: find a better way
+    InterpreterSuperClassNotAClass(String, Option<LoxSourceSpan>),
+    #[error("Stack overflow.")]
+    InterpreterStackOverflow(LoxSourceSpan),
+    /// Runtime error, followed by the calls which led to it (innermost call first).
+    #[error("{0}\n{}", stack_trace(.0, .1).join("\n"))]
+    InterpreterStackTrace(Box<LoxInterpreterError>, Vec<LoxCallFrame>),
+}
+
+/// Call of a Lox function, as recorded in the stack trace of a runtime error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoxCallFrame {
+    function: String,
+    /// Line of the call site.
+    line: usize,
+}
+
+impl LoxCallFrame {
+    pub fn new(function: String, line: usize) -> Self {
+        Self { function, line }
+    }
+
+    pub fn get_function(&self) -> &str {
+        &self.function
+    }
+
+    pub fn get_line(&self) -> usize {
+        self.line
+    }
+}
+
+/// Format the stack trace of a runtime error as clox does, each function being
+/// reported with the line being run in it.
+fn stack_trace(error: &LoxInterpreterError, frames: &[LoxCallFrame]) -> Vec<String> {
+    let error_line = error.span().map_or(0, |span| span.get_line());
+    let lines = std::iter::once(error_line).chain(frames.iter().map(LoxCallFrame::get_line));
+    let functions = frames
+        .iter()
+        .map(|frame| format!("{}()", frame.function))
+        .chain(std::iter::once("script".to_string()));
+    lines
+        .zip(functions)
+        .map(|(line, function)| format!("[line {}] in {}", line, function))
+        .collect()
+}
+
+/// Description of the token where a syntax error occurred, as in " at 'x'".
+fn token_location(token: &LoxToken) -> String {
+    if token.get_kind() == &LoxTokenType::EndOfFile {
+        " at end".into()
+    } else {
+        format!(" at '{}'", token.get_lexeme())
+    }
+}
+
+impl LoxInterpreterError {
+    /// Is this error raised while running a script, after its successful parsing?
+    pub fn is_runtime_error(&self) -> bool {
+        matches!(
+            self,
+            Self::InterpreterUnexpectedOperation(_)
+                | Self::InterpreterNotANumber(_)
+                | Self::InterpreterNotNumbers(_)
+                | Self::InterpreterInvalidAddition(_)
+                | Self::InterpreterUndefinedVariable(_, _)
+                | Self::InterpreterNonCallableValue(_)
+                | Self::InterpreterCannotGetProperty(_)
+                | Self::InterpreterCannotSetField(_)
+                | Self::InterpreterUndefinedClassProperty(_)
+                | Self::InterpreterCallableWrongArity(_, _, _)
+                | Self::InterpreterStackOverflow(_)
+                | Self::InterpreterSuperClassNotAClass(_, _)
+                | Self::InterpreterStackTrace(_, _)
+        )
+    }
+
+    /// Record that the runtime error went up through the call of the given function.
+    pub fn through_call(self, frame: LoxCallFrame) -> Self {
+        match self {
+            Self::InterpreterStackTrace(error, mut frames) => {
+                frames.push(frame);
+                Self::InterpreterStackTrace(error, frames)
+            }
+            error if error.is_runtime_error() => {
+                Self::InterpreterStackTrace(Box::new(error), vec![frame])
+            }
+            error => error,
+        }
+    }
+
+    /// Attach a stack trace to a runtime error raised by the top-level code.
+    pub fn with_stack_trace(self) -> Self {
+        match self {
+            Self::InterpreterStackTrace(_, _) => self,
+            error if error.is_runtime_error() => {
+                Self::InterpreterStackTrace(Box::new(error), vec![])
+            }
+            error => error,
+        }
+    }
+
+    /// The lines of the stack trace of a runtime error (innermost call first), if any.
+    pub fn get_stack_trace(&self) -> Vec<String> {
+        match self {
+            Self::InterpreterStackTrace(error, frames) => stack_trace(error, frames),
+            _ => vec![],
+        }
+    }
+
+    /// Location in the source code of the lexemes responsible for the error, if any.
+    pub fn span(&self) -> Option<LoxSourceSpan> {
+        match self {
+            Self::IOError(_) => None,
+            Self::InterpreterStackTrace(error, _) => error.span(),
+            Self::LexerUnterminatedString(span)
+            | Self::LexerInvalidNumber(_, span)
+            | Self::LexerUnexpectedCharacter(_, span)
+            | Self::InterpreterNotANumber(span)
+            | Self::InterpreterNotNumbers(span)
+            | Self::InterpreterInvalidAddition(span)
+            | Self::InterpreterUndefinedVariable(_, span)
+            | Self::InterpreterCallableWrongArity(_, _, span)
+            | Self::InterpreterStackOverflow(span) => Some(*span),
+            Self::ParserErrors(errors) => errors.first().and_then(|error| error.span()),
+            Self::ParserUnexpectedOperation(_, span)
+            | Self::ResolverUnexpectedOperation(_, span)
+            | Self::InterpreterSuperClassNotAClass(_, span) => *span,
+            Self::ParserError(token, _)
+            | Self::ResolverRecursiveLocalAssignment(token)
+            | Self::ResolverDuplicateVariableDeclaration(token)
+            | Self::ResolverImpossibleTopLevelReturn(token)
+            | Self::ResolverImpossibleInitializerReturn(token)
+            | Self::ResolverImpossibleThisUsage(token)
+            | Self::ResolverRecursiveInheritance(token)
+            | Self::ResolverSuperUseOutsideOfClass(token)
+            | Self::ResolverSuperUseOutsideOfSubClass(token)
+            | Self::InterpreterUnexpectedOperation(token)
+            | Self::InterpreterNonCallableValue(token)
+            | Self::InterpreterCannotGetProperty(token)
+            | Self::InterpreterCannotSetField(token)
+            | Self::InterpreterUndefinedClassProperty(token) => Some(token.get_span()),
+        }
+    }
 }
 
 pub type BResult<T> = std::result::Result<T, LoxBytecodeInterpreterError>;
@@ -63,9 +216,117 @@ pub enum LoxBytecodeInterpreterError {
     #[error("IO Error: {0}")]
     IOError(#[from] std::io::Error),
     #[error("{0}")]
-    ParserError(String),
+    ParserError(String, LoxSourceSpan),
     #[error("'{0}' is not a valid number.")]
-    ParserInvalidNumber(String),
+    ParserInvalidNumber(String, LoxSourceSpan),
     #[error("Could not find the '{0}' rule.")]
-    CompilerUnknownRule(String),
+    CompilerUnknownRule(String, LoxSourceSpan),
+    #[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))]
+    CompilerErrors(Vec<LoxBytecodeCompilerError>),
+    /// Runtime error message, followed by the call stack trace (innermost call first),
+    /// and the location of the failing instruction.
+    #[error("{0}\n{}", .1.join("\n"))]
+    VMRuntimeError(String, Vec<String>, LoxSourceSpan),
+}
+
+impl LoxBytecodeInterpreterError {
+    /// Location in the source code of the lexemes responsible for the error, if any.
+    ///
+    /// When compilation fails, this is the location of the first error.
+    pub fn span(&self) -> Option<LoxSourceSpan> {
+        match self {
+            Self::IOError(_) => None,
+            Self::ParserError(_, span)
+            | Self::ParserInvalidNumber(_, span)
+            | Self::CompilerUnknownRule(_, span)
+            | Self::VMRuntimeError(_, _, span) => Some(*span),
+            Self::CompilerErrors(errors) => errors.first().map(|error| error.get_span()),
+        }
+    }
+}
+
+/// Compilation error reported by the bytecode compiler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoxBytecodeCompilerError {
+    message: String,
+    /// Description of the offending token (" at 'x'", " at end"), if relevant.
+    location: String,
+    line: usize,
+    span: LoxSourceSpan,
+}
+
+impl LoxBytecodeCompilerError {
+    pub fn new(message: String, location: String, line: usize, span: LoxSourceSpan) -> Self {
+        Self {
+            message,
+            location,
+            line,
+            span,
+        }
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn get_line(&self) -> usize {
+        self.line
+    }
+
+    pub fn get_span(&self) -> LoxSourceSpan {
+        self.span
+    }
+}
+
+impl fmt::Display for LoxBytecodeCompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[line {}] Error{}: {}",
+            self.line, self.location, self.message
+        )
+    }
+}
+
+pub type LoxResult<T> = std::result::Result<T, LoxError>;
+
+/// Exit code of a script failing to compile (`EX_DATAERR`), as expected by the
+/// reference test runner.
+pub const LOX_EXIT_CODE_COMPILE_ERROR: i32 = 65;
+/// Exit code of a script failing at runtime (`EX_SOFTWARE`).
+pub const LOX_EXIT_CODE_RUNTIME_ERROR: i32 = 70;
+/// Exit code of a script that could not be read (`EX_IOERR`).
+pub const LOX_EXIT_CODE_IO_ERROR: i32 = 74;
+
+/// Error raised by any of the Lox interpreters.
+#[derive(Debug, Error)]
+pub enum LoxError {
+    #[error(transparent)]
+    TreeWalk(#[from] LoxInterpreterError),
+    #[error(transparent)]
+    Bytecode(#[from] LoxBytecodeInterpreterError),
+}
+
+impl LoxError {
+    /// Location in the source code of the lexemes responsible for the error, if any.
+    pub fn span(&self) -> Option<LoxSourceSpan> {
+        match self {
+            Self::TreeWalk(error) => error.span(),
+            Self::Bytecode(error) => error.span(),
+        }
+    }
+
+    /// Process exit code reporting this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::TreeWalk(LoxInterpreterError::IOError(_))
+            | Self::Bytecode(LoxBytecodeInterpreterError::IOError(_)) => LOX_EXIT_CODE_IO_ERROR,
+            Self::TreeWalk(error) if error.is_runtime_error() => LOX_EXIT_CODE_RUNTIME_ERROR,
+            Self::Bytecode(LoxBytecodeInterpreterError::VMRuntimeError(_, _, _)) => {
+                LOX_EXIT_CODE_RUNTIME_ERROR
+            }
+            // lexing, parsing, resolving and compiling errors
+            _ => LOX_EXIT_CODE_COMPILE_ERROR,
+        }
+    }
 }
@@ -1,9 +1,9 @@
-use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::{
     errors::{LoxInterpreterError, Result},
     lexer::LoxToken,
-    printer::LoxPrintable,
+    span::LoxSourceSpan,
 };
 
 #[derive(Clone)]
@@ -19,6 +19,7 @@ impl LoxOperation {
             Self::Expression(expression) => Ok(expression),
             _ => Err(LoxInterpreterError::ParserUnexpectedOperation(
                 "operation is not an expression".into(),
+                self.span(),
             )),
         }
     }
@@ -28,9 +29,41 @@ impl LoxOperation {
             Self::Statement(statement) => Ok(statement),
             _ => Err(LoxInterpreterError::ParserUnexpectedOperation(
                 "operation is not a statement".into(),
+                self.span(),
             )),
         }
     }
+
+    /// Location of the operation in the source code, if any.
+    pub fn span(&self) -> Option<LoxSourceSpan> {
+        match self {
+            Self::Invalid => None,
+            Self::Expression(expression) => expression.span(),
+            Self::Statement(statement) => statement.span(),
+        }
+    }
+}
+
+/// Smallest span covering all the given spans, if any.
+fn merge_spans(spans: impl IntoIterator<Item = Option<LoxSourceSpan>>) -> Option<LoxSourceSpan> {
+    spans
+        .into_iter()
+        .flatten()
+        .reduce(|merged, span| merged.merge(&span))
+}
+
+/// Unique identifier of an expression referring to a variable, used to resolve
+/// the scope the variable is declared in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LoxExpressionId(usize);
+
+impl LoxExpressionId {
+    /// Identifiers are unique across parsings, for the expressions of successive
+    /// runs (e.g. in the REPL) to never collide.
+    pub fn new_unique() -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
 }
 
 #[derive(Clone)]
@@ -38,6 +71,7 @@ pub enum LoxExpression {
     NoOp,
     /// Variable assignment.
     Assign {
+        id: LoxExpressionId,
         name: LoxToken,
         value: Box<LoxExpression>,
     },
@@ -65,6 +99,7 @@ pub enum LoxExpression {
     /// Literal value.
     Literal {
         value: LoxLiteral,
+        span: LoxSourceSpan,
     },
     /// Logical (and/or) branching.
     Logical {
@@ -80,11 +115,13 @@ pub enum LoxExpression {
     },
     /// Super expression.
     Super {
+        id: LoxExpressionId,
         keyword: LoxToken,
         method: LoxToken,
     },
     /// This expression.
     This {
+        id: LoxExpressionId,
         keyword: LoxToken,
     },
     /// Unary operation.
@@ -94,90 +131,60 @@ pub enum LoxExpression {
     },
     /// Variable access.
     Variable {
+        id: LoxExpressionId,
         name: LoxToken,
     },
 }
 
-impl Hash for LoxExpression {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+impl LoxExpression {
+    pub fn is_noop(&self) -> bool {
+        matches!(self, Self::NoOp)
+    }
+
+    /// Location of the expression in the source code, if any.
+    pub fn span(&self) -> Option<LoxSourceSpan> {
         match self {
-            Self::NoOp => {
-                self.representation().hash(state);
-            }
-            Self::Assign { name, value } => {
-                name.hash(state);
-                value.hash(state);
+            Self::NoOp => None,
+            Self::Assign { id: _, name, value } => {
+                merge_spans([Some(name.get_span()), value.span()])
             }
             Self::Binary {
                 left,
-                operator,
+                operator: _,
                 right,
-            } => {
-                left.hash(state);
-                operator.hash(state);
-                right.hash(state);
             }
+            | Self::Logical {
+                left,
+                operator: _,
+                right,
+            } => merge_spans([left.span(), right.span()]),
             Self::Call {
                 callee,
-                arguments,
                 parenthesis,
-            } => {
-                callee.hash(state);
-                arguments.hash(state);
-                parenthesis.hash(state);
-            }
-            Self::Get { name, object } => {
-                name.hash(state);
-                object.hash(state);
-            }
-            Self::Group { expression } => {
-                expression.hash(state);
-            }
-            Self::Literal { value: _ } => {
-                self.representation().hash(state);
-            }
-            Self::Logical {
-                left,
-                operator,
-                right,
-            } => {
-                left.hash(state);
-                operator.hash(state);
-                right.hash(state);
-            }
+                arguments: _,
+            } => merge_spans([callee.span(), Some(parenthesis.get_span())]),
+            Self::Get { object, name } => merge_spans([object.span(), Some(name.get_span())]),
+            Self::Group { expression } => expression.span(),
+            Self::Literal { value: _, span } => Some(*span),
             Self::Set {
-                name,
                 object,
+                name: _,
                 value,
-            } => {
-                name.hash(state);
-                object.hash(state);
-                value.hash(state);
-            }
-            Self::Super { keyword, method } => {
-                keyword.hash(state);
-                method.hash(state);
-            }
-            Self::This { keyword } => {
-                keyword.hash(state);
-            }
+            } => merge_spans([object.span(), value.span()]),
+            Self::Super {
+                id: _,
+                keyword,
+                method,
+            } => merge_spans([Some(keyword.get_span()), Some(method.get_span())]),
+            Self::This { id: _, keyword } => Some(keyword.get_span()),
             Self::Unary { operator, right } => {
-                operator.hash(state);
-                right.hash(state);
-            }
-            Self::Variable { name } => {
-                name.hash(state);
+                merge_spans([Some(operator.get_span()), right.span()])
             }
+            Self::Variable { id: _, name } => Some(name.get_span()),
         }
     }
 }
 
-impl LoxExpression {
-    pub fn is_noop(&self) -> bool {
-        matches!(self, Self::NoOp)
-    }
-}
-
 #[derive(Clone, Debug, PartialEq)]
 pub enum LoxLiteral {
     Number(f64),
@@ -255,6 +262,43 @@ impl LoxStatement {
         }
     }
 
+    /// Location of the statement in the source code, if any.
+    ///
+    /// Keywords and punctuation not stored in the syntax tree are not covered.
+    pub fn span(&self) -> Option<LoxSourceSpan> {
+        match self {
+            Self::NoOp => None,
+            Self::Block { statements } => merge_spans(statements.iter().map(Self::span)),
+            Self::Class {
+                name,
+                super_class: _,
+                methods,
+            } => merge_spans(
+                std::iter::once(Some(name.get_span())).chain(methods.iter().map(Self::span)),
+            ),
+            Self::Expression { expression } | Self::Print { expression } => expression.span(),
+            Self::Function {
+                name,
+                parameters: _,
+                body,
+            } => merge_spans(
+                std::iter::once(Some(name.get_span())).chain(body.iter().map(Self::span)),
+            ),
+            Self::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => merge_spans([condition.span(), then_branch.span(), else_branch.span()]),
+            Self::Return { keyword, value } => {
+                merge_spans([Some(keyword.get_span()), value.span()])
+            }
+            Self::Variable { name, initializer } => {
+                merge_spans([Some(name.get_span()), initializer.span()])
+            }
+            Self::While { condition, body } => merge_spans([condition.span(), body.span()]),
+        }
+    }
+
     pub fn get_type_representation(&self) -> &str {
         match self {
             Self::NoOp => "noop",
@@ -1,49 +1,38 @@
 use crate::{
-    errors::Result,
-    expressions::LoxOperation,
+    errors::{LoxInterpreterError, LoxResult, Result},
+    expressions::{LoxOperation, LoxStatement},
     lexer::Lexer,
-    parser::Parser,
-    values::{LoxValue, LoxValueHandle},
+    parser::{LoxParsedScript, Parser},
+    printer::{LoxLinePrinterInstance, LoxPrintable, StdOutPrinter},
+    values::LoxValue,
 };
 
 use self::{
-    environment::LoxEnvironmentHandle,
-    resolver::LoxResolver,
-    tree_walk::{LoxLinePrinter, LoxLinePrinterInstance, LoxTreeWalkEvaluator},
+    environment::LoxEnvironmentHandle, resolver::LoxResolver, tree_walk::LoxTreeWalkEvaluator,
 };
 
 pub mod builtins;
 pub mod environment;
+pub mod heap;
 pub mod resolver;
 pub mod tree_walk;
 
+/// Common interface of the Lox interpreters (tree-walk and bytecode virtual machine).
 pub trait LoxInterpreter {
-    fn parse(&self, source: String) -> Result<Vec<LoxOperation>> {
-        let lexer = Lexer::from_source(source)?;
-        Parser::from_tokens(lexer.get_tokens().clone()).parse()
-    }
-
-    fn interpret(&mut self, operations: &[LoxOperation]) -> Result<LoxValueHandle>;
+    /// Run the given source code, keeping any state (e.g. global variables) from
+    /// the previous runs.
+    ///
+    /// Returns the representation of the value of the trailing expression, if any.
+    fn run_code(&mut self, code: &str) -> LoxResult<Option<String>>;
 
-    fn get_environment(&self) -> &LoxEnvironmentHandle;
+    /// Output sink of the `print` statement.
+    fn get_printer(&self) -> &LoxLinePrinterInstance;
 }
 
 pub struct LoxTreeWalkInterpreter {
     resolver: LoxResolver,
 }
 
-pub struct StdOutPrinter;
-
-impl LoxLinePrinter for StdOutPrinter {
-    fn print(&mut self, output: String) {
-        println!("{}", output);
-    }
-
-    fn history(&self) -> Option<&[String]> {
-        None
-    }
-}
-
 impl LoxTreeWalkInterpreter {
     pub fn new(printer: Option<LoxLinePrinterInstance>) -> Self {
         let evaluator =
@@ -52,28 +41,66 @@ impl LoxTreeWalkInterpreter {
             resolver: LoxResolver::new(evaluator),
         }
     }
-}
 
-impl LoxInterpreter for LoxTreeWalkInterpreter {
-    fn interpret(&mut self, operations: &[LoxOperation]) -> Result<LoxValueHandle> {
-        for operation in operations {
-            self.resolver.resolve(operation)?;
+    /// Parse the given source code, refusing any script with syntax errors.
+    pub fn parse(&self, source: String) -> Result<Vec<LoxOperation>> {
+        Self::parse_script(source).into_operations()
+    }
+
+    /// Parse the given source code, reporting all the syntax errors along with
+    /// the partial syntax tree.
+    pub fn parse_script(source: String) -> LoxParsedScript {
+        Parser::from_lexer(Lexer::scan(source)).parse()
+    }
+
+    pub fn interpret(&mut self, operations: &[LoxOperation]) -> Result<LoxValue> {
+        // resolve every declaration, to report all their errors at once
+        self.resolver.get_evaluator_mut().reset_locals();
+        let mut errors: Vec<LoxInterpreterError> = operations
+            .iter()
+            .filter_map(|operation| self.resolver.resolve(operation).err())
+            .collect();
+        match errors.len() {
+            0 => (),
+            1 => return Err(errors.remove(0)),
+            _ => return Err(LoxInterpreterError::ParserErrors(errors)),
         }
-        let mut last_value = LoxValue::new(LoxValue::Nil);
+        let mut last_value = LoxValue::Nil;
         for operation in operations {
-            last_value = self.resolver.get_evaluator_mut().evaluate(operation)?;
+            last_value = self
+                .resolver
+                .get_evaluator_mut()
+                .evaluate(operation)
+                .map_err(LoxInterpreterError::with_stack_trace)?;
         }
         Ok(last_value)
     }
 
-    fn get_environment(&self) -> &LoxEnvironmentHandle {
+    pub fn get_environment(&self) -> &LoxEnvironmentHandle {
         self.resolver.get_evaluator().get_environment()
     }
 }
 
+impl LoxInterpreter for LoxTreeWalkInterpreter {
+    fn run_code(&mut self, code: &str) -> LoxResult<Option<String>> {
+        let operations = self.parse(code.to_string())?;
+        let value = self.interpret(&operations)?;
+        match operations.last() {
+            Some(LoxOperation::Statement(LoxStatement::Expression { expression: _ })) => {
+                Ok(Some(value.representation()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn get_printer(&self) -> &LoxLinePrinterInstance {
+        self.resolver.get_evaluator().get_printer()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{printer::operations_representation, values::LoxValue};
+    use crate::{printer::operations_representation, span::LoxSourceSpan, values::LoxValue};
 
     use super::{LoxInterpreter, LoxTreeWalkInterpreter};
 
@@ -203,6 +230,258 @@ variable = "after";
             .borrow()
             .get("variable")
             .unwrap();
-        assert!(variable.borrow().equals(&LoxValue::String("after".into())));
+        assert!(variable.equals(&LoxValue::String("after".into())));
+    }
+
+    #[test]
+    fn test_tree_walk_interpreter_implicit_returns() {
+        let source = r#"
+class Point {
+  init(x) {
+    this.x = x;
+  }
+  move() {
+    this.x = this.x + 1;
+  }
+}
+fun nothing() {}
+var point = Point(1);
+"#;
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        assert_eq!(interpreter.run_code(source).unwrap(), None);
+        // the initializer is bound to the new instance
+        assert_eq!(interpreter.run_code("point.x;").unwrap(), Some("1".into()));
+        // functions and methods falling off their end return nil
+        assert_eq!(
+            interpreter.run_code("nothing();").unwrap(),
+            Some("nil".into())
+        );
+        assert_eq!(
+            interpreter.run_code("point.move();").unwrap(),
+            Some("nil".into())
+        );
+        assert_eq!(interpreter.run_code("point.x;").unwrap(), Some("2".into()));
+    }
+
+    #[test]
+    fn test_tree_walk_interpreter_error_spans() {
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        assert_eq!(
+            interpreter
+                .run_code("var a = 1;\n-\"a\";")
+                .unwrap_err()
+                .span(),
+            Some(LoxSourceSpan::new(11, 1, 2, 1))
+        );
+        assert_eq!(
+            interpreter.run_code("print  missing;").unwrap_err().span(),
+            Some(LoxSourceSpan::new(7, 7, 1, 8))
+        );
+    }
+
+    #[test]
+    fn test_tree_walk_interpreter_syntax_errors() {
+        let source = "print 1 +;\nvar = 2;\n{ print; print \"block\"; }\nprint \"ok\";\n@";
+        let script = LoxTreeWalkInterpreter::parse_script(source.to_string());
+        assert_eq!(
+            operations_representation(script.get_operations()),
+            "(block (print block))\n(print ok)"
+        );
+        let errors: Vec<String> = script
+            .get_errors()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "[line 1] Error at ';': Expect expression.",
+                "[line 2] Error at '=': Expect variable name.",
+                "[line 3] Error at ';': Expect expression.",
+                "Unexpected character.",
+            ]
+        );
+
+        // scripts with syntax errors are not run
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        assert!(interpreter
+            .run_code("var defined = 1;\nprint 1 +;")
+            .is_err());
+        assert!(interpreter
+            .get_environment()
+            .borrow()
+            .get("defined")
+            .is_none());
+        assert_eq!(
+            interpreter.run_code("1 = 2;").unwrap_err().to_string(),
+            "[line 1] Error at '=': Invalid assignment target."
+        );
+    }
+
+    #[test]
+    fn test_tree_walk_interpreter_stack_traces() {
+        let source = r#"
+fun inner() {
+  return -"not a number";
+}
+fun outer() {
+  return inner();
+}
+outer();
+"#;
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        assert_eq!(
+            interpreter.run_code(source).unwrap_err().to_string(),
+            "Operand must be a number.\n[line 3] in inner()\n[line 6] in outer()\n[line 8] in script"
+        );
+        assert_eq!(
+            interpreter.run_code("print -nil;").unwrap_err().to_string(),
+            "Operand must be a number.\n[line 1] in script"
+        );
+        assert_eq!(
+            interpreter
+                .run_code("print 1 < nil;")
+                .unwrap_err()
+                .to_string(),
+            "Operands must be numbers.\n[line 1] in script"
+        );
+        let stack_overflow = interpreter
+            .run_code("fun f() { f(); } f();")
+            .unwrap_err()
+            .to_string();
+        assert!(stack_overflow.starts_with("Stack overflow.\n[line 1] in f()\n"));
+        assert!(stack_overflow.ends_with("[line 1] in f()\n[line 1] in script"));
+        // the call depth is back to zero after the error
+        assert_eq!(
+            interpreter
+                .run_code("fun g(n) { if (n > 0) return g(n - 1); return n; } g(32);")
+                .unwrap(),
+            Some("0".into())
+        );
+    }
+
+    #[test]
+    fn test_tree_walk_interpreter_variable_resolution() {
+        let source = r#"
+var a = "global";
+var first;
+var second;
+{
+  fun showA() { return a; }
+  first = showA();
+  var a = "block";
+  second = showA() + a;
+}
+first + second;
+"#;
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        assert_eq!(
+            interpreter.run_code(source).unwrap(),
+            Some("globalglobalblock".into())
+        );
+        // deeper scopes, with several uses of the same variable on one line
+        let source = "fun twice(n) { { { n = n + n; } } return n; }
+twice(21);";
+        assert_eq!(interpreter.run_code(source).unwrap(), Some("42".into()));
+        assert_eq!(interpreter.run_code("twice(1);").unwrap(), Some("2".into()));
+    }
+
+    #[test]
+    fn test_tree_walk_interpreter_local_slots() {
+        let source = r#"
+fun outer() {
+  var a = 1;
+  var b = 2;
+  fun inner() { var c = 3; a = a + c; return a + b; }
+  return inner;
+}
+var f = outer();
+f();
+f();
+"#;
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        assert_eq!(interpreter.run_code(source).unwrap(), Some("9".into()));
+        let source = r#"
+fun make(x) {
+  class Point { init(x) { this.x = x; } get() { return this.x; } }
+  return Point(x);
+}
+make(5).get();
+"#;
+        assert_eq!(interpreter.run_code(source).unwrap(), Some("5".into()));
+    }
+
+    #[test]
+    fn test_tree_walk_interpreter_successive_runs() {
+        let source = r#"
+fun counter() {
+  var count = 0;
+  fun increment() { count = count + 1; return count; }
+  return increment;
+}
+var next = counter();
+"#;
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        interpreter.run_code(source).unwrap();
+        // functions keep the resolved local variables of the run declaring them
+        for count in 1..=3 {
+            assert_eq!(
+                interpreter.run_code("next();").unwrap(),
+                Some(count.to_string())
+            );
+            assert!(interpreter.resolver.get_evaluator().get_locals().is_empty());
+        }
+        assert_eq!(
+            interpreter.run_code("{ var a = 1; a + next(); }").unwrap(),
+            None
+        );
+        assert_eq!(interpreter.resolver.get_evaluator().get_locals().len(), 1);
+    }
+
+    #[test]
+    fn test_tree_walk_interpreter_returns() {
+        let source = r#"
+fun find(target) {
+  for (var i = 0; ; i = i + 1) {
+    { if (i == target) return i; }
+  }
+}
+fun nothing() { 1; }
+fun early() { return; print "unreachable"; }
+print nothing();
+print early();
+find(3);
+"#;
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        assert_eq!(interpreter.run_code(source).unwrap(), Some("3".into()));
+        assert_eq!(
+            interpreter.run_code("nothing() == early();").unwrap(),
+            Some("true".into())
+        );
+    }
+
+    #[test]
+    fn test_tree_walk_interpreter_value_semantics() {
+        let source = r#"
+class Box {}
+var a = 1;
+var b = a;
+b = b + 1;
+var first = Box();
+var second = first;
+second.value = "shared";
+var greeting = "hello";
+"#;
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        assert_eq!(interpreter.run_code(source).unwrap(), None);
+        for (code, expected) in [
+            ("a;", "1"),
+            ("first.value;", "shared"),
+            ("first == second;", "true"),
+            ("Box() == Box();", "false"),
+            ("greeting == \"hel\" + \"lo\";", "true"),
+        ] {
+            assert_eq!(interpreter.run_code(code).unwrap(), Some(expected.into()));
+        }
     }
 }
@@ -2,18 +2,16 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::{
     errors::Result,
-    values::{LoxValue, LoxValueHandle},
+    values::{LoxObject, LoxValue},
 };
 
-pub fn build_lox_clock_builtin() -> LoxValueHandle {
-    LoxValue::new(LoxValue::NativeFunction {
+pub fn build_lox_clock_builtin() -> LoxValue {
+    LoxValue::new_object(LoxObject::NativeFunction {
         label: "clock".into(),
         arity: 0,
-        execute: |_env, _arguments| -> Result<LoxValueHandle> {
+        execute: |_env, _arguments| -> Result<LoxValue> {
             let time_since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-            Ok(LoxValue::new(LoxValue::Number(
-                time_since_epoch.as_secs_f64(),
-            )))
+            Ok(LoxValue::Number(time_since_epoch.as_secs_f64()))
         },
     })
 }
@@ -1,112 +1,167 @@
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-use crate::{
-    errors::{LoxInterpreterError, Result},
-    values::LoxValueHandle,
-};
+use crate::values::LoxValue;
+
+use super::heap::LoxHeapTracer;
 
 pub type LoxEnvironmentHandle = Rc<RefCell<LoxEnvironment>>;
 
-/// Retrieve a variable, with the given lookup depth.
+/// Retrieve a local variable, with the given lookup depth and slot.
 pub fn environment_handle_get_at_depth(
     handle: &LoxEnvironmentHandle,
-    name: &str,
-    distance: usize,
-) -> Result<LoxValueHandle> {
-    environment_handle_ancestor(handle, distance)
-        .borrow()
-        .get(name)
+    depth: usize,
+    slot: usize,
+) -> Option<LoxValue> {
+    handle.borrow().get_at_depth(depth, slot)
 }
 
-/// Assign a variable with the given lookup depth.
+/// Assign a local variable, with the given lookup depth and slot.
 pub fn environment_handle_assign_at_depth(
-    handle: &mut LoxEnvironmentHandle,
+    handle: &LoxEnvironmentHandle,
+    depth: usize,
+    slot: usize,
+    value: LoxValue,
+) -> bool {
+    handle.borrow_mut().assign_at_depth(depth, slot, value)
+}
+
+/// Retrieve a global variable.
+pub fn environment_handle_get_global(
+    handle: &LoxEnvironmentHandle,
     name: &str,
-    distance: usize,
-    value: LoxValueHandle,
-) {
-    environment_handle_ancestor(handle, distance)
-        .borrow_mut()
-        .values
-        .insert(name.into(), value);
+) -> Option<LoxValue> {
+    handle.borrow().get(name)
 }
 
-fn environment_handle_ancestor(
+/// Assign an existing global variable.
+///
+/// Returns false if the variable is undefined.
+pub fn environment_handle_assign_global(
     handle: &LoxEnvironmentHandle,
-    distance: usize,
-) -> LoxEnvironmentHandle {
-    let mut current = handle.clone();
-    for _ in 0..distance {
-        let current_env = handle.borrow();
-        current = current_env.outer.as_ref().unwrap().clone();
-    }
-    current
+    name: &str,
+    value: LoxValue,
+) -> bool {
+    handle.borrow_mut().assign(name, value)
 }
 
 /// A Lox environment stores variables within a certain scope.
+///
+/// Local variables are stored in the slots assigned by the resolver, while the
+/// global variables of the outermost environment are looked up by name.
 #[derive(Clone)]
 pub struct LoxEnvironment {
-    values: HashMap<String, LoxValueHandle>,
+    /// Local variables, in order of declaration.
+    slots: Vec<LoxValue>,
+    /// Global variables, by name.
+    globals: HashMap<String, LoxValue>,
     /// The enclosing environment, if any.
     outer: Option<LoxEnvironmentHandle>,
+    /// Is the environment registered in the heap, i.e. captured by a closure?
+    is_tracked: bool,
 }
 
 impl LoxEnvironment {
     pub fn new(outer: Option<LoxEnvironmentHandle>) -> LoxEnvironmentHandle {
         Rc::new(RefCell::new(Self {
-            values: HashMap::new(),
+            slots: vec![],
+            globals: HashMap::new(),
             outer,
+            is_tracked: false,
         }))
     }
 
-    /// Define a variable.
-    pub fn define(&mut self, name: String, value: LoxValueHandle) {
-        self.values.insert(name, value);
+    pub fn is_global(&self) -> bool {
+        self.outer.is_none()
     }
 
-    /// Assign to an existing variable.
-    pub fn assign(&mut self, name: &str, value: LoxValueHandle) -> Result<()> {
-        if self.values.contains_key(name) {
-            self.values.insert(name.to_string(), value);
-            Ok(())
+    /// Define a variable, by name in the global environment and in the next
+    /// slot otherwise.
+    pub fn define(&mut self, name: &str, value: LoxValue) {
+        if self.is_global() {
+            self.globals.insert(name.to_string(), value);
+        } else {
+            self.slots.push(value);
+        }
+    }
+
+    /// Assign to an existing global variable.
+    ///
+    /// Returns false if the variable is undefined.
+    pub fn assign(&mut self, name: &str, value: LoxValue) -> bool {
+        if let Some(variable) = self.globals.get_mut(name) {
+            *variable = value;
+            true
         } else if let Some(outer) = &mut self.outer {
             outer.borrow_mut().assign(name, value)
         } else {
-            Err(LoxInterpreterError::InterpreterUndefinedVariable(
-                name.to_string(),
-            ))
+            false
         }
     }
 
-    /// Retrieve a variable.
-    pub fn get(&self, name: &str) -> Result<LoxValueHandle> {
-        let local_value = self.values.get(name);
-        if let Some(value) = local_value {
-            Ok(value.clone())
+    /// Retrieve a global variable, if defined.
+    pub fn get(&self, name: &str) -> Option<LoxValue> {
+        if let Some(value) = self.globals.get(name) {
+            Some(value.clone())
         } else if let Some(outer) = &self.outer {
-            Self::get_deeply(name, outer)
+            outer.borrow().get(name)
         } else {
-            Err(LoxInterpreterError::InterpreterUndefinedVariable(
-                name.to_string(),
-            ))
+            None
         }
     }
 
-    fn get_deeply(name: &str, env: &LoxEnvironmentHandle) -> Result<LoxValueHandle> {
-        let mut current = env.clone();
-        loop {
-            if let Some(value) = current.borrow().values.get(name).cloned() {
-                return Ok(value);
-            }
-            let new = if let Some(outer) = &current.borrow().outer {
-                outer.clone()
-            } else {
-                break;
+    /// Retrieve a local variable, with the given lookup depth and slot.
+    fn get_at_depth(&self, depth: usize, slot: usize) -> Option<LoxValue> {
+        if depth == 0 {
+            self.slots.get(slot).cloned()
+        } else {
+            self.outer.as_ref()?.borrow().get_at_depth(depth - 1, slot)
+        }
+    }
+
+    /// Assign a local variable, with the given lookup depth and slot.
+    fn assign_at_depth(&mut self, depth: usize, slot: usize, value: LoxValue) -> bool {
+        if depth > 0 {
+            return match &self.outer {
+                Some(outer) => outer.borrow_mut().assign_at_depth(depth - 1, slot, value),
+                None => false,
             };
-            current = new;
         }
-        Err(LoxInterpreterError::InterpreterUndefinedVariable(
-            name.to_string(),
-        ))
+        match self.slots.get_mut(slot) {
+            Some(variable) => {
+                *variable = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_outer(&self) -> Option<&LoxEnvironmentHandle> {
+        self.outer.as_ref()
+    }
+
+    pub fn is_tracked(&self) -> bool {
+        self.is_tracked
+    }
+
+    pub fn set_tracked(&mut self) {
+        self.is_tracked = true;
+    }
+
+    /// Mark the values and the enclosing environment referenced by this one.
+    pub fn trace(&self, tracer: &mut LoxHeapTracer) {
+        self.slots
+            .iter()
+            .chain(self.globals.values())
+            .for_each(|value| tracer.mark_value(value));
+        if let Some(outer) = &self.outer {
+            tracer.mark_environment(outer);
+        }
+    }
+
+    /// Drop all the variables and the enclosing environment.
+    pub fn clear_references(&mut self) {
+        self.slots.clear();
+        self.globals.clear();
+        self.outer = None;
     }
 }
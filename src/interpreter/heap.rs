@@ -0,0 +1,356 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    rc::{Rc, Weak},
+};
+
+use crate::values::{LoxObject, LoxObjectHandle, LoxValue};
+
+use super::environment::{LoxEnvironment, LoxEnvironmentHandle};
+
+/// Collection threshold, in tracked objects and environments, before the first
+/// cycle collection.
+const LOX_GC_INITIAL_THRESHOLD: usize = 16 * 1024;
+/// Growth of the collection threshold relative to the values surviving a collection.
+const LOX_GC_HEAP_GROW_FACTOR: usize = 2;
+
+thread_local! {
+    static LOX_TREE_WALK_HEAP: RefCell<LoxTreeWalkHeap> = RefCell::new(LoxTreeWalkHeap::default());
+}
+
+/// Register a newly allocated object, collecting cycles beforehand if needed.
+///
+/// Environments are only registered once captured by a closure, since the other
+/// ones cannot be part of a cycle.
+pub fn heap_track_object(object: &LoxObjectHandle) {
+    LOX_TREE_WALK_HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        if heap.should_collect() {
+            heap.collect();
+        }
+        heap.objects.push(Rc::downgrade(object));
+        if let LoxObject::Function {
+            arity: _,
+            is_initializer: _,
+            declaration: _,
+            closure,
+            locals: _,
+        } = &*object.borrow()
+        {
+            heap.track_environment(closure);
+        }
+    });
+}
+
+/// Free the reference cycles that are no longer reachable, returning what was freed.
+pub fn heap_collect_cycles() -> LoxHeapStatistics {
+    LOX_TREE_WALK_HEAP.with(|heap| heap.borrow_mut().collect())
+}
+
+/// Objects and closure environments currently alive, including the unreachable
+/// cycles not yet collected.
+pub fn heap_statistics() -> LoxHeapStatistics {
+    LOX_TREE_WALK_HEAP.with(|heap| heap.borrow().statistics())
+}
+
+/// Counts of tree-walk heap values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoxHeapStatistics {
+    objects: usize,
+    environments: usize,
+}
+
+impl LoxHeapStatistics {
+    pub fn get_objects(&self) -> usize {
+        self.objects
+    }
+
+    pub fn get_environments(&self) -> usize {
+        self.environments
+    }
+}
+
+impl fmt::Display for LoxHeapStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} objects, {} environments",
+            self.objects, self.environments
+        )
+    }
+}
+
+/// References to other heap values, found while tracing an object or an environment.
+#[derive(Default)]
+pub struct LoxHeapTracer {
+    references: Vec<usize>,
+}
+
+impl LoxHeapTracer {
+    pub fn mark_value(&mut self, value: &LoxValue) {
+        if let LoxValue::Object(object) = value {
+            self.mark_object(object);
+        }
+    }
+
+    pub fn mark_object(&mut self, object: &LoxObjectHandle) {
+        self.references.push(heap_address(object));
+    }
+
+    pub fn mark_environment(&mut self, environment: &LoxEnvironmentHandle) {
+        self.references.push(heap_address(environment));
+    }
+}
+
+fn heap_address<T>(handle: &Rc<RefCell<T>>) -> usize {
+    Rc::as_ptr(handle) as *const () as usize
+}
+
+/// Object or environment, while a collection is running.
+enum LoxHeapNode {
+    Object(LoxObjectHandle),
+    Environment(LoxEnvironmentHandle),
+}
+
+impl LoxHeapNode {
+    fn address(&self) -> usize {
+        match self {
+            Self::Object(object) => heap_address(object),
+            Self::Environment(environment) => heap_address(environment),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Self::Object(object) => Rc::strong_count(object),
+            Self::Environment(environment) => Rc::strong_count(environment),
+        }
+    }
+
+    /// References of the node, or `None` if it is borrowed elsewhere.
+    fn trace(&self) -> Option<Vec<usize>> {
+        let mut tracer = LoxHeapTracer::default();
+        match self {
+            Self::Object(object) => object.try_borrow().ok()?.trace(&mut tracer),
+            Self::Environment(environment) => environment.try_borrow().ok()?.trace(&mut tracer),
+        }
+        Some(tracer.references)
+    }
+
+    /// Drop the references of the node, breaking the cycles it is part of.
+    fn release(&self) {
+        match self {
+            Self::Object(object) => {
+                if let Ok(mut object) = object.try_borrow_mut() {
+                    object.clear_references();
+                }
+            }
+            Self::Environment(environment) => {
+                if let Ok(mut environment) = environment.try_borrow_mut() {
+                    environment.clear_references();
+                }
+            }
+        }
+    }
+}
+
+/// Registry of the objects allocated by the tree-walk interpreter and of the
+/// environments captured by its closures, reclaiming the reference cycles between them.
+///
+/// Values are reference-counted and can be allocated from anywhere, so the heap only
+/// keeps weak references to them. Acyclic values are freed as soon as they are
+/// dropped, while cycles are found by trial deletion: values referenced more often
+/// than by the other tracked values are held by the interpreter itself, and every
+/// value that none of them can reach is garbage.
+struct LoxTreeWalkHeap {
+    objects: Vec<Weak<RefCell<LoxObject>>>,
+    environments: Vec<Weak<RefCell<LoxEnvironment>>>,
+    next_collection: usize,
+}
+
+impl Default for LoxTreeWalkHeap {
+    fn default() -> Self {
+        Self {
+            objects: vec![],
+            environments: vec![],
+            next_collection: LOX_GC_INITIAL_THRESHOLD,
+        }
+    }
+}
+
+impl LoxTreeWalkHeap {
+    /// Have enough values been allocated since the last collection to warrant a new one?
+    fn should_collect(&self) -> bool {
+        cfg!(feature = "stress-gc")
+            || self.objects.len() + self.environments.len() > self.next_collection
+    }
+
+    /// Register the given environment, along with the enclosing ones not yet tracked.
+    fn track_environment(&mut self, environment: &LoxEnvironmentHandle) {
+        let mut current = Some(environment.clone());
+        while let Some(environment) = current {
+            if environment.borrow().is_tracked() {
+                break;
+            }
+            environment.borrow_mut().set_tracked();
+            self.environments.push(Rc::downgrade(&environment));
+            current = environment.borrow().get_outer().cloned();
+        }
+    }
+
+    fn statistics(&self) -> LoxHeapStatistics {
+        LoxHeapStatistics {
+            objects: self
+                .objects
+                .iter()
+                .filter(|object| object.strong_count() > 0)
+                .count(),
+            environments: self
+                .environments
+                .iter()
+                .filter(|environment| environment.strong_count() > 0)
+                .count(),
+        }
+    }
+
+    fn collect(&mut self) -> LoxHeapStatistics {
+        #[cfg(feature = "log-gc")]
+        println!("-- tree-walk gc begin");
+        let before = self.statistics();
+
+        let nodes: Vec<LoxHeapNode> = self
+            .objects
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(LoxHeapNode::Object)
+            .chain(
+                self.environments
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .map(LoxHeapNode::Environment),
+            )
+            .collect();
+        let indices: HashMap<usize, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node.address(), index))
+            .collect();
+
+        // count the references held by the tracked values themselves
+        let references: Vec<Option<Vec<usize>>> = nodes
+            .iter()
+            .map(|node| {
+                node.trace().map(|addresses| {
+                    addresses
+                        .iter()
+                        .filter_map(|address| indices.get(address).copied())
+                        .collect()
+                })
+            })
+            .collect();
+        let mut internal_counts = vec![0; nodes.len()];
+        for index in references.iter().flatten().flatten() {
+            internal_counts[*index] += 1;
+        }
+
+        // the other references (minus the one taken above) come from the interpreter
+        let mut is_reachable = vec![false; nodes.len()];
+        let mut gray_stack: Vec<usize> = (0..nodes.len())
+            .filter(|index| {
+                references[*index].is_none()
+                    || nodes[*index].strong_count() - 1 > internal_counts[*index]
+            })
+            .collect();
+        while let Some(index) = gray_stack.pop() {
+            if is_reachable[index] {
+                continue;
+            }
+            is_reachable[index] = true;
+            gray_stack.extend(references[index].iter().flatten().copied());
+        }
+
+        for (node, _) in nodes
+            .iter()
+            .zip(&is_reachable)
+            .filter(|(_, is_reachable)| !**is_reachable)
+        {
+            node.release();
+        }
+        // the garbage is freed along with the last strong references to it
+        drop(nodes);
+        self.objects.retain(|object| object.strong_count() > 0);
+        self.environments
+            .retain(|environment| environment.strong_count() > 0);
+
+        let after = self.statistics();
+        self.next_collection = ((after.objects + after.environments) * LOX_GC_HEAP_GROW_FACTOR)
+            .max(LOX_GC_INITIAL_THRESHOLD);
+        let freed = LoxHeapStatistics {
+            objects: before.objects - after.objects,
+            environments: before.environments - after.environments,
+        };
+
+        #[cfg(feature = "log-gc")]
+        {
+            println!("-- tree-walk gc end");
+            println!(
+                "   collected {} (from {} to {}) next at {}",
+                freed, before, after, self.next_collection
+            );
+        }
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::{LoxInterpreter, LoxTreeWalkInterpreter};
+
+    use super::{heap_collect_cycles, heap_statistics, LoxHeapStatistics};
+
+    #[test]
+    fn test_tree_walk_heap_cycle_collection() {
+        let source = r#"
+class Node {}
+fun makeCycles() {
+  var node = Node();
+  node.self = node;
+  fun recursive() { return recursive; }
+  return node;
+}
+var kept = makeCycles();
+for (var i = 0; i < 100; i = i + 1) {
+  makeCycles();
+}
+"#;
+        let mut interpreter = LoxTreeWalkInterpreter::new(None);
+        interpreter.run_code(source).unwrap();
+        let before = heap_statistics();
+        let freed = heap_collect_cycles();
+        assert_eq!(
+            heap_statistics().get_objects(),
+            before.get_objects() - freed.get_objects()
+        );
+        // the clock builtin, the class, the function and the kept node, in the globals
+        assert_eq!(
+            heap_statistics(),
+            LoxHeapStatistics {
+                objects: 4,
+                environments: 1,
+            }
+        );
+
+        // reachable values are left untouched
+        assert_eq!(
+            interpreter.run_code("kept.self == kept;").unwrap(),
+            Some("true".into())
+        );
+        assert_eq!(heap_collect_cycles(), LoxHeapStatistics::default());
+
+        // the cycles through the global variables are broken with the interpreter
+        drop(interpreter);
+        heap_collect_cycles();
+        assert_eq!(heap_statistics(), LoxHeapStatistics::default());
+    }
+}
@@ -2,11 +2,11 @@ use std::collections::HashMap;
 
 use crate::{
     errors::{LoxInterpreterError, Result},
-    expressions::{LoxExpression, LoxOperation, LoxStatement},
+    expressions::{LoxExpression, LoxExpressionId, LoxOperation, LoxStatement},
     lexer::LoxToken,
 };
 
-use super::tree_walk::LoxTreeWalkEvaluator;
+use super::tree_walk::{LoxResolvedLocal, LoxTreeWalkEvaluator};
 
 #[derive(Clone, PartialEq, Eq)]
 enum LoxClassType {
@@ -23,7 +23,15 @@ enum LoxFunctionType {
     ClassInitializer,
 }
 
-type LoxLexicalScope = HashMap<String, bool>;
+/// Variable declared in a block scope.
+struct LoxScopeVariable {
+    /// False while the variable's initializer is being resolved.
+    is_defined: bool,
+    /// Index of the variable in its scope, in order of declaration.
+    slot: usize,
+}
+
+type LoxLexicalScope = HashMap<String, LoxScopeVariable>;
 
 pub struct LoxResolver {
     evaluator: LoxTreeWalkEvaluator,
@@ -51,11 +59,22 @@ impl LoxResolver {
     }
 
     pub fn resolve(&mut self, operation: &LoxOperation) -> Result<()> {
-        match operation {
+        let resolved = match operation {
             LoxOperation::Invalid => Ok(()),
             LoxOperation::Statement(statement) => self.resolve_statement(statement),
             LoxOperation::Expression(expression) => self.resolve_expression(expression),
+        };
+        if resolved.is_err() {
+            // an error can leave us in the middle of a scope: start afresh for the next operations
+            self.reset();
         }
+        resolved
+    }
+
+    fn reset(&mut self) {
+        self.scopes.clear();
+        self.current_class_kind = LoxClassType::None;
+        self.current_function_kind = LoxFunctionType::None;
     }
 
     fn resolve_statements(&mut self, statements: &[LoxStatement]) -> Result<()> {
@@ -118,12 +137,13 @@ impl LoxResolver {
                 // inheritance handling
                 if !super_class.is_noop() {
                     if let LoxExpression::Variable {
+                        id: _,
                         name: super_class_name,
                     } = super_class
                     {
                         if super_class_name.get_lexeme() == name.get_lexeme() {
                             return Err(LoxInterpreterError::ResolverRecursiveInheritance(
-                                name.get_lexeme().clone(),
+                                super_class_name.clone(),
                             ));
                         }
                     } else {
@@ -132,15 +152,11 @@ impl LoxResolver {
                     self.current_class_kind = LoxClassType::SubClass;
                     self.resolve_expression(super_class)?;
                     self.begin_scope();
-                    if let Some(scope) = self.scopes.last_mut() {
-                        scope.insert("super".into(), true);
-                    }
+                    self.declare_keyword("super");
                 }
 
                 self.begin_scope();
-                if let Some(scope) = self.scopes.last_mut() {
-                    scope.insert("this".into(), true);
-                }
+                self.declare_keyword("this");
                 for method in methods {
                     self.resolve_function(
                         method,
@@ -188,36 +204,47 @@ impl LoxResolver {
     fn resolve_expression(&mut self, expression: &LoxExpression) -> Result<()> {
         match expression {
             LoxExpression::NoOp => (),
-            LoxExpression::This { keyword } => {
+            LoxExpression::This { id, keyword } => {
                 if self.current_class_kind == LoxClassType::None {
                     return Err(LoxInterpreterError::ResolverImpossibleThisUsage(
                         keyword.clone(),
                     ));
                 }
-                self.resolve_local_variable(expression, keyword)?;
+                self.resolve_local_variable(*id, "this");
             }
-            LoxExpression::Super { keyword, method: _ } => match &self.current_class_kind {
+            LoxExpression::Super {
+                id,
+                keyword,
+                method: _,
+            } => match &self.current_class_kind {
                 LoxClassType::None => {
-                    return Err(LoxInterpreterError::ResolverSuperUseOutsideOfClass())
+                    return Err(LoxInterpreterError::ResolverSuperUseOutsideOfClass(
+                        keyword.clone(),
+                    ))
                 }
                 LoxClassType::Class => {
-                    return Err(LoxInterpreterError::ResolverSuperUseOutsideOfSubClass())
+                    return Err(LoxInterpreterError::ResolverSuperUseOutsideOfSubClass(
+                        keyword.clone(),
+                    ))
                 }
-                _ => self.resolve_local_variable(expression, keyword)?,
+                _ => self.resolve_local_variable(*id, "super"),
             },
-            LoxExpression::Variable { name } => {
+            LoxExpression::Variable { id, name } => {
                 if let Some(scope) = self.scopes.last() {
-                    if scope.get(name.get_lexeme()) == Some(&false) {
+                    if scope
+                        .get(name.get_lexeme())
+                        .is_some_and(|variable| !variable.is_defined)
+                    {
                         return Err(LoxInterpreterError::ResolverRecursiveLocalAssignment(
                             name.clone(),
                         ));
                     }
-                    self.resolve_local_variable(expression, name)?;
+                    self.resolve_local_variable(*id, name.get_lexeme());
                 }
             }
-            LoxExpression::Assign { name, value } => {
+            LoxExpression::Assign { id, name, value } => {
                 self.resolve_expression(value)?;
-                self.resolve_local_variable(expression, name)?;
+                self.resolve_local_variable(*id, name.get_lexeme());
             }
             LoxExpression::Get { name: _, object } => {
                 self.resolve_expression(object)?;
@@ -257,7 +284,7 @@ impl LoxResolver {
                 self.resolve_expression(left)?;
                 self.resolve_expression(right)?;
             }
-            LoxExpression::Literal { value: _ } => (),
+            LoxExpression::Literal { value: _, span: _ } => (),
             LoxExpression::Group { expression } => self.resolve_expression(expression)?,
         }
         Ok(())
@@ -284,22 +311,21 @@ impl LoxResolver {
             }
             _ => Err(LoxInterpreterError::ResolverUnexpectedOperation(
                 "resolve_function expected a function".into(),
+                function.span(),
             )),
         }
     }
 
-    fn resolve_local_variable(
-        &mut self,
-        expression: &LoxExpression,
-        name: &LoxToken,
-    ) -> Result<()> {
+    /// Resolve the variable to its innermost declaration, assuming it is global
+    /// if not found.
+    fn resolve_local_variable(&mut self, id: LoxExpressionId, name: &str) {
         for (i, scope) in self.scopes.iter().enumerate().rev() {
-            if scope.contains_key(name.get_lexeme()) {
-                self.evaluator
-                    .resolve_variable(expression, self.scopes.len() - 1 - i);
+            if let Some(variable) = scope.get(name) {
+                let local = LoxResolvedLocal::new(self.scopes.len() - 1 - i, variable.slot);
+                self.evaluator.resolve_variable(id, local);
+                return;
             }
         }
-        Ok(())
     }
 
     /// Declares a variable in the innermost scope in order to shadow any outer one.
@@ -310,15 +336,34 @@ impl LoxResolver {
                     name.clone(),
                 ));
             }
-            scope.insert(name.get_lexeme().clone(), false);
+            let variable = LoxScopeVariable {
+                is_defined: false,
+                slot: scope.len(),
+            };
+            scope.insert(name.get_lexeme().clone(), variable);
         }
         Ok(())
     }
 
     /// Marks a variable as defined in the innermost scope.
     fn define(&mut self, name: &LoxToken) {
+        if let Some(variable) = self
+            .scopes
+            .last_mut()
+            .and_then(|scope| scope.get_mut(name.get_lexeme()))
+        {
+            variable.is_defined = true;
+        }
+    }
+
+    /// Declare and define the variable bound to `this` or `super` in the innermost scope.
+    fn declare_keyword(&mut self, keyword: &str) {
         if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(name.get_lexeme().clone(), true);
+            let variable = LoxScopeVariable {
+                is_defined: true,
+                slot: scope.len(),
+            };
+            scope.insert(keyword.into(), variable);
         }
     }
 
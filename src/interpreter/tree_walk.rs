@@ -1,39 +1,94 @@
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hasher},
+    rc::Rc,
 };
 
 use crate::{
     callable::LoxCallable,
     errors::{LoxInterpreterError, Result},
-    expressions::{LoxExpression, LoxLiteral, LoxOperation, LoxStatement},
+    expressions::{LoxExpression, LoxExpressionId, LoxLiteral, LoxOperation, LoxStatement},
     interpreter::environment::environment_handle_assign_at_depth,
     lexer::{LoxToken, LoxTokenType},
-    printer::LoxPrintable,
+    printer::{LoxLinePrinterInstance, LoxPrintable},
+    span::LoxSourceSpan,
     values::{
-        lox_value_handle_instance_get_field, lox_value_handle_instance_set_field, LoxValue,
-        LoxValueHandle,
+        lox_value_instance_get_field, lox_value_instance_set_field, LoxObject, LoxObjectHandle,
+        LoxValue,
     },
 };
 
 use super::{
     builtins::build_lox_clock_builtin,
-    environment::{environment_handle_get_at_depth, LoxEnvironment, LoxEnvironmentHandle},
+    environment::{
+        environment_handle_assign_global, environment_handle_get_at_depth,
+        environment_handle_get_global, LoxEnvironment, LoxEnvironmentHandle,
+    },
 };
 
-pub type LoxTreeWalkEvaluatorLocals = HashMap<u64, usize>;
+/// Outcome of a statement's evaluation, when it does not fail.
+pub enum LoxCompletion {
+    /// The evaluation goes on with the next statement.
+    Normal,
+    /// A `return` statement was reached, with the returned value.
+    Return(LoxValue),
+}
+
+/// Location of a local variable, as resolved before evaluation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoxResolvedLocal {
+    /// Number of scopes between the variable's use and its declaration.
+    depth: usize,
+    /// Index of the variable in the scope it is declared in.
+    slot: usize,
+}
+
+impl LoxResolvedLocal {
+    pub fn new(depth: usize, slot: usize) -> Self {
+        Self { depth, slot }
+    }
+
+    pub fn get_depth(&self) -> usize {
+        self.depth
+    }
 
-pub trait LoxLinePrinter {
-    fn print(&mut self, output: String);
-    fn history(&self) -> Option<&[String]>;
+    pub fn get_slot(&self) -> usize {
+        self.slot
+    }
 }
 
-pub type LoxLinePrinterInstance = Box<dyn LoxLinePrinter>;
+/// Hasher of the expression IDs, which are already unique integers.
+#[derive(Default)]
+pub struct LoxExpressionIdHasher(u64);
+
+impl Hasher for LoxExpressionIdHasher {
+    fn finish(&self) -> u64 {
+        // spread the consecutive IDs over all the bits of the hash
+        self.0.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = self.0 << 8 | *byte as u64;
+        }
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.0 = i as u64;
+    }
+}
+
+/// Resolved local variables, by expression.
+pub type LoxTreeWalkEvaluatorLocals =
+    HashMap<LoxExpressionId, LoxResolvedLocal, BuildHasherDefault<LoxExpressionIdHasher>>;
+
+/// Resolved local variables of a script, shared with the functions it declares.
+pub type LoxTreeWalkEvaluatorLocalsHandle = Rc<LoxTreeWalkEvaluatorLocals>;
 
 pub struct LoxTreeWalkEvaluator {
     globals: LoxEnvironmentHandle,
     printer: LoxLinePrinterInstance,
-    locals: LoxTreeWalkEvaluatorLocals,
+    locals: LoxTreeWalkEvaluatorLocalsHandle,
 }
 
 impl LoxTreeWalkEvaluator {
@@ -41,11 +96,11 @@ impl LoxTreeWalkEvaluator {
         let globals = LoxEnvironment::new(None);
         globals
             .borrow_mut()
-            .define("clock".into(), build_lox_clock_builtin());
+            .define("clock", build_lox_clock_builtin());
         Self {
             globals,
             printer,
-            locals: HashMap::new(),
+            locals: LoxTreeWalkEvaluatorLocalsHandle::default(),
         }
     }
 
@@ -53,69 +108,93 @@ impl LoxTreeWalkEvaluator {
         &self.globals
     }
 
-    pub fn evaluate(&mut self, operation: &LoxOperation) -> Result<LoxValueHandle> {
+    pub fn get_printer(&self) -> &LoxLinePrinterInstance {
+        &self.printer
+    }
+
+    pub fn get_locals(&self) -> &LoxTreeWalkEvaluatorLocals {
+        &self.locals
+    }
+
+    pub fn evaluate(&mut self, operation: &LoxOperation) -> Result<LoxValue> {
         match operation {
-            LoxOperation::Invalid => Ok(LoxValue::new(LoxValue::Nil)),
+            LoxOperation::Invalid => Ok(LoxValue::Nil),
             LoxOperation::Expression(expression) => Self::evaluate_expression(
                 expression,
                 &mut self.globals,
                 &self.locals,
                 &mut self.printer,
             ),
-            LoxOperation::Statement(statement) => Self::evaluate_statement(
-                statement,
-                &mut self.globals,
-                &self.locals,
-                &mut self.printer,
-            ),
+            // the value of a trailing expression is reported by the interpreter
+            LoxOperation::Statement(LoxStatement::Expression { expression }) => {
+                Self::evaluate_expression(
+                    expression,
+                    &mut self.globals,
+                    &self.locals,
+                    &mut self.printer,
+                )
+            }
+            LoxOperation::Statement(statement) => {
+                Self::evaluate_statement(
+                    statement,
+                    &mut self.globals,
+                    &self.locals,
+                    &mut self.printer,
+                )?;
+                Ok(LoxValue::Nil)
+            }
         }
     }
 
-    pub fn resolve_variable(&mut self, expression: &LoxExpression, depth: usize) {
-        let key = Self::compute_locals_key_from_expression(expression);
-        self.locals.insert(key, depth);
+    /// Start resolving a new script, the functions declared by the previous ones
+    /// keeping their own resolved local variables.
+    pub fn reset_locals(&mut self) {
+        self.locals = LoxTreeWalkEvaluatorLocalsHandle::default();
+    }
+
+    pub fn resolve_variable(&mut self, id: LoxExpressionId, local: LoxResolvedLocal) {
+        Rc::make_mut(&mut self.locals).insert(id, local);
     }
 
     pub fn lookup_variable(
-        expression: &LoxExpression,
+        id: LoxExpressionId,
         name: &LoxToken,
         env: &LoxEnvironmentHandle,
-        locals: &LoxTreeWalkEvaluatorLocals,
-    ) -> Result<LoxValueHandle> {
-        if let Some(distance) = locals.get(&Self::compute_locals_key_from_expression(expression)) {
-            environment_handle_get_at_depth(env, name.get_lexeme().as_str(), *distance)
+        locals: &LoxTreeWalkEvaluatorLocalsHandle,
+    ) -> Result<LoxValue> {
+        let value = if let Some(local) = locals.get(&id) {
+            environment_handle_get_at_depth(env, local.get_depth(), local.get_slot())
         } else {
-            env.borrow().get(name.get_lexeme().as_str())
-        }
+            environment_handle_get_global(env, name.get_lexeme())
+        };
+        value.ok_or_else(|| Self::undefined_variable(name.get_lexeme(), name.get_span()))
     }
 
-    fn compute_locals_key_from_expression(expression: &LoxExpression) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        expression.hash(&mut hasher);
-        hasher.finish()
+    fn undefined_variable(name: &str, span: LoxSourceSpan) -> LoxInterpreterError {
+        LoxInterpreterError::InterpreterUndefinedVariable(name.to_string(), span)
     }
 
     fn evaluate_statement(
         statement: &LoxStatement,
         env: &mut LoxEnvironmentHandle,
-        locals: &LoxTreeWalkEvaluatorLocals,
+        locals: &LoxTreeWalkEvaluatorLocalsHandle,
         output: &mut LoxLinePrinterInstance,
-    ) -> Result<LoxValueHandle> {
+    ) -> Result<LoxCompletion> {
         match statement {
-            LoxStatement::NoOp => Ok(LoxValue::new(LoxValue::Nil)),
+            LoxStatement::NoOp => Ok(LoxCompletion::Normal),
             LoxStatement::Expression { expression } => {
                 Self::evaluate_expression(expression, env, locals, output)?;
-                Ok(LoxValue::new(LoxValue::Nil))
+                Ok(LoxCompletion::Normal)
             }
             LoxStatement::Print { expression } => {
                 let value = Self::evaluate_expression(expression, env, locals, output)?;
-                output.print(value.borrow().representation());
-                Ok(LoxValue::new(LoxValue::Nil))
+                output.print(value.representation());
+                Ok(LoxCompletion::Normal)
             }
             LoxStatement::Variable { name, initializer } => {
                 let value = Self::evaluate_expression(initializer, env, locals, output)?;
-                env.borrow_mut().define(name.get_lexeme().clone(), value);
-                Ok(LoxValue::new(LoxValue::Nil))
+                env.borrow_mut().define(name.get_lexeme(), value);
+                Ok(LoxCompletion::Normal)
             }
             LoxStatement::Block { statements } => {
                 let mut block_env = LoxEnvironment::new(Some(env.clone()));
@@ -127,40 +206,44 @@ impl LoxTreeWalkEvaluator {
                 else_branch,
             } => {
                 let condition_value = Self::evaluate_expression(condition, env, locals, output)?;
-                if condition_value.borrow().is_truthy() {
-                    Self::evaluate_statement(then_branch, env, locals, output)?;
+                if condition_value.is_truthy() {
+                    Self::evaluate_statement(then_branch, env, locals, output)
                 } else if !else_branch.is_noop() {
-                    Self::evaluate_statement(else_branch, env, locals, output)?;
+                    Self::evaluate_statement(else_branch, env, locals, output)
+                } else {
+                    Ok(LoxCompletion::Normal)
                 }
-                Ok(LoxValue::new(LoxValue::Nil))
             }
             LoxStatement::While { condition, body } => {
-                while Self::evaluate_expression(condition, env, locals, output)?.borrow().is_truthy() {
-                    let _ = Self::evaluate_statement(body, env, locals, output)?;
+                while Self::evaluate_expression(condition, env, locals, output)?.is_truthy() {
+                    if let completion @ LoxCompletion::Return(_) = Self::evaluate_statement(body, env, locals, output)? {
+                        return Ok(completion);
+                    }
                 }
-                Ok(LoxValue::new(LoxValue::Nil))
+                Ok(LoxCompletion::Normal)
             }
             LoxStatement::Function {
                 name,
                 parameters,
                 body: _,
             } => {
-                let function = LoxValue::new(LoxValue::Function {
+                let function = LoxValue::new_object(LoxObject::Function {
                     is_initializer: false,
                     arity: parameters.len(),
                     declaration: Box::new(statement.clone()),
                     closure: env.clone(),
+                    locals: locals.clone(),
                 });
-                env.borrow_mut().define(name.get_lexeme().clone(), function);
-                Ok(LoxValue::new(LoxValue::Nil))
+                env.borrow_mut().define(name.get_lexeme(), function);
+                Ok(LoxCompletion::Normal)
             }
             LoxStatement::Return { keyword: _, value } => {
                 let returned_value = if value.is_noop() {
-                    LoxValue::new(LoxValue::Nil)
+                    LoxValue::Nil
                 } else {
                     Self::evaluate_expression(value, env, locals, output)?
                 };
-                Err(LoxInterpreterError::InterpreterReturn(returned_value))
+                Ok(LoxCompletion::Return(returned_value))
             }
             LoxStatement::Class {
                 name,
@@ -169,48 +252,47 @@ impl LoxTreeWalkEvaluator {
             } => {
                 // super-class handling
                 let super_class_value = if super_class.is_noop() {
-                    LoxValue::new(LoxValue::Nil)
+                    None
                 } else {
-                    let super_class_value = Self::evaluate_expression(super_class, env, locals, output)?;
-                    if super_class_value.borrow().is_class() {
-                        super_class_value
-                    } else {
-                        return Err(LoxInterpreterError::InterpreterSuperClassNotAClass(super_class.representation()));
+                    match Self::evaluate_expression(super_class, env, locals, output)? {
+                        LoxValue::Object(super_class_value) if super_class_value.borrow().is_class() => Some(super_class_value),
+                        _ => {
+                            return Err(LoxInterpreterError::InterpreterSuperClassNotAClass(super_class.representation(), super_class.span()));
+                        }
                     }
                 };
-                // allows references to the class inside its own methods
-                env.borrow_mut()
-                    .define(name.get_lexeme().clone(), LoxValue::new(LoxValue::Nil));
                 // "super" handling
-                let class_env = if super_class.is_noop() {
-                    env.clone()
-                } else {
-                    let class_env = env.clone();
-                    class_env.borrow_mut().define("super".into(), super_class_value.clone());
-                    class_env
+                let class_env = match &super_class_value {
+                    None => env.clone(),
+                    Some(super_class_value) => {
+                        let class_env = LoxEnvironment::new(Some(env.clone()));
+                        class_env.borrow_mut().define("super", LoxValue::Object(super_class_value.clone()));
+                        class_env
+                    }
                 };
                 // methods
-                let mut evaluated_methods: HashMap<String, LoxValueHandle> = HashMap::new();
+                let mut evaluated_methods: HashMap<String, LoxObjectHandle> = HashMap::new();
                 for method in methods {
                     if let LoxStatement::Function { name: method_name, parameters, body: _ } = method {
                             let borrowed_method: &LoxStatement = method;
                             let declaration = borrowed_method.clone();
-                            let function = LoxValue::new(LoxValue::Function {
+                            let function = LoxObject::new_handle(LoxObject::Function {
                                 arity: parameters.len(),
                                 is_initializer: method_name.get_lexeme() == "init",
                                 declaration: Box::new(declaration),
                                 closure: class_env.clone(),
+                                locals: locals.clone(),
                             });
                             evaluated_methods.insert(method_name.get_lexeme().clone(), function);
                         } else {
                             panic!("interpreter: expected a function statement in class methods");
                         }
                 }
-                // class value
-                let class = LoxValue::new(LoxValue::Class { name: name.get_lexeme().clone(), super_class: super_class_value.clone(), methods: evaluated_methods });
+                // class value, defined last since its methods refer to it only once called
+                let class = LoxValue::new_object(LoxObject::Class { name: name.get_lexeme().clone(), super_class: super_class_value, methods: evaluated_methods });
                 env.borrow_mut()
-                    .define(name.get_lexeme().clone(), class);
-                Ok(LoxValue::new(LoxValue::Nil))
+                    .define(name.get_lexeme(), class);
+                Ok(LoxCompletion::Normal)
             }
             // _ => panic!(
             //     "treewalk.evaluate_statement: not implemented for: {}\n{}",
@@ -223,24 +305,26 @@ impl LoxTreeWalkEvaluator {
     pub fn execute_block_statement(
         statements: &[LoxStatement],
         env: &mut LoxEnvironmentHandle,
-        locals: &LoxTreeWalkEvaluatorLocals,
+        locals: &LoxTreeWalkEvaluatorLocalsHandle,
         output: &mut LoxLinePrinterInstance,
-    ) -> Result<LoxValueHandle> {
+    ) -> Result<LoxCompletion> {
         for statement in statements {
-            Self::evaluate_statement(statement, env, locals, output)?;
+            if let completion @ LoxCompletion::Return(_) = Self::evaluate_statement(statement, env, locals, output)? {
+                return Ok(completion);
+            }
         }
-        Ok(LoxValue::new(LoxValue::Nil))
+        Ok(LoxCompletion::Normal)
     }
 
     fn evaluate_expression(
         expression: &LoxExpression,
         env: &mut LoxEnvironmentHandle,
-        locals: &LoxTreeWalkEvaluatorLocals,
+        locals: &LoxTreeWalkEvaluatorLocalsHandle,
         output: &mut LoxLinePrinterInstance,
-    ) -> Result<LoxValueHandle> {
+    ) -> Result<LoxValue> {
         match expression {
-            LoxExpression::NoOp => Ok(LoxValue::new(LoxValue::Nil)),
-            LoxExpression::Literal { value } => Ok(Self::evaluate_literal(value)),
+            LoxExpression::NoOp => Ok(LoxValue::Nil),
+            LoxExpression::Literal { value, span: _ } => Ok(Self::evaluate_literal(value)),
             LoxExpression::Group { expression: expr } => {
                 Self::evaluate_expression(expr, env, locals, output)
             }
@@ -248,16 +332,16 @@ impl LoxTreeWalkEvaluator {
                 let right_value = Self::evaluate_expression(right, env, locals, output)?;
                 match operator.get_kind() {
                     // number inversion
-                    LoxTokenType::Minus => Ok(LoxValue::new(LoxValue::Number(
-                        -Self::extract_number(&right_value)?,
-                    ))),
+                    LoxTokenType::Minus => Ok(LoxValue::Number(
+                        -Self::extract_number(&right_value, operator)?,
+                    )),
                     // logical not
-                    LoxTokenType::Bang => Ok(LoxValue::new(LoxValue::Boolean(
-                        !right_value.borrow().is_truthy(),
-                    ))),
+                    LoxTokenType::Bang => Ok(LoxValue::Boolean(
+                        !right_value.is_truthy(),
+                    )),
                     // unexpected
                     _ => Err(LoxInterpreterError::InterpreterUnexpectedOperation(
-                        operator.get_lexeme().clone(),
+                        operator.clone(),
                     )),
                 }
             }
@@ -272,56 +356,56 @@ impl LoxTreeWalkEvaluator {
                 );
                 match operator.get_kind() {
                     // subtraction
-                    LoxTokenType::Minus => Ok(LoxValue::new(LoxValue::Number(
-                        Self::extract_number(&left_value)? - Self::extract_number(&right_value)?,
-                    ))),
+                    LoxTokenType::Minus => Ok(LoxValue::Number(
+                        Self::extract_numbers(&left_value, &right_value, operator).map(|(left, right)| left - right)?,
+                    )),
                     // division
-                    LoxTokenType::Slash => Ok(LoxValue::new(LoxValue::Number(
-                        Self::extract_number(&left_value)? / Self::extract_number(&right_value)?,
-                    ))),
+                    LoxTokenType::Slash => Ok(LoxValue::Number(
+                        Self::extract_numbers(&left_value, &right_value, operator).map(|(left, right)| left / right)?,
+                    )),
                     // multiplication
-                    LoxTokenType::Star => Ok(LoxValue::new(LoxValue::Number(
-                        Self::extract_number(&left_value)? * Self::extract_number(&right_value)?,
-                    ))),
+                    LoxTokenType::Star => Ok(LoxValue::Number(
+                        Self::extract_numbers(&left_value, &right_value, operator).map(|(left, right)| left * right)?,
+                    )),
                     // addition and string concatenation
-                    LoxTokenType::Plus => match (&*left_value.borrow(), &*right_value.borrow()) {
+                    LoxTokenType::Plus => match (&left_value, &right_value) {
                         (LoxValue::Number(left), LoxValue::Number(right)) => {
-                            Ok(LoxValue::new(LoxValue::Number(left + right)))
+                            Ok(LoxValue::Number(left + right))
                         }
-                        (LoxValue::String(left), LoxValue::String(right)) => Ok(LoxValue::new(
-                            LoxValue::String(format!("{}{}", left, right)),
-                        )),
-                        _ => Err(LoxInterpreterError::InterpreterUnexpectedOperation(
-                            operator.get_lexeme().clone(),
+                        (LoxValue::String(left), LoxValue::String(right)) => {
+                            Ok(LoxValue::String(format!("{}{}", left, right).into()))
+                        }
+                        _ => Err(LoxInterpreterError::InterpreterInvalidAddition(
+                            operator.get_span(),
                         )),
                     },
                     // greater than
-                    LoxTokenType::Greater => Ok(LoxValue::new(LoxValue::Boolean(
-                        Self::extract_number(&left_value)? > Self::extract_number(&right_value)?,
-                    ))),
+                    LoxTokenType::Greater => Ok(LoxValue::Boolean(
+                        Self::extract_numbers(&left_value, &right_value, operator).map(|(left, right)| left > right)?,
+                    )),
                     // greater or equal
-                    LoxTokenType::GreaterEqual => Ok(LoxValue::new(LoxValue::Boolean(
-                        Self::extract_number(&left_value)? >= Self::extract_number(&right_value)?,
-                    ))),
+                    LoxTokenType::GreaterEqual => Ok(LoxValue::Boolean(
+                        Self::extract_numbers(&left_value, &right_value, operator).map(|(left, right)| left >= right)?,
+                    )),
                     // less than
-                    LoxTokenType::Less => Ok(LoxValue::new(LoxValue::Boolean(
-                        Self::extract_number(&left_value)? < Self::extract_number(&right_value)?,
-                    ))),
+                    LoxTokenType::Less => Ok(LoxValue::Boolean(
+                        Self::extract_numbers(&left_value, &right_value, operator).map(|(left, right)| left < right)?,
+                    )),
                     // less or equal
-                    LoxTokenType::LessEqual => Ok(LoxValue::new(LoxValue::Boolean(
-                        Self::extract_number(&left_value)? <= Self::extract_number(&right_value)?,
-                    ))),
+                    LoxTokenType::LessEqual => Ok(LoxValue::Boolean(
+                        Self::extract_numbers(&left_value, &right_value, operator).map(|(left, right)| left <= right)?,
+                    )),
                     // equality
-                    LoxTokenType::EqualEqual => Ok(LoxValue::new(LoxValue::Boolean(
-                        left_value.borrow().equals(&right_value.borrow()),
-                    ))),
+                    LoxTokenType::EqualEqual => Ok(LoxValue::Boolean(
+                        left_value.equals(&right_value),
+                    )),
                     // non-equality
-                    LoxTokenType::BangEqual => Ok(LoxValue::new(LoxValue::Boolean(
-                        !left_value.borrow().equals(&right_value.borrow()),
-                    ))),
+                    LoxTokenType::BangEqual => Ok(LoxValue::Boolean(
+                        !left_value.equals(&right_value),
+                    )),
                     // unexpected
                     _ => Err(LoxInterpreterError::InterpreterUnexpectedOperation(
-                        operator.get_lexeme().clone(),
+                        operator.clone(),
                     )),
                 }
             }
@@ -333,57 +417,56 @@ impl LoxTreeWalkEvaluator {
                 let left_value = Self::evaluate_expression(left, env, locals, output)?;
                 match operator.get_kind() {
                     LoxTokenType::Or => {
-                        if left_value.borrow().is_truthy() {
+                        if left_value.is_truthy() {
                             Ok(left_value)
                         } else {
                             Self::evaluate_expression(right, env, locals, output)
                         }
                     }
                     LoxTokenType::And => {
-                        if !left_value.borrow().is_truthy() {
+                        if !left_value.is_truthy() {
                             Ok(left_value)
                         } else {
                             Self::evaluate_expression(right, env, locals, output)
                         }
                     }
                     _ => Err(LoxInterpreterError::InterpreterUnexpectedOperation(
-                        operator.get_lexeme().to_string(),
+                        operator.clone(),
                     )),
                 }
             }
-            LoxExpression::Variable { name } => {
-                let value = env.borrow().get(name.get_lexeme().as_str())?;
-                Ok(value)
+            LoxExpression::Variable { id, name } => {
+                Self::lookup_variable(*id, name, env, locals)
             }
-            LoxExpression::Assign { name, value } => {
+            LoxExpression::Assign { id, name, value } => {
                 let evaluated_value = Self::evaluate_expression(value, env, locals, output)?;
-                if let Some(distance) =
-                    locals.get(&Self::compute_locals_key_from_expression(expression))
-                {
+                let assigned = if let Some(local) = locals.get(id) {
                     environment_handle_assign_at_depth(
                         env,
-                        name.get_lexeme(),
-                        *distance,
+                        local.get_depth(),
+                        local.get_slot(),
                         evaluated_value.clone(),
-                    );
+                    )
                 } else {
-                    env.borrow_mut()
-                        .assign(name.get_lexeme(), evaluated_value.clone())?;
+                    environment_handle_assign_global(env, name.get_lexeme(), evaluated_value.clone())
+                };
+                if !assigned {
+                    return Err(Self::undefined_variable(name.get_lexeme(), name.get_span()));
                 }
                 Ok(evaluated_value)
             }
             LoxExpression::Get { name, object } => {
                 let object_value = Self::evaluate_expression(object, env, locals, output)?;
-                lox_value_handle_instance_get_field(&object_value, name)
+                lox_value_instance_get_field(&object_value, name)
             }
             LoxExpression::Set {
                 name,
                 object,
                 value,
             } => {
-                let mut object_value = Self::evaluate_expression(object, env, locals, output)?;
+                let object_value = Self::evaluate_expression(object, env, locals, output)?;
                 let evaluated_value = Self::evaluate_expression(value, env, locals, output)?;
-                lox_value_handle_instance_set_field(&mut object_value, name, evaluated_value)
+                lox_value_instance_set_field(&object_value, name, evaluated_value)
             }
             LoxExpression::Call {
                 callee,
@@ -396,38 +479,57 @@ impl LoxTreeWalkEvaluator {
                     arguments_values
                         .push(Self::evaluate_expression(argument, env, locals, output)?);
                 }
-                callee_value.call(env, locals, &arguments_values, parenthesis, output)
+                callee_value.call(env, &arguments_values, parenthesis, output)
             }
-            LoxExpression::This { keyword } => {
-                Self::lookup_variable(expression, keyword, env, locals)
+            LoxExpression::This { id, keyword } => {
+                Self::lookup_variable(*id, keyword, env, locals)
             }
-            LoxExpression::Super { keyword: _, method } => {
-                let distance = locals.get(&Self::compute_locals_key_from_expression(expression)).expect("interpreter evaluating LoxExpression::Super expects a defined superclass method.");
-                let super_class = environment_handle_get_at_depth(env, "super", *distance)?;
-                let super_class_method = super_class.borrow().class_find_method(method.get_lexeme()).expect("interpreter evaluating LoxExpression::Super expects a defined superclass method.");
-                let this_instance = environment_handle_get_at_depth(env, "this", distance - 1)?;
-                Ok(super_class_method
-                    .clone() // TODO: can we avoid this?
+            LoxExpression::Super { id, keyword, method } => {
+                let local = locals.get(id).expect("interpreter evaluating LoxExpression::Super expects a resolved 'super'.");
+                let super_class = environment_handle_get_at_depth(env, local.get_depth(), local.get_slot())
+                    .ok_or_else(|| Self::undefined_variable("super", keyword.get_span()))?;
+                let super_class_method = super_class.as_object().and_then(|super_class| super_class.borrow().class_find_method(method.get_lexeme()))
+                    .ok_or_else(|| LoxInterpreterError::InterpreterUndefinedClassProperty(method.clone()))?;
+                // "this" is the only variable of the scope right inside the "super" one
+                let this_instance = environment_handle_get_at_depth(env, local.get_depth() - 1, 0)
+                    .ok_or_else(|| Self::undefined_variable("this", keyword.get_span()))?;
+                let bound_method = super_class_method
                     .borrow()
                     .class_method_bind_this(&this_instance)
-                    .expect("superclass method value is a function"))
+                    .expect("superclass method value is a function");
+                Ok(bound_method)
             }
         }
     }
 
-    fn evaluate_literal(literal: &LoxLiteral) -> LoxValueHandle {
+    fn evaluate_literal(literal: &LoxLiteral) -> LoxValue {
         match literal {
-            LoxLiteral::Number(number) => LoxValue::new(LoxValue::Number(*number)),
-            LoxLiteral::String(string) => LoxValue::new(LoxValue::String(string.clone())),
-            LoxLiteral::True => LoxValue::new(LoxValue::Boolean(true)),
-            LoxLiteral::False => LoxValue::new(LoxValue::Boolean(false)),
-            LoxLiteral::Nil => LoxValue::new(LoxValue::Nil),
+            LoxLiteral::Number(number) => LoxValue::Number(*number),
+            LoxLiteral::String(string) => LoxValue::String(string.as_str().into()),
+            LoxLiteral::True => LoxValue::Boolean(true),
+            LoxLiteral::False => LoxValue::Boolean(false),
+            LoxLiteral::Nil => LoxValue::Nil,
         }
     }
 
-    fn extract_number(value: &LoxValueHandle) -> Result<f64> {
-        value.borrow().as_number().ok_or_else(|| {
-            LoxInterpreterError::InterpreterNotANumber(value.borrow().representation())
-        })
+    fn extract_number(value: &LoxValue, operator: &LoxToken) -> Result<f64> {
+        value
+            .as_number()
+            .ok_or_else(|| LoxInterpreterError::InterpreterNotANumber(operator.get_span()))
+    }
+
+    fn extract_numbers(left: &LoxValue, right: &LoxValue, operator: &LoxToken) -> Result<(f64, f64)> {
+        match (left, right) {
+            (LoxValue::Number(left), LoxValue::Number(right)) => Ok((*left, *right)),
+            _ => Err(LoxInterpreterError::InterpreterNotNumbers(operator.get_span())),
+        }
+    }
+}
+
+impl Drop for LoxTreeWalkEvaluator {
+    /// Break the cycles between the global environment and its functions and classes,
+    /// which would otherwise only be freed by the next cycle collection.
+    fn drop(&mut self) {
+        self.globals.borrow_mut().clear_references();
     }
 }
@@ -0,0 +1,291 @@
+use std::fmt;
+
+use crate::{
+    expressions::{LoxExpression, LoxLiteral, LoxOperation, LoxStatement},
+    lexer::LoxToken,
+};
+
+/// Minimal JSON document, used to export the syntax tree to external tools.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoxJsonValue {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<LoxJsonValue>),
+    /// Object, with its members in insertion order.
+    Object(Vec<(&'static str, LoxJsonValue)>),
+}
+
+impl fmt::Display for LoxJsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Boolean(boolean) => write!(f, "{}", boolean),
+            Self::Number(number) => write!(f, "{}", number),
+            Self::String(string) => write_json_string(f, string),
+            Self::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Self::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_json_string(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for character in string.chars() {
+        match character {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            character if character.is_control() => write!(f, "\\u{:04x}", character as u32)?,
+            character => write!(f, "{}", character)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Structured representation of a syntax tree node.
+pub trait LoxJsonPrintable {
+    fn json(&self) -> LoxJsonValue;
+}
+
+/// Build a syntax tree node of the given type.
+fn node(node_type: &str, members: Vec<(&'static str, LoxJsonValue)>) -> LoxJsonValue {
+    let mut object = vec![("type", LoxJsonValue::String(node_type.to_string()))];
+    object.extend(members);
+    LoxJsonValue::Object(object)
+}
+
+impl<T: LoxJsonPrintable> LoxJsonPrintable for [T] {
+    fn json(&self) -> LoxJsonValue {
+        LoxJsonValue::Array(self.iter().map(LoxJsonPrintable::json).collect())
+    }
+}
+
+impl LoxJsonPrintable for LoxToken {
+    fn json(&self) -> LoxJsonValue {
+        LoxJsonValue::Object(vec![
+            (
+                "kind",
+                LoxJsonValue::String(self.get_kind().get_name().to_string()),
+            ),
+            ("lexeme", LoxJsonValue::String(self.get_lexeme().clone())),
+            ("line", LoxJsonValue::Number(self.get_line_number() as f64)),
+        ])
+    }
+}
+
+impl LoxJsonPrintable for LoxLiteral {
+    fn json(&self) -> LoxJsonValue {
+        match self {
+            Self::Number(number) => LoxJsonValue::Number(*number),
+            Self::String(string) => LoxJsonValue::String(string.clone()),
+            Self::True => LoxJsonValue::Boolean(true),
+            Self::False => LoxJsonValue::Boolean(false),
+            Self::Nil => LoxJsonValue::Null,
+        }
+    }
+}
+
+impl LoxJsonPrintable for LoxExpression {
+    fn json(&self) -> LoxJsonValue {
+        match self {
+            Self::NoOp => LoxJsonValue::Null,
+            Self::Assign { id: _, name, value } => node(
+                "assign",
+                vec![("name", name.json()), ("value", value.json())],
+            ),
+            Self::Binary {
+                left,
+                operator,
+                right,
+            } => node(
+                "binary",
+                vec![
+                    ("left", left.json()),
+                    ("operator", operator.json()),
+                    ("right", right.json()),
+                ],
+            ),
+            Self::Call {
+                callee,
+                parenthesis,
+                arguments,
+            } => node(
+                "call",
+                vec![
+                    ("callee", callee.json()),
+                    ("parenthesis", parenthesis.json()),
+                    ("arguments", arguments.json()),
+                ],
+            ),
+            Self::Get { object, name } => node(
+                "get",
+                vec![("object", object.json()), ("name", name.json())],
+            ),
+            Self::Group { expression } => node("group", vec![("expression", expression.json())]),
+            Self::Literal { value, span: _ } => node("literal", vec![("value", value.json())]),
+            Self::Logical {
+                left,
+                operator,
+                right,
+            } => node(
+                "logical",
+                vec![
+                    ("left", left.json()),
+                    ("operator", operator.json()),
+                    ("right", right.json()),
+                ],
+            ),
+            Self::Set {
+                object,
+                name,
+                value,
+            } => node(
+                "set",
+                vec![
+                    ("object", object.json()),
+                    ("name", name.json()),
+                    ("value", value.json()),
+                ],
+            ),
+            Self::Super {
+                id: _,
+                keyword,
+                method,
+            } => node(
+                "super",
+                vec![("keyword", keyword.json()), ("method", method.json())],
+            ),
+            Self::This { id: _, keyword } => node("this", vec![("keyword", keyword.json())]),
+            Self::Unary { operator, right } => node(
+                "unary",
+                vec![("operator", operator.json()), ("right", right.json())],
+            ),
+            Self::Variable { id: _, name } => node("variable", vec![("name", name.json())]),
+        }
+    }
+}
+
+impl LoxJsonPrintable for LoxStatement {
+    fn json(&self) -> LoxJsonValue {
+        let members = match self {
+            Self::NoOp => return LoxJsonValue::Null,
+            Self::Block { statements } => vec![("statements", statements.json())],
+            Self::Class {
+                name,
+                super_class,
+                methods,
+            } => vec![
+                ("name", name.json()),
+                ("superclass", super_class.json()),
+                ("methods", methods.json()),
+            ],
+            Self::Expression { expression } => vec![("expression", expression.json())],
+            Self::Function {
+                name,
+                parameters,
+                body,
+            } => vec![
+                ("name", name.json()),
+                ("parameters", parameters.json()),
+                ("body", body.json()),
+            ],
+            Self::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => vec![
+                ("condition", condition.json()),
+                ("then", then_branch.json()),
+                ("else", else_branch.json()),
+            ],
+            Self::Print { expression } => vec![("expression", expression.json())],
+            Self::Return { keyword, value } => {
+                vec![("keyword", keyword.json()), ("value", value.json())]
+            }
+            Self::Variable { name, initializer } => {
+                vec![("name", name.json()), ("initializer", initializer.json())]
+            }
+            Self::While { condition, body } => {
+                vec![("condition", condition.json()), ("body", body.json())]
+            }
+        };
+        node(self.get_type_representation(), members)
+    }
+}
+
+impl LoxJsonPrintable for LoxOperation {
+    fn json(&self) -> LoxJsonValue {
+        match self {
+            Self::Invalid => LoxJsonValue::Null,
+            Self::Statement(statement) => statement.json(),
+            Self::Expression(expression) => expression.json(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::LoxTreeWalkInterpreter;
+
+    use super::{LoxJsonPrintable, LoxJsonValue};
+
+    #[test]
+    fn test_json_values() {
+        assert_eq!(
+            LoxJsonValue::Object(vec![
+                (
+                    "array",
+                    LoxJsonValue::Array(vec![
+                        LoxJsonValue::Null,
+                        LoxJsonValue::Boolean(true),
+                        LoxJsonValue::Number(1.5),
+                    ])
+                ),
+                ("string", LoxJsonValue::String("\"quoted\"\n\\\u{1}".into())),
+            ])
+            .to_string(),
+            r#"{"array":[null,true,1.5],"string":"\"quoted\"\n\\\u0001"}"#
+        );
+    }
+
+    #[test]
+    fn test_json_syntax_tree() {
+        let interpreter = LoxTreeWalkInterpreter::new(None);
+        let operations = interpreter
+            .parse("var a = 1;\nprint -a;".to_string())
+            .unwrap();
+        assert_eq!(
+            operations.json().to_string(),
+            concat!(
+                r#"[{"type":"variable","name":{"kind":"IDENTIFIER","lexeme":"a","line":1},"#,
+                r#""initializer":{"type":"literal","value":1}},"#,
+                r#"{"type":"print","expression":{"type":"unary","#,
+                r#""operator":{"kind":"MINUS","lexeme":"-","line":2},"#,
+                r#""right":{"type":"variable","name":{"kind":"IDENTIFIER","lexeme":"a","line":2}}}}]"#,
+            )
+        );
+    }
+}
@@ -1,11 +1,9 @@
-use std::{
-    collections::HashMap,
-    hash::{self, Hash},
-};
+use std::collections::HashMap;
 
 use crate::{
     errors::{LoxInterpreterError, Result},
     expressions::LoxLiteral,
+    span::LoxSourceSpan,
 };
 
 #[derive(Clone, Debug, PartialEq)]
@@ -57,6 +55,51 @@ pub enum LoxTokenType {
 }
 
 impl LoxTokenType {
+    /// Name of the token kind, as used by the reference implementation.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::LeftParenthesis => "LEFT_PAREN",
+            Self::RightParenthesis => "RIGHT_PAREN",
+            Self::LeftBrace => "LEFT_BRACE",
+            Self::RightBrace => "RIGHT_BRACE",
+            Self::Comma => "COMMA",
+            Self::Dot => "DOT",
+            Self::Minus => "MINUS",
+            Self::Plus => "PLUS",
+            Self::Semicolon => "SEMICOLON",
+            Self::Slash => "SLASH",
+            Self::Star => "STAR",
+            Self::Bang => "BANG",
+            Self::BangEqual => "BANG_EQUAL",
+            Self::Equal => "EQUAL",
+            Self::EqualEqual => "EQUAL_EQUAL",
+            Self::Greater => "GREATER",
+            Self::GreaterEqual => "GREATER_EQUAL",
+            Self::Less => "LESS",
+            Self::LessEqual => "LESS_EQUAL",
+            Self::Identifier(_) => "IDENTIFIER",
+            Self::String(_) => "STRING",
+            Self::Number(_) => "NUMBER",
+            Self::And => "AND",
+            Self::Class => "CLASS",
+            Self::Else => "ELSE",
+            Self::False => "FALSE",
+            Self::Fun => "FUN",
+            Self::For => "FOR",
+            Self::If => "IF",
+            Self::Nil => "NIL",
+            Self::Or => "OR",
+            Self::Print => "PRINT",
+            Self::Return => "RETURN",
+            Self::Super => "SUPER",
+            Self::This => "THIS",
+            Self::True => "TRUE",
+            Self::Var => "VAR",
+            Self::While => "WHILE",
+            Self::EndOfFile => "EOF",
+        }
+    }
+
     pub fn is_identifier(&self) -> bool {
         matches!(self, LoxTokenType::Identifier(_))
     }
@@ -74,16 +117,14 @@ impl LoxTokenType {
 pub struct LoxToken {
     kind: LoxTokenType,
     lexeme: String,
-    line_number: usize,
+    span: LoxSourceSpan,
 }
 
-impl Hash for LoxToken {
-    fn hash<H: hash::Hasher>(&self, state: &mut H) {
-        format!("{}-{}", self.lexeme, self.line_number).hash(state);
+impl LoxToken {
+    pub fn new(kind: LoxTokenType, lexeme: String, span: LoxSourceSpan) -> Self {
+        Self { kind, lexeme, span }
     }
-}
 
-impl LoxToken {
     pub fn get_kind(&self) -> &LoxTokenType {
         &self.kind
     }
@@ -92,6 +133,14 @@ impl LoxToken {
         &self.lexeme
     }
 
+    pub fn get_line_number(&self) -> usize {
+        self.span.get_line()
+    }
+
+    pub fn get_span(&self) -> LoxSourceSpan {
+        self.span
+    }
+
     pub fn build_literal(&self) -> Option<LoxLiteral> {
         match &self.kind {
             LoxTokenType::String(string) => Some(LoxLiteral::String(string.clone())),
@@ -109,16 +158,33 @@ pub struct Lexer {
     keywords: HashMap<&'static str, LoxTokenType>,
     source: String,
     tokens: Vec<LoxToken>,
-    /// Index in the source of the first character of the lexeme being scanned.
+    /// Errors encountered while scanning, in order of appearance.
+    errors: Vec<LoxInterpreterError>,
+    /// Byte offset in the source of the first character of the lexeme being scanned.
     start: usize,
-    /// Index in the source of the current character.
+    /// Byte offset in the source of the current character.
     current: usize,
     /// Current line in the source being scanned.
     line: usize,
+    /// Byte offset in the source of the first character of the current line.
+    line_start: usize,
+    /// Line and column of the first character of the lexeme being scanned.
+    start_position: (usize, usize),
 }
 
 impl Lexer {
+    /// Scan the given source code, failing on the first error encountered.
     pub fn from_source(source: String) -> Result<Self> {
+        let mut lexer = Self::scan(source);
+        if lexer.errors.is_empty() {
+            Ok(lexer)
+        } else {
+            Err(lexer.errors.remove(0))
+        }
+    }
+
+    /// Scan the given source code, skipping over the invalid lexemes.
+    pub fn scan(source: String) -> Self {
         let mut keywords = HashMap::new();
         keywords.insert("and", LoxTokenType::And);
         keywords.insert("class", LoxTokenType::Class);
@@ -141,29 +207,41 @@ impl Lexer {
             keywords,
             source,
             tokens: vec![],
+            errors: vec![],
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_position: (1, 1),
         };
-        lexer.scan_tokens()?;
-        Ok(lexer)
+        lexer.scan_tokens();
+        lexer
     }
 
     pub fn get_tokens(&self) -> &Vec<LoxToken> {
         &self.tokens
     }
 
-    fn scan_tokens(&mut self) -> Result<()> {
+    pub fn get_errors(&self) -> &[LoxInterpreterError] {
+        &self.errors
+    }
+
+    /// Consume the lexer, returning the scanned tokens and the errors encountered.
+    pub fn into_tokens_and_errors(self) -> (Vec<LoxToken>, Vec<LoxInterpreterError>) {
+        (self.tokens, self.errors)
+    }
+
+    fn scan_tokens(&mut self) {
         while !self.is_at_end() {
-            self.start = self.current;
-            self.scan_token()?;
+            self.start_lexeme();
+            if let Err(why) = self.scan_token() {
+                self.errors.push(why);
+            }
         }
-        self.tokens.push(LoxToken {
-            kind: LoxTokenType::EndOfFile,
-            lexeme: "".into(),
-            line_number: self.line,
-        });
-        Ok(())
+        self.start_lexeme();
+        let span = self.get_lexeme_span();
+        self.tokens
+            .push(LoxToken::new(LoxTokenType::EndOfFile, "".into(), span));
     }
 
     fn scan_token(&mut self) -> Result<()> {
@@ -220,21 +298,20 @@ impl Lexer {
             }
             ' ' | '\r' | '\t' => Ok(()),
             '\n' => {
-                self.line += 1;
+                self.new_line();
                 Ok(())
             }
             '"' => {
-                let mut current_character = self.peek();
-                while current_character != '"' && !self.is_at_end() {
-                    if current_character == '\n' {
-                        self.line += 1;
+                while self.peek() != '"' && !self.is_at_end() {
+                    if self.advance() == '\n' {
+                        self.new_line();
                     }
-                    self.advance();
-                    current_character = self.peek();
                 }
 
                 if self.is_at_end() {
-                    Err(LoxInterpreterError::LexerUnterminatedString)
+                    Err(LoxInterpreterError::LexerUnterminatedString(
+                        self.get_lexeme_span(),
+                    ))
                 } else {
                     self.advance(); // the closing "
                     let value = self.source[self.start + 1..self.current - 1].to_string(); // trim the surrounding quotes
@@ -249,6 +326,7 @@ impl Lexer {
                 } else {
                     Err(LoxInterpreterError::LexerUnexpectedCharacter(
                         char.to_string(),
+                        self.get_lexeme_span(),
                     ))
                 }
             }
@@ -269,9 +347,9 @@ impl Lexer {
         }
 
         let raw = &self.source[self.start..self.current];
-        let value = raw
-            .parse()
-            .map_err(|_| LoxInterpreterError::LexerInvalidNumber(raw.to_string()))?;
+        let value = raw.parse().map_err(|_| {
+            LoxInterpreterError::LexerInvalidNumber(raw.to_string(), self.get_lexeme_span())
+        })?;
         self.add_token_with_kind(LoxTokenType::Number(value))?;
 
         Ok(())
@@ -295,48 +373,50 @@ impl Lexer {
 
     fn add_token_with_kind(&mut self, kind: LoxTokenType) -> Result<()> {
         let lexeme = self.source[self.start..self.current].to_string();
-        self.tokens.push(LoxToken {
-            kind,
-            lexeme,
-            line_number: self.line,
-        });
+        let span = self.get_lexeme_span();
+        self.tokens.push(LoxToken::new(kind, lexeme, span));
         Ok(())
     }
 
+    /// Start scanning a new lexeme at the current character.
+    fn start_lexeme(&mut self) {
+        self.start = self.current;
+        let column = self.source[self.line_start..self.start].chars().count() + 1;
+        self.start_position = (self.line, column);
+    }
+
+    /// Location of the lexeme being scanned.
+    fn get_lexeme_span(&self) -> LoxSourceSpan {
+        let (line, column) = self.start_position;
+        LoxSourceSpan::new(self.start, self.current - self.start, line, column)
+    }
+
+    /// Move on to the next line, whose first character is the current one.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn advance(&mut self) -> char {
-        let char = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
+        let char = self.peek();
+        self.current += char.len_utf8();
         char
     }
 
     fn advance_if_match(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
-        if let Some(current_character) = self.source.chars().nth(self.current) {
-            if current_character == expected {
-                self.current += 1;
-                return true;
-            }
-        }
-        false
+        self.current += expected.len_utf8();
+        true
     }
 
     fn peek(&self) -> char {
-        if self.current >= self.source.len() {
-            '\0'
-        } else {
-            self.source.chars().nth(self.current).unwrap()
-        }
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        let next = self.current + 1;
-        if next >= self.source.len() {
-            '\0'
-        } else {
-            self.source.chars().nth(next).unwrap()
-        }
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     fn is_at_end(&self) -> bool {
@@ -344,11 +424,11 @@ impl Lexer {
     }
 
     fn is_digit(char: char) -> bool {
-        ('0'..='9').contains(&char)
+        char.is_ascii_digit()
     }
 
     fn is_alpha(char: char) -> bool {
-        char == '_' || ('a'..='z').contains(&char) || ('A'..='Z').contains(&char)
+        char == '_' || char.is_ascii_alphabetic()
     }
 
     fn is_alphanumeric(char: char) -> bool {
@@ -358,7 +438,10 @@ impl Lexer {
 
 #[cfg(test)]
 mod tests {
-    use crate::lexer::{LoxToken, LoxTokenType};
+    use crate::{
+        lexer::{LoxToken, LoxTokenType},
+        span::LoxSourceSpan,
+    };
 
     use super::Lexer;
 
@@ -366,73 +449,61 @@ mod tests {
     fn test_lexer_basic() {
         let source = "(5 - (3 - 1)) + -1";
         let lexer = Lexer::from_source(source.to_string()).unwrap();
+        let token = |kind, lexeme: &str, offset| {
+            LoxToken::new(
+                kind,
+                lexeme.to_string(),
+                LoxSourceSpan::new(offset, lexeme.len(), 1, offset + 1),
+            )
+        };
         let expected = vec![
-            LoxToken {
-                kind: LoxTokenType::LeftParenthesis,
-                lexeme: "(".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::Number(5.0),
-                lexeme: "5".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::Minus,
-                lexeme: "-".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::LeftParenthesis,
-                lexeme: "(".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::Number(3.0),
-                lexeme: "3".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::Minus,
-                lexeme: "-".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::Number(1.0),
-                lexeme: "1".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::RightParenthesis,
-                lexeme: ")".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::RightParenthesis,
-                lexeme: ")".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::Plus,
-                lexeme: "+".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::Minus,
-                lexeme: "-".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::Number(1.0),
-                lexeme: "1".to_string(),
-                line_number: 1,
-            },
-            LoxToken {
-                kind: LoxTokenType::EndOfFile,
-                lexeme: "".to_string(),
-                line_number: 1,
-            },
+            token(LoxTokenType::LeftParenthesis, "(", 0),
+            token(LoxTokenType::Number(5.0), "5", 1),
+            token(LoxTokenType::Minus, "-", 3),
+            token(LoxTokenType::LeftParenthesis, "(", 5),
+            token(LoxTokenType::Number(3.0), "3", 6),
+            token(LoxTokenType::Minus, "-", 8),
+            token(LoxTokenType::Number(1.0), "1", 10),
+            token(LoxTokenType::RightParenthesis, ")", 11),
+            token(LoxTokenType::RightParenthesis, ")", 12),
+            token(LoxTokenType::Plus, "+", 14),
+            token(LoxTokenType::Minus, "-", 16),
+            token(LoxTokenType::Number(1.0), "1", 17),
+            token(LoxTokenType::EndOfFile, "", 18),
         ];
         assert_eq!(lexer.get_tokens(), &expected);
     }
+
+    #[test]
+    fn test_lexer_spans() {
+        let source = "var a = \"é\nb\";\n  print a;";
+        let lexer = Lexer::from_source(source.to_string()).unwrap();
+        let spans: Vec<LoxSourceSpan> = lexer
+            .get_tokens()
+            .iter()
+            .map(|token| token.get_span())
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                LoxSourceSpan::new(0, 3, 1, 1),
+                LoxSourceSpan::new(4, 1, 1, 5),
+                LoxSourceSpan::new(6, 1, 1, 7),
+                // strings spanning several lines start where their opening quote is
+                LoxSourceSpan::new(8, 6, 1, 9),
+                LoxSourceSpan::new(14, 1, 2, 3),
+                LoxSourceSpan::new(18, 5, 3, 3),
+                LoxSourceSpan::new(24, 1, 3, 9),
+                LoxSourceSpan::new(25, 1, 3, 10),
+                LoxSourceSpan::new(26, 0, 3, 11),
+            ]
+        );
+        assert_eq!(
+            Lexer::from_source("1 @".to_string())
+                .unwrap_err()
+                .span()
+                .unwrap(),
+            LoxSourceSpan::new(2, 1, 1, 3)
+        );
+    }
 }
@@ -1,10 +1,14 @@
 pub mod bytecode;
 pub mod callable;
+pub mod diagnostics;
 pub mod errors;
 pub mod expressions;
 pub mod interpreter;
+pub mod json;
 pub mod lexer;
 pub mod parser;
 pub mod printer;
 pub mod reader;
+pub mod repl;
+pub mod span;
 pub mod values;
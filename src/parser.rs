@@ -1,27 +1,82 @@
 use crate::{
     errors::{LoxInterpreterError, Result},
-    expressions::{LoxExpression, LoxLiteral, LoxOperation, LoxStatement},
-    lexer::{LoxToken, LoxTokenType},
+    expressions::{LoxExpression, LoxExpressionId, LoxLiteral, LoxOperation, LoxStatement},
+    lexer::{Lexer, LoxToken, LoxTokenType},
 };
 
+/// Syntax tree of a script, with the declarations which could not be parsed left out,
+/// and all the syntax errors found in the script.
+pub struct LoxParsedScript {
+    operations: Vec<LoxOperation>,
+    errors: Vec<LoxInterpreterError>,
+}
+
+impl LoxParsedScript {
+    pub fn get_operations(&self) -> &[LoxOperation] {
+        &self.operations
+    }
+
+    pub fn get_errors(&self) -> &[LoxInterpreterError] {
+        &self.errors
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    /// The syntax tree of the script, if it is free of syntax errors.
+    pub fn into_operations(self) -> Result<Vec<LoxOperation>> {
+        if self.errors.is_empty() {
+            Ok(self.operations)
+        } else {
+            Err(LoxInterpreterError::ParserErrors(self.errors))
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Parser {
     tokens: Vec<LoxToken>,
     /// Index of the current token.
     current: usize,
+    /// Syntax errors encountered so far, the lexer ones first.
+    errors: Vec<LoxInterpreterError>,
 }
 
 impl Parser {
     pub fn from_tokens(tokens: Vec<LoxToken>) -> Self {
-        Self { tokens, current: 0 }
+        Self {
+            tokens,
+            current: 0,
+            errors: vec![],
+        }
+    }
+
+    /// Parse the tokens of the given lexer, reporting its errors along with the parsing ones.
+    pub fn from_lexer(lexer: Lexer) -> Self {
+        let (tokens, errors) = lexer.into_tokens_and_errors();
+        Self {
+            tokens,
+            current: 0,
+            errors,
+        }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<LoxOperation>> {
+    /// Parse all the declarations, recovering from syntax errors to report all of them.
+    pub fn parse(mut self) -> LoxParsedScript {
         let mut operations = vec![];
         while !self.is_at_end() {
-            operations.push(self.handle_declaration()?);
+            if let Some(declaration) = self.handle_declaration() {
+                operations.push(declaration);
+            }
+        }
+        // the lexer errors were all recorded before parsing started
+        self.errors
+            .sort_by_key(|error| error.span().map(|span| span.get_offset()));
+        LoxParsedScript {
+            operations,
+            errors: self.errors,
         }
-        Ok(operations)
     }
 
     /// Discards tokens until a probable statement boundary is found.
@@ -148,7 +203,9 @@ impl Parser {
         LoxInterpreterError::ParserError(token.clone(), message.to_string())
     }
 
-    fn handle_declaration(&mut self) -> Result<LoxOperation> {
+    /// Parse a declaration, or report the syntax error and skip to the next
+    /// statement if it is invalid.
+    fn handle_declaration(&mut self) -> Option<LoxOperation> {
         let mut inner_parsing = || -> Result<LoxOperation> {
             if self.match_kinds(&[LoxTokenType::Class]) {
                 self.handle_class_declaration()
@@ -162,12 +219,11 @@ impl Parser {
         };
 
         match inner_parsing() {
-            Ok(declaration) => Ok(declaration),
+            Ok(declaration) => Some(declaration),
             Err(why) => {
+                self.errors.push(why);
                 self.synchronize();
-                // TODO: improve error reporting (line number, etc.)
-                println!("{}: {:?}", why, why);
-                Ok(LoxOperation::Invalid)
+                None
             }
         }
     }
@@ -179,6 +235,7 @@ impl Parser {
         let super_class = if self.match_kinds(&[LoxTokenType::Less]) {
             let _ = self.consume_identifier("Expect superclass name.")?;
             LoxExpression::Variable {
+                id: LoxExpressionId::new_unique(),
                 name: self.peek_previous().clone(),
             }
         } else {
@@ -214,14 +271,11 @@ impl Parser {
             parameters.push(self.consume_identifier("Expect parameter name.")?.clone());
             while self.match_kinds(&[LoxTokenType::Comma]) {
                 if parameters.len() >= 255 {
-                    // TODO: better error reporting
-                    println!(
-                        "{:?}",
-                        Self::build_parse_error(
-                            self.peek(),
-                            "Can't have more than 255 parameters."
-                        )
-                    );
+                    // reported without entering panic mode
+                    self.errors.push(Self::build_parse_error(
+                        self.peek(),
+                        "Can't have more than 255 parameters.",
+                    ));
                 }
                 parameters.push(self.consume_identifier("Expect parameter name.")?.clone());
             }
@@ -331,7 +385,9 @@ impl Parser {
         } else {
             self.handle_expression()?.as_expression()?
         };
-        let _ = self.consume_kind(&LoxTokenType::Semicolon, "Expect ';' after loop condition.")?;
+        let condition_end = self
+            .consume_kind(&LoxTokenType::Semicolon, "Expect ';' after loop condition.")?
+            .get_span();
         // increment
         let increment = if self.check(&LoxTokenType::RightParenthesis) {
             LoxExpression::NoOp
@@ -360,6 +416,7 @@ impl Parser {
             condition: if condition.is_noop() {
                 LoxExpression::Literal {
                     value: LoxLiteral::True,
+                    span: condition_end,
                 }
             } else {
                 condition
@@ -398,7 +455,9 @@ impl Parser {
     fn handle_statements_block(&mut self) -> Result<Vec<LoxStatement>> {
         let mut statements = vec![];
         while !self.check(&LoxTokenType::RightBrace) && !self.is_at_end() {
-            statements.push(self.handle_declaration()?.as_statement()?);
+            if let Some(declaration) = self.handle_declaration() {
+                statements.push(declaration.as_statement()?);
+            }
         }
         let _ = self.consume_kind(&LoxTokenType::RightBrace, "Expect '}' after block.")?;
         Ok(statements)
@@ -422,7 +481,8 @@ impl Parser {
             let equals = self.peek_previous().clone();
             let value = self.handle_assignment()?;
             match &expression {
-                LoxExpression::Variable { name } => Ok(LoxExpression::Assign {
+                LoxExpression::Variable { id: _, name } => Ok(LoxExpression::Assign {
+                    id: LoxExpressionId::new_unique(),
                     name: name.clone(),
                     value: Box::new(value),
                 }),
@@ -431,10 +491,14 @@ impl Parser {
                     object: object.clone(),
                     value: Box::new(value),
                 }),
-                _ => Err(Self::build_parse_error(
-                    &equals,
-                    "Invalid assignment target.",
-                )),
+                _ => {
+                    // reported without entering panic mode
+                    self.errors.push(Self::build_parse_error(
+                        &equals,
+                        "Invalid assignment target.",
+                    ));
+                    Ok(expression)
+                }
             }
         } else {
             Ok(expression)
@@ -573,11 +637,11 @@ impl Parser {
             arguments.push(self.handle_expression()?.as_expression()?);
             while self.match_kinds(&[LoxTokenType::Comma]) {
                 if arguments.len() >= 255 {
-                    // TODO: better error reporting
-                    println!(
-                        "{:?}",
-                        Self::build_parse_error(self.peek(), "Can't have more than 255 arguments.")
-                    );
+                    // reported without entering panic mode
+                    self.errors.push(Self::build_parse_error(
+                        self.peek(),
+                        "Can't have more than 255 arguments.",
+                    ));
                 }
                 arguments.push(self.handle_expression()?.as_expression()?);
             }
@@ -599,31 +663,43 @@ impl Parser {
         if self.match_kinds(&[LoxTokenType::False]) {
             Ok(LoxExpression::Literal {
                 value: LoxLiteral::False,
+                span: self.peek_previous().get_span(),
             })
         } else if self.match_kinds(&[LoxTokenType::True]) {
             Ok(LoxExpression::Literal {
                 value: LoxLiteral::True,
+                span: self.peek_previous().get_span(),
             })
         } else if self.match_kinds(&[LoxTokenType::Nil]) {
             Ok(LoxExpression::Literal {
                 value: LoxLiteral::Nil,
+                span: self.peek_previous().get_span(),
             })
         } else if self.match_number() || self.match_string() {
-            let value = self.peek_previous().build_literal().unwrap();
-            Ok(LoxExpression::Literal { value })
+            let token = self.peek_previous();
+            Ok(LoxExpression::Literal {
+                value: token.build_literal().unwrap(),
+                span: token.get_span(),
+            })
         } else if self.match_kinds(&[LoxTokenType::Super]) {
             let keyword = self.peek_previous().clone();
-            let _ = self.consume_kind(&LoxTokenType::Semicolon, "Expect '.' after 'super'.")?;
+            let _ = self.consume_kind(&LoxTokenType::Dot, "Expect '.' after 'super'.")?;
             let method = self
                 .consume_identifier("Expect superclass method name.")?
                 .clone();
-            Ok(LoxExpression::Super { keyword, method })
+            Ok(LoxExpression::Super {
+                id: LoxExpressionId::new_unique(),
+                keyword,
+                method,
+            })
         } else if self.match_kinds(&[LoxTokenType::This]) {
             Ok(LoxExpression::This {
+                id: LoxExpressionId::new_unique(),
                 keyword: self.peek_previous().clone(),
             })
         } else if self.match_identifier() {
             Ok(LoxExpression::Variable {
+                id: LoxExpressionId::new_unique(),
                 name: self.peek_previous().clone(),
             })
         } else if self.match_kinds(&[LoxTokenType::LeftParenthesis]) {
@@ -7,6 +7,26 @@ pub trait LoxPrintable {
     fn representation(&self) -> String;
 }
 
+/// Output sink for the Lox `print` statement, shared by all the interpreters.
+pub trait LoxLinePrinter {
+    fn print(&mut self, output: String);
+    fn history(&self) -> Option<&[String]>;
+}
+
+pub type LoxLinePrinterInstance = Box<dyn LoxLinePrinter>;
+
+pub struct StdOutPrinter;
+
+impl LoxLinePrinter for StdOutPrinter {
+    fn print(&mut self, output: String) {
+        println!("{}", output);
+    }
+
+    fn history(&self) -> Option<&[String]> {
+        None
+    }
+}
+
 impl LoxPrintable for LoxLiteral {
     fn representation(&self) -> String {
         match self {
@@ -23,7 +43,7 @@ impl LoxPrintable for LoxExpression {
     fn representation(&self) -> String {
         match self {
             Self::NoOp => "".to_string(),
-            Self::Assign { name, value } => debug_parenthesize_fragments(&[
+            Self::Assign { id: _, name, value } => debug_parenthesize_fragments(&[
                 LoxPrintableFragment::Arbitrary("=".into()),
                 LoxPrintableFragment::Token(name),
                 LoxPrintableFragment::Expression(value),
@@ -48,7 +68,7 @@ impl LoxPrintable for LoxExpression {
                     ],
                     arguments
                         .iter()
-                        .map(|argument| LoxPrintableFragment::Expression(argument))
+                        .map(LoxPrintableFragment::Expression)
                         .collect(),
                 ]
                 .concat()
@@ -70,7 +90,7 @@ impl LoxPrintable for LoxExpression {
                 LoxPrintableFragment::Expression(value),
             ]),
             Self::Group { expression } => debug_parenthesize("group", &[expression.as_ref()]),
-            Self::Literal { value } => value.representation(),
+            Self::Literal { value, span: _ } => value.representation(),
             Self::Logical {
                 left,
                 operator,
@@ -81,14 +101,15 @@ impl LoxPrintable for LoxExpression {
                 LoxPrintableFragment::Expression(right),
             ]),
             Self::Super {
+                id: _,
                 keyword: _,
                 method: _,
             } => "super".to_string(),
-            Self::This { keyword: _ } => "this".to_string(),
+            Self::This { id: _, keyword: _ } => "this".to_string(),
             Self::Unary { operator, right } => {
                 debug_parenthesize(operator.get_lexeme().as_str(), &[right.as_ref()])
             }
-            Self::Variable { name } => name.get_lexeme().clone(),
+            Self::Variable { id: _, name } => name.get_lexeme().clone(),
         }
     }
 }
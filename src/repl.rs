@@ -0,0 +1,130 @@
+use std::io::{BufRead, Write};
+
+use crate::{
+    diagnostics::render_error,
+    errors::{LoxResult, Result},
+    interpreter::LoxInterpreter,
+};
+
+const REPL_PROMPT: &str = "> ";
+const REPL_CONTINUATION_PROMPT: &str = "... ";
+
+/// Interactive line-by-line Lox session.
+///
+/// A single interpreter is kept alive for the whole session so that globals,
+/// functions and classes declared on one line can be used on the following ones.
+pub struct LoxRepl {
+    interpreter: Box<dyn LoxInterpreter>,
+}
+
+impl LoxRepl {
+    pub fn new(interpreter: Box<dyn LoxInterpreter>) -> Self {
+        Self { interpreter }
+    }
+
+    /// Read-eval-print until the end of the input is reached.
+    ///
+    /// Errors are reported on the standard error output and do not end the session.
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> Result<()> {
+        let mut buffer = String::new();
+        loop {
+            output.write_all(
+                if buffer.is_empty() {
+                    REPL_PROMPT
+                } else {
+                    REPL_CONTINUATION_PROMPT
+                }
+                .as_bytes(),
+            )?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                output.write_all(b"\n")?;
+                return Ok(());
+            }
+            buffer += line.as_str();
+            if !is_input_complete(&buffer) {
+                continue;
+            }
+
+            match self.evaluate(&buffer) {
+                Ok(Some(representation)) => writeln!(output, "{}", representation)?,
+                Ok(None) => (),
+                Err(why) => eprint!("{}", render_error(&why, &buffer, "<repl>")),
+            }
+            buffer.clear();
+        }
+    }
+
+    /// Run a complete input, returning the representation of its value if it
+    /// is a bare expression.
+    pub fn evaluate(&mut self, input: &str) -> LoxResult<Option<String>> {
+        let source = input.trim_end();
+        if source.is_empty() {
+            return Ok(None);
+        }
+        // allow bare expressions without their trailing semicolon
+        if source.ends_with(';') || source.ends_with('}') {
+            self.interpreter.run_code(source)
+        } else {
+            self.interpreter.run_code(format!("{};", source).as_str())
+        }
+    }
+}
+
+/// Is the given input ready to be run, i.e. with no unbalanced braces or parentheses
+/// and no unterminated string?
+fn is_input_complete(input: &str) -> bool {
+    let mut depth: isize = 0;
+    let mut in_string = false;
+    let mut characters = input.chars().peekable();
+    while let Some(character) = characters.next() {
+        match character {
+            '"' => in_string = !in_string,
+            _ if in_string => (),
+            '/' if characters.peek() == Some(&'/') => {
+                // a comment goes until the end of the line
+                for next in characters.by_ref() {
+                    if next == '\n' {
+                        break;
+                    }
+                }
+            }
+            '{' | '(' => depth += 1,
+            '}' | ')' => depth -= 1,
+            _ => (),
+        }
+    }
+    !in_string && depth <= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::LoxTreeWalkInterpreter;
+
+    use super::{is_input_complete, LoxRepl};
+
+    #[test]
+    fn test_repl_input_completeness() {
+        assert!(is_input_complete("print 1;"));
+        assert!(is_input_complete("fun f() { return 1; }"));
+        assert!(!is_input_complete("fun f() {\n"));
+        assert!(!is_input_complete("print (1 +\n"));
+        assert!(!is_input_complete("print \"multi\nline"));
+        assert!(is_input_complete("print \"{\";"));
+        assert!(is_input_complete("print 1; // {"));
+    }
+
+    #[test]
+    fn test_repl_persistent_session() {
+        let mut repl = LoxRepl::new(Box::new(LoxTreeWalkInterpreter::new(None)));
+        let input = "var a = 1;\nfun add(b) {\n  return a + b;\n}\nadd(2)\na = ;\n1 + a\n";
+        let mut output = vec![];
+        repl.run(input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "> > ... ... > 3\n> > 2\n> \n"
+        );
+    }
+}
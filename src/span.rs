@@ -0,0 +1,80 @@
+use std::fmt;
+
+/// Location of a lexeme, or of a range of lexemes, in the source code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LoxSourceSpan {
+    /// Byte offset of the first character.
+    offset: usize,
+    /// Length in bytes.
+    length: usize,
+    /// Line of the first character, starting at 1.
+    line: usize,
+    /// Column of the first character on its line, in characters and starting at 1.
+    column: usize,
+}
+
+impl LoxSourceSpan {
+    pub fn new(offset: usize, length: usize, line: usize, column: usize) -> Self {
+        Self {
+            offset,
+            length,
+            line,
+            column,
+        }
+    }
+
+    pub fn get_offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn get_length(&self) -> usize {
+        self.length
+    }
+
+    pub fn get_end(&self) -> usize {
+        self.offset + self.length
+    }
+
+    pub fn get_line(&self) -> usize {
+        self.line
+    }
+
+    pub fn get_column(&self) -> usize {
+        self.column
+    }
+
+    /// Smallest span covering both this span and the given one.
+    pub fn merge(&self, other: &Self) -> Self {
+        let (first, last) = if self.offset <= other.offset {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        Self {
+            length: first.get_end().max(last.get_end()) - first.offset,
+            ..*first
+        }
+    }
+}
+
+impl fmt::Display for LoxSourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoxSourceSpan;
+
+    #[test]
+    fn test_span_merge() {
+        let first = LoxSourceSpan::new(4, 3, 1, 5);
+        let second = LoxSourceSpan::new(10, 2, 2, 1);
+        let merged = LoxSourceSpan::new(4, 8, 1, 5);
+        assert_eq!(first.merge(&second), merged);
+        assert_eq!(second.merge(&first), merged);
+        assert_eq!(merged.merge(&second), merged);
+        assert_eq!(merged.to_string(), "1:5");
+    }
+}
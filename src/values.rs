@@ -3,31 +3,44 @@ use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
 use crate::{
     errors::{LoxInterpreterError, Result},
     expressions::LoxStatement,
-    interpreter::environment::{LoxEnvironment, LoxEnvironmentHandle},
+    interpreter::{
+        environment::{LoxEnvironment, LoxEnvironmentHandle},
+        heap::{heap_track_object, LoxHeapTracer},
+        tree_walk::LoxTreeWalkEvaluatorLocalsHandle,
+    },
     lexer::LoxToken,
     printer::LoxPrintable,
 };
 
 pub const LOX_NUMBER_VALUE_COMPARISON_EPSILON: f64 = f64::EPSILON;
 
-pub type LoxNativeFunctionExecutor =
-    fn(&mut LoxEnvironmentHandle, &[LoxValueHandle]) -> Result<LoxValueHandle>;
+pub type LoxNativeFunctionExecutor = fn(&mut LoxEnvironmentHandle, &[LoxValue]) -> Result<LoxValue>;
 
-pub type LoxValueHandle = Rc<RefCell<LoxValue>>;
+pub type LoxObjectHandle = Rc<RefCell<LoxObject>>;
 
 /// A runtime Lox value.
+///
+/// Numbers, booleans and nil are stored inline, while strings and objects are
+/// shared between all their references.
 #[derive(Clone)]
 pub enum LoxValue {
     Nil,
     Number(f64),
     Boolean(bool),
-    String(String),
+    String(Rc<str>),
+    Object(LoxObjectHandle),
+}
+
+/// A heap-allocated runtime Lox value.
+pub enum LoxObject {
     Function {
         /// Number of input parameters.
         arity: usize,
         is_initializer: bool,
         declaration: Box<LoxStatement>,
         closure: LoxEnvironmentHandle,
+        /// Resolved local variables of the script declaring the function.
+        locals: LoxTreeWalkEvaluatorLocalsHandle,
     },
     NativeFunction {
         label: String,
@@ -37,18 +50,18 @@ pub enum LoxValue {
     },
     Class {
         name: String,
-        super_class: LoxValueHandle,
-        methods: HashMap<String, LoxValueHandle>,
+        super_class: Option<LoxObjectHandle>,
+        methods: HashMap<String, LoxObjectHandle>,
     },
     ClassInstance {
-        class: LoxValueHandle,
-        fields: HashMap<String, LoxValueHandle>,
+        class: LoxObjectHandle,
+        fields: HashMap<String, LoxValue>,
     },
 }
 
 impl LoxValue {
-    pub fn new(value: LoxValue) -> LoxValueHandle {
-        Rc::new(RefCell::new(value))
+    pub fn new_object(object: LoxObject) -> Self {
+        Self::Object(LoxObject::new_handle(object))
     }
 
     pub fn is_nil(&self) -> bool {
@@ -66,25 +79,19 @@ impl LoxValue {
     }
 
     pub fn is_class(&self) -> bool {
-        matches!(
-            self,
-            Self::Class {
-                name: _,
-                super_class: _,
-                methods: _,
-            }
-        )
+        matches!(self, Self::Object(object) if object.borrow().is_class())
     }
 
+    /// Objects are only equal to themselves.
     pub fn equals(&self, other: &Self) -> bool {
         match (self, other) {
             (Self::Nil, Self::Nil) => true,
-            (Self::Nil, _) => false,
             (Self::Boolean(left), Self::Boolean(right)) => *left == *right,
             (Self::String(left), Self::String(right)) => *left == *right,
             (Self::Number(left), Self::Number(right)) => {
                 (left - right).abs() < LOX_NUMBER_VALUE_COMPARISON_EPSILON
             }
+            (Self::Object(left), Self::Object(right)) => Rc::ptr_eq(left, right),
             _ => false,
         }
     }
@@ -96,12 +103,39 @@ impl LoxValue {
         }
     }
 
+    pub fn as_object(&self) -> Option<&LoxObjectHandle> {
+        match self {
+            Self::Object(object) => Some(object),
+            _ => None,
+        }
+    }
+}
+
+impl LoxObject {
+    pub fn new_handle(object: LoxObject) -> LoxObjectHandle {
+        let handle = Rc::new(RefCell::new(object));
+        heap_track_object(&handle);
+        handle
+    }
+
+    pub fn is_class(&self) -> bool {
+        matches!(
+            self,
+            Self::Class {
+                name: _,
+                super_class: _,
+                methods: _,
+            }
+        )
+    }
+
     pub fn function_is_initializer(&self) -> bool {
         if let Self::Function {
             arity: _,
             closure: _,
             declaration: _,
             is_initializer,
+            locals: _,
         } = self
         {
             *is_initializer
@@ -121,41 +155,100 @@ impl LoxValue {
         }
     }
 
-    pub fn class_find_method(&self, name: &str) -> Option<LoxValueHandle> {
+    /// Find the method with the given name in the class, or else in its superclasses.
+    pub fn class_find_method(&self, name: &str) -> Option<LoxObjectHandle> {
         if let Self::Class {
             name: _,
             super_class,
             methods,
         } = self
         {
-            let borrowed = super_class.borrow();
-            if !borrowed.is_nil() {
-                borrowed.class_find_method(name)
-            } else {
-                methods.get(name).cloned()
-            }
+            methods.get(name).cloned().or_else(|| {
+                super_class
+                    .as_ref()
+                    .and_then(|super_class| super_class.borrow().class_find_method(name))
+            })
         } else {
             None
         }
     }
 
-    pub fn class_method_bind_this(&self, instance: &LoxValueHandle) -> Option<LoxValueHandle> {
+    /// Mark the values and environments referenced by this object.
+    pub fn trace(&self, tracer: &mut LoxHeapTracer) {
+        match self {
+            Self::Function {
+                arity: _,
+                is_initializer: _,
+                declaration: _,
+                closure,
+                locals: _,
+            } => tracer.mark_environment(closure),
+            Self::NativeFunction {
+                label: _,
+                arity: _,
+                execute: _,
+            } => (),
+            Self::Class {
+                name: _,
+                super_class,
+                methods,
+            } => super_class
+                .iter()
+                .chain(methods.values())
+                .for_each(|object| tracer.mark_object(object)),
+            Self::ClassInstance { class, fields } => {
+                tracer.mark_object(class);
+                fields.values().for_each(|value| tracer.mark_value(value));
+            }
+        }
+    }
+
+    /// Drop the references that can be part of a cycle.
+    ///
+    /// Functions refer to their closure only, whose references are cleared instead.
+    pub fn clear_references(&mut self) {
+        match self {
+            Self::Class {
+                name: _,
+                super_class,
+                methods,
+            } => {
+                *super_class = None;
+                methods.clear();
+            }
+            Self::ClassInstance { class: _, fields } => fields.clear(),
+            Self::Function {
+                arity: _,
+                is_initializer: _,
+                declaration: _,
+                closure: _,
+                locals: _,
+            }
+            | Self::NativeFunction {
+                label: _,
+                arity: _,
+                execute: _,
+            } => (),
+        }
+    }
+
+    pub fn class_method_bind_this(&self, instance: &LoxValue) -> Option<LoxValue> {
         if let Self::Function {
             arity,
             is_initializer,
             closure,
             declaration,
+            locals,
         } = self
         {
             let environment = LoxEnvironment::new(Some(closure.clone()));
-            environment
-                .borrow_mut()
-                .define("this".into(), instance.clone());
-            Some(Self::new(LoxValue::Function {
+            environment.borrow_mut().define("this", instance.clone());
+            Some(LoxValue::new_object(LoxObject::Function {
                 arity: *arity,
                 closure: environment,
                 is_initializer: *is_initializer,
                 declaration: declaration.clone(),
+                locals: locals.clone(),
             }))
         } else {
             None
@@ -163,50 +256,54 @@ impl LoxValue {
     }
 }
 
-pub fn lox_value_handle_instance_get_field(
-    handle: &LoxValueHandle,
-    name: &LoxToken,
-) -> Result<LoxValueHandle> {
-    dbg!(name.get_lexeme());
-    if let LoxValue::ClassInstance { class, fields } = &*handle.borrow() {
-        // find method
-        if let Some(method) = class.borrow().class_find_method(name.get_lexeme()) {
-            return Ok(method
-                .borrow()
-                .class_method_bind_this(handle)
-                .expect("method value is a function"));
+pub fn lox_value_instance_get_field(value: &LoxValue, name: &LoxToken) -> Result<LoxValue> {
+    let object = match value {
+        LoxValue::Object(object) => object.borrow(),
+        _ => {
+            return Err(LoxInterpreterError::InterpreterCannotGetProperty(
+                name.clone(),
+            ))
         }
+    };
+    if let LoxObject::ClassInstance { class, fields } = &*object {
         // find field
-        fields
-            .get(name.get_lexeme())
-            .ok_or_else(|| {
-                LoxInterpreterError::InterpreterUndefinedClassProperty(name.get_lexeme().clone())
+        if let Some(field) = fields.get(name.get_lexeme()) {
+            return Ok(field.clone());
+        }
+        // find method
+        class
+            .borrow()
+            .class_find_method(name.get_lexeme())
+            .map(|method| {
+                method
+                    .borrow()
+                    .class_method_bind_this(value)
+                    .expect("method value is a function")
             })
-            .map(|handle| handle.clone())
+            .ok_or_else(|| LoxInterpreterError::InterpreterUndefinedClassProperty(name.clone()))
     } else {
-        Err(LoxInterpreterError::InterpreterCannotGetOrSetField(
+        Err(LoxInterpreterError::InterpreterCannotGetProperty(
             name.clone(),
         ))
     }
 }
 
-pub fn lox_value_handle_instance_set_field(
-    handle: &mut LoxValueHandle,
+pub fn lox_value_instance_set_field(
+    value: &LoxValue,
     name: &LoxToken,
-    value: LoxValueHandle,
-) -> Result<LoxValueHandle> {
-    if let LoxValue::ClassInstance {
-        class: _,
-        ref mut fields,
-    } = &mut *handle.borrow_mut()
-    {
-        fields.insert(name.get_lexeme().clone(), value.clone());
-        Ok(value)
-    } else {
-        Err(LoxInterpreterError::InterpreterCannotGetOrSetField(
-            name.clone(),
-        ))
+    field: LoxValue,
+) -> Result<LoxValue> {
+    if let LoxValue::Object(object) = value {
+        if let LoxObject::ClassInstance {
+            class: _,
+            ref mut fields,
+        } = &mut *object.borrow_mut()
+        {
+            fields.insert(name.get_lexeme().clone(), field.clone());
+            return Ok(field);
+        }
     }
+    Err(LoxInterpreterError::InterpreterCannotSetField(name.clone()))
 }
 
 impl LoxPrintable for LoxValue {
@@ -215,21 +312,30 @@ impl LoxPrintable for LoxValue {
             Self::Nil => "nil".to_string(),
             Self::Number(number) => format!("{}", number),
             Self::Boolean(boolean) => (if *boolean { "true" } else { "false" }).to_string(),
-            Self::String(string) => string.clone(),
+            Self::String(string) => string.to_string(),
+            Self::Object(object) => object.borrow().representation(),
+        }
+    }
+}
+
+impl LoxPrintable for LoxObject {
+    fn representation(&self) -> String {
+        match self {
             Self::Function {
                 arity: _,
                 is_initializer: _,
                 declaration,
                 closure: _,
+                locals: _,
             } => {
                 let (name, _, _) = declaration.deconstruct_function_declaration().unwrap();
                 format!("<fn {}>", name.get_lexeme())
             }
             Self::NativeFunction {
-                label,
+                label: _,
                 arity: _,
                 execute: _,
-            } => format!("<native fn {}>", label),
+            } => "<native fn>".to_string(),
             Self::Class {
                 name,
                 super_class: _,
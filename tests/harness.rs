@@ -1,11 +1,24 @@
-use std::path::{Path, PathBuf};
+use std::{
+    fmt,
+    io::{Read, Write},
+    iter,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
 
 use lazy_static::lazy_static;
 use regex::Regex;
 use walkdir::WalkDir;
 
-use rust_crafting_interpreters_lib::interpreter::{
-    tree_walk::LoxLinePrinter, LoxInterpreter, LoxTreeWalkInterpreter,
+use rust_crafting_interpreters_lib::{
+    bytecode::vm::LoxBytecodeVirtualMachine,
+    diagnostics::diagnostics_from_error,
+    errors::LoxError,
+    interpreter::{LoxInterpreter, LoxTreeWalkInterpreter},
+    printer::{LoxLinePrinter, LoxLinePrinterInstance},
+    span::LoxSourceSpan,
 };
 
 pub fn discover_tests<P: AsRef<Path>>(root: P) -> Vec<PathBuf> {
@@ -21,9 +34,90 @@ pub fn discover_tests<P: AsRef<Path>>(root: P) -> Vec<PathBuf> {
     paths
 }
 
+/// Interpreter running the test suites.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoxAutoTestBackend {
+    TreeWalk,
+    Bytecode,
+}
+
+impl LoxAutoTestBackend {
+    pub fn build_interpreter(self, printer: LoxLinePrinterInstance) -> Box<dyn LoxInterpreter> {
+        match self {
+            Self::TreeWalk => Box::new(LoxTreeWalkInterpreter::new(Some(printer))),
+            Self::Bytecode => Box::new(LoxBytecodeVirtualMachine::new(Some(printer))),
+        }
+    }
+
+    /// Name of the backend for the command-line interface.
+    fn get_cli_name(self) -> &'static str {
+        match self {
+            Self::TreeWalk => "tree-walk",
+            Self::Bytecode => "bytecode",
+        }
+    }
+
+    /// Language tag of the expectations only holding for the reference
+    /// implementation of this backend (e.g. `// [c line 3] Error ...`).
+    fn get_language(self) -> &'static str {
+        match self {
+            Self::TreeWalk => "java",
+            Self::Bytecode => "c",
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum LoxAutoTestAssertion {
     ExpectOutput(String),
+    /// Syntax or resolution error, reported before running the script.
+    ExpectCompileError {
+        line: usize,
+        /// Offending lexeme, or `None` for errors without a location (e.g. lexical errors).
+        location: Option<LoxAutoTestErrorLocation>,
+        message: String,
+    },
+    ExpectRuntimeError {
+        line: usize,
+        message: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoxAutoTestErrorLocation {
+    Lexeme(String),
+    EndOfFile,
+}
+
+impl LoxAutoTestErrorLocation {
+    /// Location of the error with the given span, in the reference implementations' format.
+    fn from_span(source: &str, span: LoxSourceSpan) -> Self {
+        match &source[span.get_offset()..span.get_end()] {
+            "" => Self::EndOfFile,
+            lexeme => Self::Lexeme(lexeme.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for LoxAutoTestErrorLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lexeme(lexeme) => write!(f, " at '{}'", lexeme),
+            Self::EndOfFile => write!(f, " at end"),
+        }
+    }
+}
+
+/// Format an expected or reported compile error like the reference implementations.
+fn format_compile_error(
+    line: usize,
+    location: Option<&LoxAutoTestErrorLocation>,
+    message: &str,
+) -> String {
+    match location {
+        Some(location) => format!("[line {}] Error{}: {}", line, location, message),
+        None => format!("[line {}] Error: {}", line, message),
+    }
 }
 
 impl LoxAutoTestAssertion {
@@ -34,6 +128,7 @@ impl LoxAutoTestAssertion {
     pub fn as_output(&self) -> Option<&String> {
         match self {
             Self::ExpectOutput(output) => Some(output),
+            _ => None,
         }
     }
 }
@@ -46,15 +141,54 @@ pub struct LoxAutoTestSuite {
 }
 
 impl LoxAutoTestSuite {
-    pub fn from_code(path: PathBuf, code: String) -> Result<Self, String> {
+    pub fn from_code(
+        path: PathBuf,
+        code: String,
+        backend: LoxAutoTestBackend,
+    ) -> Result<Self, String> {
         lazy_static! {
             static ref ASSERT_OUTPUT_REGEX: Regex = Regex::new("// expect: ?(.*)").unwrap();
+            static ref ASSERT_COMPILE_ERROR_REGEX: Regex =
+                Regex::new(r"// (?:\[(?:(java|c) )?line (\d+)\] )?Error(?: at (end|'.*'))?: (.*)")
+                    .unwrap();
+            static ref ASSERT_RUNTIME_ERROR_REGEX: Regex =
+                Regex::new("// expect runtime error: (.*)").unwrap();
         }
 
         let mut asserts = vec![];
-        for captures in ASSERT_OUTPUT_REGEX.captures_iter(&code) {
-            let expected = captures.get(1).unwrap().as_str();
-            asserts.push(LoxAutoTestAssertion::ExpectOutput(expected.into()));
+        for (i, line) in code.lines().enumerate() {
+            if let Some(captures) = ASSERT_OUTPUT_REGEX.captures(line) {
+                let expected = captures.get(1).unwrap().as_str();
+                asserts.push(LoxAutoTestAssertion::ExpectOutput(expected.into()));
+            } else if let Some(captures) = ASSERT_COMPILE_ERROR_REGEX.captures(line) {
+                // errors only reported by the other reference implementation
+                if let Some(language) = captures.get(1) {
+                    if language.as_str() != backend.get_language() {
+                        continue;
+                    }
+                }
+                let line = match captures.get(2) {
+                    Some(line) => line
+                        .as_str()
+                        .parse()
+                        .map_err(|_| format!("invalid line in '{}'", line.as_str()))?,
+                    None => i + 1,
+                };
+                let location = captures.get(3).map(|location| match location.as_str() {
+                    "end" => LoxAutoTestErrorLocation::EndOfFile,
+                    lexeme => LoxAutoTestErrorLocation::Lexeme(lexeme[1..lexeme.len() - 1].into()),
+                });
+                asserts.push(LoxAutoTestAssertion::ExpectCompileError {
+                    line,
+                    location,
+                    message: captures.get(4).unwrap().as_str().into(),
+                });
+            } else if let Some(captures) = ASSERT_RUNTIME_ERROR_REGEX.captures(line) {
+                asserts.push(LoxAutoTestAssertion::ExpectRuntimeError {
+                    line: i + 1,
+                    message: captures.get(1).unwrap().as_str().into(),
+                });
+            }
         }
         Ok(Self {
             path,
@@ -62,6 +196,29 @@ impl LoxAutoTestSuite {
             asserts,
         })
     }
+
+    fn expected_compile_errors(&self) -> Vec<String> {
+        self.asserts
+            .iter()
+            .filter_map(|assertion| match assertion {
+                LoxAutoTestAssertion::ExpectCompileError {
+                    line,
+                    location,
+                    message,
+                } => Some(format_compile_error(*line, location.as_ref(), message)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn expected_runtime_error(&self) -> Option<(usize, &str)> {
+        self.asserts.iter().find_map(|assertion| match assertion {
+            LoxAutoTestAssertion::ExpectRuntimeError { line, message } => {
+                Some((*line, message.as_str()))
+            }
+            _ => None,
+        })
+    }
 }
 
 #[derive(Default)]
@@ -80,48 +237,507 @@ impl LoxLinePrinter for HistoryPrinter {
 }
 
 pub struct LoxAutoTestHarness {
-    outputs: Vec<String>,
-    interpreter: LoxTreeWalkInterpreter,
+    interpreter: Box<dyn LoxInterpreter>,
 }
 
-impl Default for LoxAutoTestHarness {
-    fn default() -> Self {
-        let mut outputs = vec![];
+impl LoxAutoTestHarness {
+    /// The printer must keep its outputs history for them to be checked.
+    pub fn new(backend: LoxAutoTestBackend, printer: LoxLinePrinterInstance) -> Self {
         Self {
-            outputs,
-            interpreter: LoxTreeWalkInterpreter::new(Some(Box::new(HistoryPrinter::default()))),
+            interpreter: backend.build_interpreter(printer),
         }
     }
-}
 
-impl LoxAutoTestHarness {
     pub fn run_test_suite(&mut self, suite: &LoxAutoTestSuite) {
-        let parsed = self
-            .interpreter
-            .parse(suite.code.clone())
-            .expect("can parse the test suite's code");
-        self.interpreter
-            .interpret(&parsed)
-            .expect("can interpret the test suite's code");
+        let expected_compile_errors = suite.expected_compile_errors();
+        let expected_runtime_error = suite.expected_runtime_error();
+        match self.interpreter.run_code(&suite.code) {
+            Ok(_) => {
+                assert!(
+                    expected_compile_errors.is_empty(),
+                    "{}: expected compile errors {:?}",
+                    suite.path.display(),
+                    expected_compile_errors
+                );
+                assert_eq!(
+                    expected_runtime_error,
+                    None,
+                    "{}: expected a runtime error",
+                    suite.path.display()
+                );
+            }
+            Err(error) if !expected_compile_errors.is_empty() => {
+                self.check_compile_errors(suite, &error, &expected_compile_errors)
+            }
+            Err(error) => {
+                let (line, message) = expected_runtime_error.unwrap_or_else(|| {
+                    panic!("{}: unexpected error: {}", suite.path.display(), error)
+                });
+                assert_eq!(error.exit_code(), 70, "{}: {}", suite.path.display(), error);
+                let diagnostic = &diagnostics_from_error(&error)[0];
+                assert_eq!(
+                    (
+                        diagnostic.get_span().map(|span| span.get_line()),
+                        diagnostic.get_message()
+                    ),
+                    (Some(line), message),
+                    "{}",
+                    suite.path.display()
+                );
+            }
+        }
 
         self.run_assertions(suite);
     }
 
+    fn check_compile_errors(
+        &self,
+        suite: &LoxAutoTestSuite,
+        error: &LoxError,
+        expected: &[String],
+    ) {
+        assert_eq!(error.exit_code(), 65, "{}: {}", suite.path.display(), error);
+        let diagnostics = diagnostics_from_error(error);
+        // only compare the location when the test expects one
+        let expects_location = suite
+            .asserts
+            .iter()
+            .filter_map(|assertion| match assertion {
+                LoxAutoTestAssertion::ExpectCompileError { location, .. } => {
+                    Some(location.is_some())
+                }
+                _ => None,
+            });
+        let reported: Vec<String> = diagnostics
+            .iter()
+            .zip(expects_location.chain(iter::repeat(true)))
+            .map(|(diagnostic, expects_location)| {
+                let span = diagnostic.get_span().unwrap_or_default();
+                let location = Some(LoxAutoTestErrorLocation::from_span(&suite.code, span))
+                    .filter(|_| expects_location);
+                format_compile_error(span.get_line(), location.as_ref(), diagnostic.get_message())
+            })
+            .collect();
+        assert_eq!(reported, expected, "{}", suite.path.display());
+    }
+
     fn run_assertions(&self, suite: &LoxAutoTestSuite) {
-        assert_eq!(
-            self.outputs.len(),
-            suite
-                .asserts
-                .iter()
-                .filter(|assertion| assertion.is_output())
-                .count()
-        );
-        let mut output_assertions_count = 0;
-        for output in &suite.asserts {
-            if let Some(expected_output) = output.as_output() {
-                assert_eq!(&self.outputs[output_assertions_count], expected_output);
-                output_assertions_count += 1;
+        let outputs = self
+            .interpreter
+            .get_printer()
+            .history()
+            .expect("the printer keeps the outputs history");
+        // compare line by line, as printed on the standard output
+        let output_lines: Vec<&str> = outputs
+            .iter()
+            .flat_map(|output| output.split('\n'))
+            .collect();
+        let expected_outputs: Vec<&str> = suite
+            .asserts
+            .iter()
+            .filter_map(LoxAutoTestAssertion::as_output)
+            .map(String::as_str)
+            .collect();
+        assert_eq!(output_lines, expected_outputs, "{}", suite.path.display());
+    }
+}
+
+/// Result of running a program through one backend, as compared by the differential tests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoxDifferentialOutcomeKind {
+    Success,
+    CompileError,
+    RuntimeError,
+    /// Any other exit, e.g. a stack overflow of the Rust interpreter.
+    Crash,
+    Timeout,
+}
+
+impl fmt::Display for LoxDifferentialOutcomeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Success => "success",
+            Self::CompileError => "compile error",
+            Self::RuntimeError => "runtime error",
+            Self::Crash => "crash",
+            Self::Timeout => "timeout",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoxDifferentialOutcome {
+    kind: LoxDifferentialOutcomeKind,
+    outputs: Vec<String>,
+}
+
+impl LoxDifferentialOutcome {
+    /// Run the given program with the given backend.
+    ///
+    /// Each program runs in its own process, so that hanging or crashing
+    /// programs (e.g. unbounded recursion) cannot take down the test itself.
+    pub fn run(backend: LoxAutoTestBackend, source: &str) -> Self {
+        const TIMEOUT: Duration = Duration::from_secs(2);
+        const MAX_OUTPUT_LENGTH: u64 = 1 << 20;
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rust_crafting_interpreters"))
+            .args(["-", "--backend", backend.get_cli_name()])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("can run the interpreter");
+        // a program failing to compile may exit before its source is fully written
+        let _ = child.stdin.take().unwrap().write_all(source.as_bytes());
+        let stdout = child.stdout.take().unwrap();
+        // read concurrently so that the program cannot block on a full pipe,
+        // and only up to a limit for programs printing forever
+        let reader = thread::spawn(move || {
+            let mut outputs = String::new();
+            let _ = stdout.take(MAX_OUTPUT_LENGTH).read_to_string(&mut outputs);
+            outputs
+        });
+
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait().expect("can wait for the interpreter") {
+                break Some(status);
+            }
+            if started.elapsed() > TIMEOUT {
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
             }
+            thread::sleep(Duration::from_millis(1));
+        };
+        let kind = match status.map(|status| status.code()) {
+            None => LoxDifferentialOutcomeKind::Timeout,
+            Some(Some(0)) => LoxDifferentialOutcomeKind::Success,
+            Some(Some(65)) => LoxDifferentialOutcomeKind::CompileError,
+            Some(Some(70)) => LoxDifferentialOutcomeKind::RuntimeError,
+            Some(_) => LoxDifferentialOutcomeKind::Crash,
+        };
+        let outputs = reader.join().unwrap().lines().map(String::from).collect();
+        Self { kind, outputs }
+    }
+
+    pub fn get_kind(&self) -> LoxDifferentialOutcomeKind {
+        self.kind
+    }
+
+    pub fn get_outputs(&self) -> &[String] {
+        &self.outputs
+    }
+}
+
+/// Program whose outcome differs between the tree-walk interpreter and the
+/// bytecode virtual machine.
+#[derive(Clone, Debug)]
+pub struct LoxDifferentialDivergence {
+    /// Path of the test, or seed of the generated program.
+    origin: String,
+    tree_walk: LoxDifferentialOutcome,
+    bytecode: LoxDifferentialOutcome,
+    /// Smallest program found to diverge in the same way.
+    reproducer: String,
+}
+
+impl LoxDifferentialDivergence {
+    /// Run the given program through both backends, returning how they diverge if they do.
+    pub fn check(origin: String, source: &str) -> Option<Self> {
+        let tree_walk = LoxDifferentialOutcome::run(LoxAutoTestBackend::TreeWalk, source);
+        let bytecode = LoxDifferentialOutcome::run(LoxAutoTestBackend::Bytecode, source);
+        if tree_walk == bytecode {
+            return None;
+        }
+        let kinds = (tree_walk.get_kind(), bytecode.get_kind());
+        let reproducer = minimize_program(source, |candidate| {
+            let tree_walk = LoxDifferentialOutcome::run(LoxAutoTestBackend::TreeWalk, candidate);
+            let bytecode = LoxDifferentialOutcome::run(LoxAutoTestBackend::Bytecode, candidate);
+            tree_walk != bytecode && (tree_walk.get_kind(), bytecode.get_kind()) == kinds
+        });
+        Some(Self {
+            origin,
+            tree_walk,
+            bytecode,
+            reproducer,
+        })
+    }
+
+    pub fn get_reproducer(&self) -> &str {
+        &self.reproducer
+    }
+}
+
+impl fmt::Display for LoxDifferentialDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "divergence in {}:", self.origin)?;
+        for (name, outcome) in [("tree-walk", &self.tree_walk), ("bytecode", &self.bytecode)] {
+            writeln!(
+                f,
+                "  {}: {}, outputs {:?}",
+                name, outcome.kind, outcome.outputs
+            )?;
+        }
+        writeln!(f, "  minimized reproducer:")?;
+        for line in self.reproducer.lines() {
+            writeln!(f, "    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove as many lines as possible from the given program while it keeps
+/// satisfying the given predicate.
+///
+/// Tries removing chunks of lines, halving their size until single lines can
+/// no longer be removed.
+pub fn minimize_program<F: Fn(&str) -> bool>(source: &str, predicate: F) -> String {
+    let mut lines: Vec<&str> = source.lines().collect();
+    let mut chunk_size = (lines.len() / 2).max(1);
+    loop {
+        let mut removed = false;
+        let mut start = 0;
+        while start < lines.len() {
+            let end = (start + chunk_size).min(lines.len());
+            let candidate: Vec<&str> = [&lines[..start], &lines[end..]].concat();
+            if predicate(&candidate.join("\n")) {
+                lines = candidate;
+                removed = true;
+            } else {
+                start = end;
+            }
+        }
+        if !removed {
+            if chunk_size == 1 {
+                break;
+            }
+            chunk_size /= 2;
+        }
+    }
+    lines.join("\n")
+}
+
+/// Types of the generated expressions, to mostly generate programs running
+/// without type errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LoxGeneratedType {
+    Number,
+    Boolean,
+    String,
+}
+
+/// Pseudo-random Lox programs generator, reproducible from its seed.
+///
+/// Each generated statement stays on its own line, for the reproducers to be
+/// minimized line by line.
+pub struct LoxProgramGenerator {
+    /// State of the xorshift generator.
+    state: u64,
+    variables: Vec<(String, LoxGeneratedType)>,
+    /// Declared functions, all taking two numbers and returning a number.
+    functions: Vec<String>,
+    /// Declared counters, returned by closures.
+    counters: Vec<String>,
+}
+
+impl LoxProgramGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            // the state must never be zero
+            state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            variables: vec![],
+            functions: vec![],
+            counters: vec![],
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// Random number in `0..bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+
+    pub fn generate(&mut self, statements: usize) -> String {
+        let mut lines = vec![];
+        for _ in 0..statements {
+            lines.push(self.statement());
+        }
+        lines.join("\n")
+    }
+
+    fn statement(&mut self) -> String {
+        match self.below(10) {
+            0 | 1 => {
+                let name = format!("v{}", self.variables.len());
+                let value_type = *self.pick(&[
+                    LoxGeneratedType::Number,
+                    LoxGeneratedType::Boolean,
+                    LoxGeneratedType::String,
+                ]);
+                let statement = format!("var {} = {};", name, self.expression(value_type, 2));
+                self.variables.push((name, value_type));
+                statement
+            }
+            2 if !self.variables.is_empty() => {
+                let (name, value_type) = self.pick(&self.variables.clone()).clone();
+                format!("{} = {};", name, self.expression(value_type, 2))
+            }
+            3 => format!(
+                "if ({}) print {}; else print {};",
+                self.expression(LoxGeneratedType::Boolean, 2),
+                self.expression(LoxGeneratedType::Number, 2),
+                self.expression(LoxGeneratedType::String, 2)
+            ),
+            4 => format!(
+                "for (var i = 0; i < {}; i = i + 1) {{ print i * {}; }}",
+                self.below(4),
+                self.expression(LoxGeneratedType::Number, 1)
+            ),
+            5 => {
+                let name = format!("f{}", self.functions.len());
+                let body = match self.below(3) {
+                    0 => "if (a < b) return a; return b;".to_string(),
+                    1 => format!("var c = a * {}; return c - b;", self.below(10)),
+                    _ => format!("return a + b + {};", self.expression(LoxGeneratedType::Number, 1)),
+                };
+                self.functions.push(name.clone());
+                format!("fun {}(a, b) {{ {} }}", name, body)
+            }
+            6 => {
+                let name = format!("counter{}", self.counters.len());
+                self.counters.push(name.clone());
+                format!(
+                    "fun make{0}() {{ var count = {1}; fun step() {{ count = count + 1; return count; }} return step; }} var {0} = make{0}();",
+                    name,
+                    self.below(5)
+                )
+            }
+            7 => format!(
+                "class C {{ init(x) {{ this.x = x; }} get() {{ return this.x; }} }} print C({}).get();",
+                self.expression(LoxGeneratedType::Number, 2)
+            ),
+            8 => {
+                let value_type = *self.pick(&[
+                    LoxGeneratedType::Number,
+                    LoxGeneratedType::Boolean,
+                    LoxGeneratedType::String,
+                ]);
+                format!("{{ var local = {}; print local; }}", self.expression(value_type, 2))
+            }
+            _ => {
+                let value_type = *self.pick(&[
+                    LoxGeneratedType::Number,
+                    LoxGeneratedType::Boolean,
+                    LoxGeneratedType::String,
+                ]);
+                format!("print {};", self.expression(value_type, 3))
+            }
+        }
+    }
+
+    fn expression(&mut self, value_type: LoxGeneratedType, depth: usize) -> String {
+        // type errors are rare, but both backends must report them
+        if self.below(50) == 0 {
+            let other_type = *self.pick(&[LoxGeneratedType::Boolean, LoxGeneratedType::String]);
+            return self.expression(other_type, depth);
+        }
+        if depth == 0 || self.below(3) == 0 {
+            return self.leaf(value_type);
+        }
+        match value_type {
+            LoxGeneratedType::Number => match self.below(6) {
+                0 if !self.functions.is_empty() => format!(
+                    "{}({}, {})",
+                    self.pick(&self.functions.clone()),
+                    self.expression(LoxGeneratedType::Number, depth - 1),
+                    self.expression(LoxGeneratedType::Number, depth - 1)
+                ),
+                1 if !self.counters.is_empty() => {
+                    format!("{}()", self.pick(&self.counters.clone()))
+                }
+                2 => format!("-{}", self.expression(LoxGeneratedType::Number, depth - 1)),
+                3 => format!("({})", self.expression(LoxGeneratedType::Number, depth - 1)),
+                _ => {
+                    let operator = *self.pick(&["+", "-", "*", "/"]);
+                    format!(
+                        "{} {} {}",
+                        self.expression(LoxGeneratedType::Number, depth - 1),
+                        operator,
+                        self.expression(LoxGeneratedType::Number, depth - 1)
+                    )
+                }
+            },
+            LoxGeneratedType::Boolean => match self.below(4) {
+                0 => format!("!{}", self.expression(LoxGeneratedType::Boolean, depth - 1)),
+                1 => {
+                    let operator = *self.pick(&["and", "or"]);
+                    format!(
+                        "{} {} {}",
+                        self.expression(LoxGeneratedType::Boolean, depth - 1),
+                        operator,
+                        self.expression(LoxGeneratedType::Boolean, depth - 1)
+                    )
+                }
+                2 => {
+                    let operand_type = *self.pick(&[
+                        LoxGeneratedType::Number,
+                        LoxGeneratedType::Boolean,
+                        LoxGeneratedType::String,
+                    ]);
+                    let operator = *self.pick(&["==", "!="]);
+                    format!(
+                        "{} {} {}",
+                        self.expression(operand_type, depth - 1),
+                        operator,
+                        self.expression(operand_type, depth - 1)
+                    )
+                }
+                _ => {
+                    let operator = *self.pick(&["<", "<=", ">", ">="]);
+                    format!(
+                        "{} {} {}",
+                        self.expression(LoxGeneratedType::Number, depth - 1),
+                        operator,
+                        self.expression(LoxGeneratedType::Number, depth - 1)
+                    )
+                }
+            },
+            LoxGeneratedType::String => format!(
+                "{} + {}",
+                self.expression(LoxGeneratedType::String, depth - 1),
+                self.expression(LoxGeneratedType::String, depth - 1)
+            ),
+        }
+    }
+
+    fn leaf(&mut self, value_type: LoxGeneratedType) -> String {
+        let variables: Vec<String> = self
+            .variables
+            .iter()
+            .filter(|(_, variable_type)| *variable_type == value_type)
+            .map(|(name, _)| name.clone())
+            .collect();
+        if !variables.is_empty() && self.below(2) == 0 {
+            return self.pick(&variables).clone();
+        }
+        match value_type {
+            LoxGeneratedType::Number => match self.below(4) {
+                0 => format!("{}.{}", self.below(100), self.below(100)),
+                _ => self.below(100).to_string(),
+            },
+            LoxGeneratedType::Boolean => self.pick(&["true", "false"]).to_string(),
+            LoxGeneratedType::String => format!("\"{}\"", self.pick(&["", "a", "lox", "b c"])),
         }
     }
 }
@@ -132,14 +748,16 @@ mod tests {
     use std::io::Read;
     use std::path::Path;
 
-    use super::{discover_tests, LoxAutoTestHarness, LoxAutoTestSuite};
+    use super::{
+        discover_tests, HistoryPrinter, LoxAutoTestBackend, LoxAutoTestHarness, LoxAutoTestSuite,
+    };
 
-    /// For each tests group entry, detect all files and run their tests.
+    /// For each tests group entry, detect all files and run their tests with the given backend.
     ///
     /// We manually define each group entry instead of detecting them in order to
     /// allow for separate errors for each language domain.
     macro_rules! test_lox_suites_groups {
-    ($ ( $name: ident : ($relative_root: literal), )* ) => {
+    ($backend: expr; $ ( $name: ident : ($relative_root: literal), )* ) => {
         $(
             #[test]
             fn $name() {
@@ -153,10 +771,10 @@ mod tests {
                     (test_path.clone(), test_source)
                 });
                 // parsing
-                let tests_suites = tests_tuples.map(|(test_path, test_source)| LoxAutoTestSuite::from_code(test_path.clone(), test_source).unwrap());
+                let tests_suites = tests_tuples.map(|(test_path, test_source)| LoxAutoTestSuite::from_code(test_path.clone(), test_source, $backend).unwrap());
                 // validation
                 for test_suite in tests_suites {
-                    let mut harness = LoxAutoTestHarness::default();
+                    let mut harness = LoxAutoTestHarness::new($backend, Box::new(HistoryPrinter::default()));
                     harness.run_test_suite(&test_suite);
                 }
             }
@@ -164,35 +782,233 @@ mod tests {
         }
     }
 
-    test_lox_suites_groups! {
-        test_assignment: ("assignment"),
-        test_block: ("block"),
-        test_bool: ("bool"),
-        test_call: ("call"),
-        test_class: ("class"),
-        test_closure: ("closure"),
-        test_comments: ("comments"),
-        test_constructor: ("constructor"),
-        test_expressions: ("expressions"),
-        test_field: ("field"),
-        test_for_loops: ("for"),
-        test_function: ("function"),
-        test_if: ("if"),
-        test_inheritance: ("inheritance"),
-        // test_limit: ("limit"), // TODO: expect errors for this group
-        test_logical_operator: ("logical_operator"),
-        test_method: ("method"),
-        test_nil: ("nil"),
-        test_number: ("number"),
-        test_operator: ("operator"),
-        test_print: ("print"),
-        test_regression: ("regression"),
-        test_return: ("return"),
-        test_scanning: ("scanning"),
-        test_string: ("string"),
-        test_super_class: ("super"),
-        test_this: ("this"),
-        test_variable: ("variable"),
-        test_while: ("while"),
+    mod tree_walk {
+        use super::*;
+
+        test_lox_suites_groups! {
+                LoxAutoTestBackend::TreeWalk;
+                test_assignment: ("assignment"),
+                test_block: ("block"),
+                test_bool: ("bool"),
+                test_call: ("call"),
+                test_class: ("class"),
+                test_closure: ("closure"),
+                test_comments: ("comments"),
+                test_constructor: ("constructor"),
+                // test_expressions: ("expressions"), // jlox chapter 7 only: bare expressions
+                test_field: ("field"),
+                test_for_loops: ("for"),
+                test_function: ("function"),
+                test_if: ("if"),
+                test_inheritance: ("inheritance"),
+                // test_limit: ("limit"), // clox only: constants, locals, upvalues and call frames limits
+                test_logical_operator: ("logical_operator"),
+                test_method: ("method"),
+                test_nil: ("nil"),
+                test_number: ("number"),
+                test_operator: ("operator"),
+                test_print: ("print"),
+                test_regression: ("regression"),
+                test_return: ("return"),
+                // test_scanning: ("scanning"), // jlox chapter 4 only: tokens dump
+                test_string: ("string"),
+                test_super_class: ("super"),
+                test_this: ("this"),
+                test_variable: ("variable"),
+                test_while: ("while"),
+        }
+    }
+
+    /// Groups the bytecode virtual machine is known to pass.
+    mod bytecode {
+        use super::*;
+
+        test_lox_suites_groups! {
+                LoxAutoTestBackend::Bytecode;
+                test_assignment: ("assignment"),
+                test_block: ("block"),
+                test_bool: ("bool"),
+                test_call: ("call"),
+                test_class: ("class"),
+                test_closure: ("closure"),
+                test_comments: ("comments"),
+                test_constructor: ("constructor"),
+                // test_expressions: ("expressions"), // jlox chapter 7 only: bare expressions
+                test_field: ("field"),
+                test_for_loops: ("for"),
+                test_function: ("function"),
+                test_if: ("if"),
+                test_inheritance: ("inheritance"),
+                test_limit: ("limit"),
+                test_logical_operator: ("logical_operator"),
+                test_method: ("method"),
+                test_nil: ("nil"),
+                test_number: ("number"),
+                test_operator: ("operator"),
+                test_print: ("print"),
+                test_regression: ("regression"),
+                test_return: ("return"),
+                // test_scanning: ("scanning"), // jlox chapter 4 only: tokens dump
+                test_string: ("string"),
+                test_super_class: ("super"),
+                test_this: ("this"),
+                test_variable: ("variable"),
+                test_while: ("while"),
+        }
+    }
+
+    /// Differential testing of the two backends, comparing their outputs and error kinds.
+    ///
+    /// The full comparisons are opt-in since they run every program in separate processes:
+    /// `cargo test --test harness differential -- --include-ignored`
+    mod differential {
+        use std::{env, path::Path};
+
+        use rust_crafting_interpreters_lib::{
+            bytecode::vm::LoxBytecodeVirtualMachine, interpreter::LoxInterpreter,
+        };
+
+        use super::super::{
+            discover_tests, minimize_program, HistoryPrinter, LoxDifferentialDivergence,
+            LoxProgramGenerator,
+        };
+
+        /// Groups whose expectations differ between the reference implementations,
+        /// or which are too slow to run twice.
+        const SKIPPED_GROUPS: [&str; 2] = ["benchmark", "limit"];
+
+        fn report_divergences(divergences: &[LoxDifferentialDivergence], total: usize) {
+            if divergences.is_empty() {
+                return;
+            }
+            for divergence in divergences {
+                eprintln!("{}", divergence);
+            }
+            panic!("{} of {} programs diverged", divergences.len(), total);
+        }
+
+        fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        }
+
+        #[test]
+        fn test_minimize_program() {
+            let source = "a\nb\nc\nd\ne\nf\ng";
+            let minimized = minimize_program(source, |candidate| {
+                candidate.contains('b') && candidate.contains('f')
+            });
+            assert_eq!(minimized, "b\nf");
+        }
+
+        #[test]
+        fn test_program_generator() {
+            for seed in 0..20 {
+                let program = LoxProgramGenerator::new(seed).generate(30);
+                assert_eq!(program, LoxProgramGenerator::new(seed).generate(30));
+                assert_eq!(program.lines().count(), 30);
+                let mut vm =
+                    LoxBytecodeVirtualMachine::new(Some(Box::new(HistoryPrinter::default())));
+                if let Err(why) = vm.run_code(&program) {
+                    assert_eq!(why.exit_code(), 70, "{}\n{}", program, why);
+                }
+            }
+        }
+
+        #[test]
+        #[ignore]
+        fn test_differential_loxtests() {
+            let root_path = Path::new("./tests/loxtests/");
+            let tests_paths: Vec<_> = discover_tests(root_path)
+                .into_iter()
+                .filter(|path| {
+                    let group = path.strip_prefix(root_path).unwrap().iter().next().unwrap();
+                    !SKIPPED_GROUPS.iter().any(|skipped| group == *skipped)
+                })
+                .collect();
+            let divergences: Vec<_> = tests_paths
+                .iter()
+                .filter_map(|path| {
+                    let source = std::fs::read_to_string(path).unwrap();
+                    LoxDifferentialDivergence::check(path.display().to_string(), &source)
+                })
+                .collect();
+            report_divergences(&divergences, tests_paths.len());
+        }
+
+        /// Set `LOX_DIFFERENTIAL_SEED` and `LOX_DIFFERENTIAL_PROGRAMS` to explore other programs.
+        #[test]
+        #[ignore]
+        fn test_differential_generated_programs() {
+            let first_seed = env_or("LOX_DIFFERENTIAL_SEED", 0);
+            let programs = env_or("LOX_DIFFERENTIAL_PROGRAMS", 100);
+            let divergences: Vec<_> = (first_seed..first_seed + programs)
+                .filter_map(|seed| {
+                    let program = LoxProgramGenerator::new(seed).generate(20);
+                    LoxDifferentialDivergence::check(
+                        format!("generated program #{}", seed),
+                        &program,
+                    )
+                })
+                .collect();
+            report_divergences(&divergences, programs as usize);
+        }
+    }
+
+    /// Backend selection of the command-line interface.
+    mod cli {
+        use std::{
+            io::Write,
+            iter,
+            process::{Command, Stdio},
+        };
+
+        /// Run a script through the command-line interface with the given arguments,
+        /// telling whether it ran on the tree-walk interpreter: the only one without
+        /// the 256 identifiers limit of the bytecode chunks.
+        fn runs_on_tree_walk(args: &[&str]) -> bool {
+            let source = (0..257)
+                .map(|i| format!("var v{} = {};", i, i))
+                .chain(iter::once("print \"tree-walk\";\n".to_string()))
+                .collect::<String>();
+            let mut child = Command::new(env!("CARGO_BIN_EXE_rust_crafting_interpreters"))
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("can run the interpreter");
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(source.as_bytes())
+                .unwrap();
+            let output = child.wait_with_output().unwrap();
+            String::from_utf8(output.stdout)
+                .unwrap()
+                .contains("tree-walk")
+        }
+
+        #[test]
+        fn test_repl_backend() {
+            // bytecode by default
+            assert!(!runs_on_tree_walk(&[]));
+            assert!(!runs_on_tree_walk(&["repl"]));
+            // tree-walk with either flag
+            assert!(runs_on_tree_walk(&["repl", "--tree-walk-version"]));
+            assert!(runs_on_tree_walk(&["repl", "-t"]));
+            assert!(runs_on_tree_walk(&["repl", "--backend", "tree-walk"]));
+            assert!(!runs_on_tree_walk(&["repl", "--backend", "bytecode"]));
+        }
+
+        #[test]
+        fn test_script_backend() {
+            // tree-walk by default
+            assert!(runs_on_tree_walk(&["-"]));
+            assert!(!runs_on_tree_walk(&["-", "--backend", "bytecode"]));
+        }
     }
 }